@@ -0,0 +1,531 @@
+//! AHCI (Serial ATA) host bus adapter driver.
+//!
+//! Each implemented port with an ATA disk attached is exposed as its own block
+//! device through [`AhciPort`]. Commands are issued in command slot 0 and
+//! completed by polling. A command that times out is aborted by restarting
+//! the port, or by resetting the link if the port does not stop.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::dma::{DmaBuffer, DmaHal, PAGE_SIZE};
+use crate::{BlockCapabilities, BlockDriverOps};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// The maximum number of polls before an HBA operation times out.
+const SPIN_LIMIT: usize = 100_000_000;
+
+const SECTOR_SIZE: usize = 512;
+/// The upper limit of one command, so that the PRDT always fits in the table.
+const MAX_TRANSFER: usize = 128 * 1024;
+const PRDT_ENTRIES: usize = MAX_TRANSFER / PAGE_SIZE + 1;
+
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+const HBA_VS: usize = 0x10;
+const HBA_PORTS: usize = 0x100;
+const HBA_PORT_SIZE: usize = 0x80;
+
+const CAP_S64A: u32 = 1 << 31;
+const GHC_AE: u32 = 1 << 31;
+
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SCTL: usize = 0x2c;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const PX_CMD_ST: u32 = 1 << 0;
+const PX_CMD_SUD: u32 = 1 << 1;
+const PX_CMD_POD: u32 = 1 << 2;
+const PX_CMD_FRE: u32 = 1 << 4;
+const PX_CMD_FR: u32 = 1 << 14;
+const PX_CMD_CR: u32 = 1 << 15;
+
+const PX_IS_TFES: u32 = 1 << 30;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+const SSTS_DET_PRESENT: u32 = 3;
+const SCTL_DET_MASK: u32 = 0xf;
+const SCTL_DET_INIT: u32 = 1;
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_CMD_IDENTIFY: u8 = 0xec;
+
+/// The layout of the per-port DMA page.
+const CMD_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const CMD_TABLE_OFFSET: usize = 0x500;
+const CMD_TABLE_PRDT: usize = 0x80;
+
+/// A command header in the command list.
+#[repr(C)]
+struct CommandHeader {
+    flags: u16,
+    prdtl: u16,
+    prdbc: u32,
+    ctba: u64,
+    reserved: [u32; 4],
+}
+
+/// A physical region descriptor.
+#[repr(C)]
+struct PrdEntry {
+    dba: u64,
+    reserved: u32,
+    dbc: u32,
+}
+
+/// AHCI host bus adapter driver.
+pub struct AhciController<H: DmaHal> {
+    ports: Vec<AhciPort<H>>,
+}
+
+/// An AHCI port with an ATA disk attached, as a block device.
+pub struct AhciPort<H: DmaHal> {
+    name: String,
+    regs: NonNull<u8>,
+    port: usize,
+    dma: DmaBuffer<H>,
+    num_blocks: u64,
+    caps: BlockCapabilities,
+}
+
+unsafe impl<H: DmaHal> Send for AhciPort<H> {}
+unsafe impl<H: DmaHal> Sync for AhciPort<H> {}
+
+fn read_reg(base: NonNull<u8>, offset: usize) -> u32 {
+    unsafe { (base.as_ptr().add(offset) as *const u32).read_volatile() }
+}
+
+fn write_reg(base: NonNull<u8>, offset: usize, val: u32) {
+    unsafe { (base.as_ptr().add(offset) as *mut u32).write_volatile(val) }
+}
+
+fn wait_until(mut cond: impl FnMut() -> bool) -> DevResult {
+    for _ in 0..SPIN_LIMIT {
+        if cond() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(DevError::Io)
+}
+
+impl<H: DmaHal> AhciController<H> {
+    /// Enables AHCI mode on the HBA whose registers (ABAR) are mapped at
+    /// `abar`, and initializes every port that has an ATA disk attached.
+    ///
+    /// `name` is used as the prefix of the port device names.
+    ///
+    /// # Safety
+    ///
+    /// `abar` must point to the mapped HBA registers.
+    pub unsafe fn try_new(abar: NonNull<u8>, name: &str) -> DevResult<Self> {
+        let ghc = read_reg(abar, HBA_GHC);
+        write_reg(abar, HBA_GHC, ghc | GHC_AE);
+
+        let cap = read_reg(abar, HBA_CAP);
+        let implemented = read_reg(abar, HBA_PI);
+        let version = read_reg(abar, HBA_VS);
+        log::info!(
+            "{}: AHCI {}.{}, ports implemented {:#x}",
+            name,
+            version >> 16,
+            (version >> 8) & 0xff,
+            implemented
+        );
+
+        let mut ports = Vec::new();
+        for port in (0..32).filter(|i| implemented & (1 << i) != 0) {
+            match AhciPort::init(abar, port, cap & CAP_S64A != 0, name) {
+                Ok(Some(dev)) => ports.push(dev),
+                Ok(None) => {}
+                Err(e) => log::warn!("{}: port {} init failed: {:?}", name, port, e),
+            }
+        }
+        write_reg(abar, HBA_IS, !0);
+        Ok(Self { ports })
+    }
+
+    /// Initializes the HBA at PCI function `pci` (BAR 5).
+    ///
+    /// The ports are named after the PCI address, e.g. `ahci-0000:00:1f.2p0`.
+    #[cfg(feature = "pci")]
+    pub fn try_new_pci<C: crate::pci::PciConfigSpace>(
+        mut pci: crate::pci::PciDevice<C>,
+    ) -> DevResult<Self> {
+        let (paddr, size) = pci.memory_bar(5)?;
+        pci.enable();
+        let abar = H::mmio_phys_to_virt(paddr, size);
+        let name = alloc::format!("ahci-{}", pci.bdf());
+        unsafe { Self::try_new(abar, &name) }
+    }
+
+    /// Returns the block devices of the attached disks.
+    pub fn into_ports(self) -> Vec<AhciPort<H>> {
+        self.ports
+    }
+}
+
+impl<H: DmaHal> AhciPort<H> {
+    fn reg(&self, offset: usize) -> u32 {
+        read_reg(self.regs, offset)
+    }
+
+    fn set_reg(&mut self, offset: usize, val: u32) {
+        write_reg(self.regs, offset, val)
+    }
+
+    fn init(abar: NonNull<u8>, port: usize, dma64: bool, name: &str) -> DevResult<Option<Self>> {
+        let regs =
+            unsafe { NonNull::new_unchecked(abar.as_ptr().add(HBA_PORTS + port * HBA_PORT_SIZE)) };
+        if read_reg(regs, PX_SSTS) & 0xf != SSTS_DET_PRESENT {
+            return Ok(None);
+        }
+        let dma = DmaBuffer::<H>::new(PAGE_SIZE)?;
+        if !dma64 && (dma.paddr() as u64) >> 32 != 0 {
+            return Err(DevError::NoMemory);
+        }
+        let mut dev = Self {
+            name: alloc::format!("{}p{}", name, port),
+            regs,
+            port,
+            dma,
+            num_blocks: 0,
+            caps: BlockCapabilities::new(0, SECTOR_SIZE),
+        };
+        dev.stop()?;
+
+        let clb = (dev.dma.paddr() + CMD_LIST_OFFSET) as u64;
+        let fb = (dev.dma.paddr() + RECEIVED_FIS_OFFSET) as u64;
+        dev.set_reg(PX_CLB, clb as u32);
+        dev.set_reg(PX_CLBU, (clb >> 32) as u32);
+        dev.set_reg(PX_FB, fb as u32);
+        dev.set_reg(PX_FBU, (fb >> 32) as u32);
+        dev.set_reg(PX_SERR, !0);
+        dev.set_reg(PX_IS, !0);
+        dev.set_reg(PX_IE, 0);
+        dev.start()?;
+        if dev.reg(PX_SIG) != SIG_ATA {
+            // ATAPI, port multiplier or enclosure bridge.
+            dev.stop()?;
+            return Ok(None);
+        }
+
+        let identify = DmaBuffer::<H>::new(SECTOR_SIZE)?;
+        let vaddr = identify.as_ptr() as usize;
+        dev.issue(
+            ATA_CMD_IDENTIFY,
+            0,
+            0,
+            Some((&[(vaddr, SECTOR_SIZE)], false)),
+        )?;
+        let id = identify.as_slice();
+        let word = |i: usize| u16::from_le_bytes([id[2 * i], id[2 * i + 1]]);
+        if word(83) & (1 << 10) == 0 {
+            log::warn!("{}: LBA48 is not supported", dev.name);
+            return Err(DevError::Unsupported);
+        }
+        dev.num_blocks = (0..4).fold(0, |acc, i| acc | (word(100 + i) as u64) << (16 * i));
+        let words: [u16; SECTOR_SIZE / 2] = core::array::from_fn(word);
+        dev.caps = BlockCapabilities {
+            max_transfer: MAX_TRANSFER,
+            max_segments: PRDT_ENTRIES,
+            ..crate::ata::identify_capabilities(&words, dev.num_blocks)
+        };
+        log::info!("{}: SATA disk, {} sectors", dev.name, dev.num_blocks);
+        Ok(Some(dev))
+    }
+
+    /// Stops the command list and FIS receive engines.
+    fn stop(&mut self) -> DevResult {
+        let cmd = self.reg(PX_CMD);
+        self.set_reg(PX_CMD, cmd & !(PX_CMD_ST | PX_CMD_FRE));
+        wait_until(|| self.reg(PX_CMD) & (PX_CMD_CR | PX_CMD_FR) == 0)
+    }
+
+    /// Starts the FIS receive and command list engines.
+    fn start(&mut self) -> DevResult {
+        let cmd = self.reg(PX_CMD) | PX_CMD_SUD | PX_CMD_POD;
+        self.set_reg(PX_CMD, cmd | PX_CMD_FRE);
+        wait_until(|| self.reg(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+        self.set_reg(PX_CMD, cmd | PX_CMD_FRE | PX_CMD_ST);
+        Ok(())
+    }
+
+    /// Builds the PRDT for the buffers given as `(vaddr, len)`, returns the
+    /// entry count.
+    fn setup_prdt(&mut self, bufs: &[(usize, usize)]) -> DevResult<u16> {
+        let table =
+            unsafe { self.dma.as_ptr().add(CMD_TABLE_OFFSET + CMD_TABLE_PRDT) as *mut PrdEntry };
+        let mut count = 0;
+        for &(vaddr, len) in bufs {
+            if vaddr % 2 != 0 {
+                return Err(DevError::InvalidParam);
+            }
+            let mut offset = 0;
+            while offset < len {
+                let addr = vaddr + offset;
+                let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(len - offset);
+                let paddr = H::virt_to_phys(addr) as u64;
+                if count > 0 {
+                    let prev = unsafe { &mut *table.add(count - 1) };
+                    if prev.dba + (prev.dbc & 0x3f_ffff) as u64 + 1 == paddr {
+                        // Merge physically contiguous pages.
+                        prev.dbc += chunk as u32;
+                        offset += chunk;
+                        continue;
+                    }
+                }
+                if count == PRDT_ENTRIES {
+                    return Err(DevError::InvalidParam);
+                }
+                let entry = PrdEntry {
+                    dba: paddr,
+                    reserved: 0,
+                    dbc: chunk as u32 - 1,
+                };
+                unsafe { table.add(count).write(entry) };
+                count += 1;
+                offset += chunk;
+            }
+        }
+        Ok(count as u16)
+    }
+
+    /// Issues an ATA command in slot 0 and waits for its completion.
+    ///
+    /// `data` is the `(vaddr, len)` list of the buffers to transfer, and
+    /// whether they are written to the device.
+    fn issue(
+        &mut self,
+        command: u8,
+        lba: u64,
+        sectors: u16,
+        data: Option<(&[(usize, usize)], bool)>,
+    ) -> DevResult {
+        let (prdtl, write) = match data {
+            Some((bufs, write)) => (self.setup_prdt(bufs)?, write),
+            None => (0, false),
+        };
+
+        let base = self.dma.as_ptr();
+        let fis = unsafe { core::slice::from_raw_parts_mut(base.add(CMD_TABLE_OFFSET), 20) };
+        fis.fill(0);
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = 1 << 7; // Command, not control.
+        fis[2] = command;
+        fis[4] = lba as u8;
+        fis[5] = (lba >> 8) as u8;
+        fis[6] = (lba >> 16) as u8;
+        fis[7] = 1 << 6; // LBA mode.
+        fis[8] = (lba >> 24) as u8;
+        fis[9] = (lba >> 32) as u8;
+        fis[10] = (lba >> 40) as u8;
+        fis[12] = sectors as u8;
+        fis[13] = (sectors >> 8) as u8;
+
+        let cfl = 5; // The FIS length in dwords.
+        let header = CommandHeader {
+            flags: cfl | if write { 1 << 6 } else { 0 },
+            prdtl,
+            prdbc: 0,
+            ctba: (self.dma.paddr() + CMD_TABLE_OFFSET) as u64,
+            reserved: [0; 4],
+        };
+        unsafe { (base.add(CMD_LIST_OFFSET) as *mut CommandHeader).write_volatile(header) };
+
+        fence(Ordering::SeqCst);
+        self.set_reg(PX_IS, !0);
+        self.set_reg(PX_CI, 1);
+        if wait_until(|| self.reg(PX_CI) & 1 == 0 || self.reg(PX_IS) & PX_IS_TFES != 0).is_err() {
+            // The command must be aborted before its buffers are handed back.
+            log::warn!("{}: command {:#x} timed out", self.name, command);
+            if self.recover().is_err() {
+                self.reset()?;
+            }
+            return Err(DevError::Io);
+        }
+        fence(Ordering::SeqCst);
+
+        if self.reg(PX_IS) & PX_IS_TFES != 0 || self.reg(PX_TFD) & TFD_ERR != 0 {
+            log::warn!(
+                "{}: command {:#x} failed, tfd {:#x}",
+                self.name,
+                command,
+                self.reg(PX_TFD)
+            );
+            self.recover()?;
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    /// Restarts the port after a task file error or a timeout. Stopping the
+    /// command list engine clears the commands in flight.
+    fn recover(&mut self) -> DevResult {
+        self.stop()?;
+        self.set_reg(PX_SERR, !0);
+        self.set_reg(PX_IS, !0);
+        self.start()
+    }
+
+    /// Resets the link (COMRESET) of a port that does not stop, which also
+    /// resets the device, then restarts the port.
+    fn reset(&mut self) -> DevResult {
+        log::warn!("{}: port does not stop, resetting the link", self.name);
+        let sctl = self.reg(PX_SCTL) & !SCTL_DET_MASK;
+        self.set_reg(PX_SCTL, sctl | SCTL_DET_INIT);
+        // COMRESET must be held for at least 1 ms.
+        H::delay_ns(1_000_000);
+        self.set_reg(PX_SCTL, sctl);
+        wait_until(|| self.reg(PX_SSTS) & 0xf == SSTS_DET_PRESENT)?;
+        self.recover()
+    }
+
+    fn read_write(&mut self, command: u8, block_id: u64, vaddr: usize, len: usize) -> DevResult {
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        match block_id.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.num_blocks => {}
+            _ => return Err(DevError::Io),
+        }
+        let write = command == ATA_CMD_WRITE_DMA_EXT;
+        let mut offset = 0;
+        while offset < len {
+            let chunk = (len - offset).min(MAX_TRANSFER);
+            let lba = block_id + (offset / SECTOR_SIZE) as u64;
+            let sectors = (chunk / SECTOR_SIZE) as u16;
+            self.issue(
+                command,
+                lba,
+                sectors,
+                Some((&[(vaddr + offset, chunk)], write)),
+            )?;
+            offset += chunk;
+        }
+        Ok(())
+    }
+
+    /// Transfers the buffers of a vectored request, given as `(vaddr, len)`,
+    /// with as many of them in each command as its PRDT can describe.
+    fn read_write_vectored(
+        &mut self,
+        command: u8,
+        block_id: u64,
+        bufs: impl Iterator<Item = (usize, usize)>,
+    ) -> DevResult {
+        let write = command == ATA_CMD_WRITE_DMA_EXT;
+        // Every buffer takes at least one PRDT entry.
+        let mut batch = [(0, 0); PRDT_ENTRIES];
+        let (mut count, mut entries, mut len) = (0, 0, 0);
+        let mut lba = block_id;
+        for (vaddr, buf_len) in bufs {
+            // Without merging, a buffer takes one entry per page it spans.
+            let buf_entries = (vaddr % PAGE_SIZE + buf_len).div_ceil(PAGE_SIZE);
+            if count > 0 && (len + buf_len > MAX_TRANSFER || entries + buf_entries > PRDT_ENTRIES) {
+                let sectors = (len / SECTOR_SIZE) as u16;
+                self.issue(command, lba, sectors, Some((&batch[..count], write)))?;
+                lba += sectors as u64;
+                (count, entries, len) = (0, 0, 0);
+            }
+            if buf_len > MAX_TRANSFER {
+                self.read_write(command, lba, vaddr, buf_len)?;
+                lba += (buf_len / SECTOR_SIZE) as u64;
+                continue;
+            }
+            batch[count] = (vaddr, buf_len);
+            count += 1;
+            entries += buf_entries;
+            len += buf_len;
+        }
+        if count > 0 {
+            let sectors = (len / SECTOR_SIZE) as u16;
+            self.issue(command, lba, sectors, Some((&batch[..count], write)))?;
+        }
+        Ok(())
+    }
+
+    /// The index of the port on the HBA.
+    pub const fn port(&self) -> usize {
+        self.port
+    }
+}
+
+impl<H: DmaHal> BaseDriverOps for AhciPort<H> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<H: DmaHal> BlockDriverOps for AhciPort<H> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let vaddr = buf.as_mut_ptr() as usize;
+        self.read_write(ATA_CMD_READ_DMA_EXT, block_id, vaddr, buf.len())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let vaddr = buf.as_ptr() as usize;
+        self.read_write(ATA_CMD_WRITE_DMA_EXT, block_id, vaddr, buf.len())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.issue(ATA_CMD_FLUSH_CACHE_EXT, 0, 0, None)
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.caps
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        crate::vectored_blocks(self, block_id, bufs.iter().map(|buf| buf.len()))?;
+        let bufs = bufs
+            .iter_mut()
+            .map(|buf| (buf.as_mut_ptr() as usize, buf.len()));
+        self.read_write_vectored(ATA_CMD_READ_DMA_EXT, block_id, bufs)
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        crate::vectored_blocks(self, block_id, bufs.iter().map(|buf| buf.len()))?;
+        let bufs = bufs.iter().map(|buf| (buf.as_ptr() as usize, buf.len()));
+        self.read_write_vectored(ATA_CMD_WRITE_DMA_EXT, block_id, bufs)
+    }
+}
@@ -0,0 +1,243 @@
+//! Block I/O for cooperative async executors.
+//!
+//! [`AsyncBlockOps`] is the `async` counterpart of [`BlockDriverOps`]. A
+//! driver implementing it starts a transfer, then awaits an [`IrqEvent`]
+//! that its interrupt handler signals when the transfer completes, so the
+//! executor can run other tasks in the meantime. No allocation is needed.
+//!
+//! Synchronous drivers are used through [`AsyncAdapter`], whose futures
+//! complete on their first poll.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::BlockDriverOps;
+use driver_common::{BaseDriverOps, DevResult, DeviceType};
+
+/// Asynchronous operations of a block storage device.
+///
+/// The operations have the same semantics as their [`BlockDriverOps`]
+/// counterparts. The buffers are borrowed until the future completes, a
+/// future dropped before completion must not leave a transfer in flight.
+#[allow(async_fn_in_trait)]
+pub trait AsyncBlockOps: BaseDriverOps {
+    /// The number of blocks in this storage device.
+    fn num_blocks(&self) -> u64;
+    /// The size of each block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Reads blocked data from the given block.
+    async fn read(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult;
+
+    /// Writes blocked data to the given block.
+    async fn write(&mut self, block_id: u64, buf: &[u8]) -> DevResult;
+
+    /// Flushes the device to write all pending data to the storage.
+    async fn flush(&mut self) -> DevResult;
+}
+
+const WAITING: usize = 0;
+const REGISTERING: usize = 1 << 0;
+const WAKING: usize = 1 << 1;
+
+/// A slot for the waker of one task, which can be woken from an interrupt
+/// handler while the task registers itself again.
+struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(WAITING, REGISTERING, Ordering::Acquire, Ordering::Acquire)
+            .unwrap_or_else(|state| state)
+        {
+            WAITING => {
+                // Only this side touches the slot while REGISTERING is set.
+                let slot = unsafe { &mut *self.waker.get() };
+                if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                    *slot = Some(waker.clone());
+                }
+                let done = self.state.compare_exchange(
+                    REGISTERING,
+                    WAITING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+                if done.is_err() {
+                    // Woken while registering: the waker must be woken here.
+                    let waker = slot.take();
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            WAKING => waker.wake_by_ref(),
+            // Registered concurrently by another task, which is a misuse.
+            _ => {}
+        }
+    }
+
+    fn wake(&self) {
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == WAITING {
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// An event signaled by an interrupt handler and awaited by one task.
+///
+/// A signal is kept until it is awaited, so one that happens before the
+/// task awaits it is not lost. Signals are not counted: several signals
+/// before a wait complete it once.
+pub struct IrqEvent {
+    signaled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl IrqEvent {
+    /// Creates an event that is not signaled.
+    pub const fn new() -> Self {
+        Self {
+            signaled: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Signals the event and wakes the task waiting for it, if any.
+    ///
+    /// It never blocks, and can be called from an interrupt handler as long
+    /// as waking a task of the executor can.
+    pub fn signal(&self) {
+        self.signaled.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Drops a pending signal, before starting the operation it reports.
+    pub fn clear(&self) {
+        self.signaled.store(false, Ordering::Release);
+    }
+
+    /// Whether the event is signaled.
+    pub fn is_signaled(&self) -> bool {
+        self.signaled.load(Ordering::Acquire)
+    }
+
+    /// Waits for the event to be signaled, and consumes the signal.
+    pub fn wait(&self) -> IrqWait<'_> {
+        IrqWait { event: self }
+    }
+}
+
+impl Default for IrqEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The future returned by [`IrqEvent::wait`].
+pub struct IrqWait<'a> {
+    event: &'a IrqEvent,
+}
+
+impl Future for IrqWait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let event = self.event;
+        if event.signaled.swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+        event.waker.register(cx.waker());
+        // The signal may have come before the waker was registered.
+        if event.signaled.swap(false, Ordering::AcqRel) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Runs a synchronous [`BlockDriverOps`] driver behind [`AsyncBlockOps`].
+///
+/// Each operation runs to completion on the first poll of its future, so the
+/// executor is blocked for its duration.
+pub struct AsyncAdapter<D: BlockDriverOps> {
+    dev: D,
+}
+
+impl<D: BlockDriverOps> AsyncAdapter<D> {
+    /// Wraps `dev`.
+    pub const fn new(dev: D) -> Self {
+        Self { dev }
+    }
+
+    /// The wrapped driver.
+    pub fn inner(&self) -> &D {
+        &self.dev
+    }
+
+    /// The mutable wrapped driver.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    /// Returns the wrapped driver.
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for AsyncAdapter<D> {
+    fn device_type(&self) -> DeviceType {
+        self.dev.device_type()
+    }
+
+    fn device_name(&self) -> &str {
+        self.dev.device_name()
+    }
+}
+
+impl<D: BlockDriverOps> AsyncBlockOps for AsyncAdapter<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.dev.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    async fn read(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.dev.read_block(block_id, buf)
+    }
+
+    async fn write(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.dev.write_block(block_id, buf)
+    }
+
+    async fn flush(&mut self) -> DevResult {
+        self.dev.flush()
+    }
+}
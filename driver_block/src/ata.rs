@@ -0,0 +1,39 @@
+//! The IDENTIFY DEVICE data of ATA disks, shared by the ATA drivers.
+
+use crate::BlockCapabilities;
+
+const SECTOR_SIZE: usize = 512;
+
+/// The removable media bit of the general configuration word.
+const CONFIG_REMOVABLE: u16 = 1 << 7;
+/// The volatile write cache is enabled, in word 85.
+const WRITE_CACHE_ENABLED: u16 = 1 << 5;
+/// Words 106 and 209 are valid if bit 14 is set and bit 15 cleared.
+const WORD_VALID_MASK: u16 = 0xc000;
+const WORD_VALID: u16 = 0x4000;
+/// The device has several logical sectors per physical sector, in word 106.
+const MULTIPLE_LOGICAL_PER_PHYSICAL: u16 = 1 << 13;
+/// The nominal media rotation rate of non-rotating media, in word 217.
+const NON_ROTATING: u16 = 1;
+
+/// The capabilities of a disk of `num_blocks` sectors, given the 256 words of
+/// its IDENTIFY DEVICE data.
+pub(crate) fn identify_capabilities(id: &[u16], num_blocks: u64) -> BlockCapabilities {
+    let mut caps = BlockCapabilities {
+        rotational: id[217] != NON_ROTATING,
+        removable: id[0] & CONFIG_REMOVABLE != 0,
+        write_cache: id[85] & WRITE_CACHE_ENABLED != 0,
+        ..BlockCapabilities::new(num_blocks, SECTOR_SIZE)
+    };
+    if id[106] & WORD_VALID_MASK == WORD_VALID && id[106] & MULTIPLE_LOGICAL_PER_PHYSICAL != 0 {
+        let physical = SECTOR_SIZE << (id[106] & 0xf);
+        caps.physical_block_size = physical;
+        caps.min_io_size = physical;
+        if id[209] & WORD_VALID_MASK == WORD_VALID {
+            // The offset of the first logical sector in its physical sector.
+            let offset = (id[209] & 0x3fff) as usize * SECTOR_SIZE;
+            caps.alignment_offset = (physical - offset % physical) % physical;
+        }
+    }
+    caps
+}
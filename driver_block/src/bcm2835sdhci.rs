@@ -1,88 +1,183 @@
-//! SD card driver for raspi4
+//! SD card driver for the Raspberry Pi.
+//!
+//! The SD card slot is wired to an SDHCI controller: the Arasan controller of
+//! the BCM2835, or the EMMC2 controller of the BCM2711 on the Raspberry Pi 4.
+//! Both are driven by the generic [`sdhci`](crate::sdhci) driver with the
+//! quirks of the controller, [`SdhciQuirks::BCM2835`] or
+//! [`SdhciQuirks::BCM2711`].
 
-extern crate alloc;
-use crate::BlockDriverOps;
-use bcm2835_sdhci::Bcm2835SDhci::{EmmcCtl, BLOCK_SIZE};
-use bcm2835_sdhci::SDHCIError;
-use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+use core::ptr::NonNull;
+
+use crate::dma::{DmaHal, PhysAddr};
+use crate::mmc::MmcCard;
+use crate::sdhci::{SdhciDriver, SdhciHost, SdhciQuirks};
+use crate::{BlockCapabilities, BlockDriverOps, DiscardLimits};
+use driver_common::{BaseDriverOps, DevResult, DeviceType};
+
+/// The physical address of the EMMC2 controller of the Raspberry Pi 4, used
+/// by [`SDHCIDriver::try_new`].
+pub const EMMC2_PADDR: PhysAddr = 0xfe34_0000;
+
+/// The frequency in Hz of the EMMC2 clock as set by the firmware of the
+/// Raspberry Pi 4, used by [`SDHCIDriver::try_new`].
+pub const EMMC2_CLOCK: u32 = 100_000_000;
+
+/// The offset of the linear mapping of physical memory assumed by
+/// [`LinearMapHal`], that of ArceOS on AArch64.
+pub const PHYS_VIRT_OFFSET: usize = 0xffff_0000_0000_0000;
+
+/// The platform assumed by [`SDHCIDriver::try_new`]: device registers are
+/// reached through the linear mapping at [`PHYS_VIRT_OFFSET`], and there is
+/// no DMA memory, so data goes through the controller FIFO.
+pub struct LinearMapHal;
+
+impl DmaHal for LinearMapHal {
+    fn dma_alloc(_pages: usize) -> Option<(PhysAddr, NonNull<u8>)> {
+        None
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, _vaddr: NonNull<u8>, _pages: usize) {}
+
+    fn virt_to_phys(vaddr: usize) -> PhysAddr {
+        vaddr - PHYS_VIRT_OFFSET
+    }
+
+    fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        NonNull::new((paddr + PHYS_VIRT_OFFSET) as *mut u8).unwrap()
+    }
+}
 
 /// BCM2835 SDHCI driver (Raspberry Pi SD card).
-pub struct SDHCIDriver(EmmcCtl);
+///
+/// It wraps the generic SDHCI card driver, so the card registers, the bus
+/// negotiation, card change detection and error recovery are those of
+/// [`MmcCard`], and ADMA2 transfers and interrupt-driven completion those of
+/// [`SdhciHost`]. Both are reached through [`inner`](Self::inner) and
+/// [`inner_mut`](Self::inner_mut).
+///
+/// The card is identified by [`MmcCard::cid`], [`MmcCard::csd`] and
+/// [`MmcCard::scr`], and its speed class is read by [`MmcCard::sd_status`].
+/// The bus is set to the fastest timing and the widest width both the card
+/// and the controller support, as reported by [`MmcCard::timing`] and
+/// [`MmcCard::bus_width`]. The controllers have no 1.8V signaling, so SD
+/// cards run at high speed at most.
+///
+/// Completion is polled until interrupts are enabled on the host, through
+/// [`MmcCard::host_mut`] and [`SdhciHost::enable_irq`].
+pub struct SDHCIDriver<H: DmaHal = LinearMapHal>(SdhciDriver<H>);
 
 impl SDHCIDriver {
     /// Initialize the SDHCI driver, returns `Ok` if successful.
+    ///
+    /// It drives the SD card slot of the Raspberry Pi 4: the EMMC2
+    /// controller at [`EMMC2_PADDR`], clocked at [`EMMC2_CLOCK`], whose
+    /// registers must be mapped as [`LinearMapHal`] says. Other boards use
+    /// [`try_new_bcm2835`](SDHCIDriver::try_new_bcm2835).
     pub fn try_new() -> DevResult<SDHCIDriver> {
-        let mut ctrl = EmmcCtl::new();
-        if ctrl.init() == 0 {
-            log::info!("BCM2835 sdhci: successfully initialized");
-            Ok(SDHCIDriver(ctrl))
-        } else {
-            log::warn!("BCM2835 sdhci: init failed");
-            Err(DevError::Io)
-        }
+        let base = LinearMapHal::mmio_phys_to_virt(EMMC2_PADDR, 0x100);
+        let quirks = SdhciQuirks {
+            base_clock: Some(EMMC2_CLOCK),
+            ..SdhciQuirks::BCM2711
+        };
+        let host = unsafe { SdhciHost::try_new(base, "bcm2835_sdhci", quirks)? };
+        MmcCard::try_new(host).map(Self)
     }
 }
 
-fn deal_sdhci_err(err: SDHCIError) -> DevError {
-    match err {
-        SDHCIError::Io => DevError::Io,
-        SDHCIError::AlreadyExists => DevError::AlreadyExists,
-        SDHCIError::Again => DevError::Again,
-        SDHCIError::BadState => DevError::BadState,
-        SDHCIError::InvalidParam => DevError::InvalidParam,
-        SDHCIError::NoMemory => DevError::NoMemory,
-        SDHCIError::ResourceBusy => DevError::ResourceBusy,
-        SDHCIError::Unsupported => DevError::Unsupported,
+impl<H: DmaHal> SDHCIDriver<H> {
+    /// Initializes the Arasan controller whose registers are mapped at
+    /// `base` and the card in its slot, returns `Ok` if successful.
+    ///
+    /// The controller does not report its base clock, `base_clock` is the
+    /// frequency of the EMMC clock in Hz, from the device tree or the
+    /// firmware.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to the mapped EMMC registers, which must remain
+    /// valid for the lifetime of the driver.
+    pub unsafe fn try_new_bcm2835(base: NonNull<u8>, base_clock: u32) -> DevResult<Self> {
+        let quirks = SdhciQuirks {
+            base_clock: Some(base_clock),
+            ..SdhciQuirks::BCM2835
+        };
+        MmcCard::try_new(SdhciHost::try_new(base, "bcm2835_sdhci", quirks)?).map(Self)
+    }
+
+    /// The card driver.
+    pub fn inner(&self) -> &SdhciDriver<H> {
+        &self.0
+    }
+
+    /// The mutable card driver.
+    pub fn inner_mut(&mut self) -> &mut SdhciDriver<H> {
+        &mut self.0
+    }
+
+    /// Returns the card driver.
+    pub fn into_inner(self) -> SdhciDriver<H> {
+        self.0
     }
 }
 
-impl BaseDriverOps for SDHCIDriver {
+impl<H: DmaHal> BaseDriverOps for SDHCIDriver<H> {
     fn device_type(&self) -> DeviceType {
         DeviceType::Block
     }
 
     fn device_name(&self) -> &str {
-        "bcm2835_sdhci"
+        self.0.device_name()
     }
 }
 
-impl BlockDriverOps for SDHCIDriver {
+impl<H: DmaHal> BlockDriverOps for SDHCIDriver<H> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.0.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.0.block_size()
+    }
+
     fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
-        if buf.len() < BLOCK_SIZE {
-            return Err(DevError::InvalidParam);
-        }
-        let (prefix, aligned_buf, suffix) = unsafe { buf.align_to_mut::<u32>() };
-        if !prefix.is_empty() || !suffix.is_empty() {
-            return Err(DevError::InvalidParam);
-        }
-        self.0
-            .read_block(block_id as u32, 1, aligned_buf)
-            .map_err(deal_sdhci_err)
+        self.0.read_block(block_id, buf)
     }
 
     fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
-        if buf.len() < BLOCK_SIZE {
-            return Err(DevError::Io);
-        }
-        let (prefix, aligned_buf, suffix) = unsafe { buf.align_to::<u32>() };
-        if !prefix.is_empty() || !suffix.is_empty() {
-            return Err(DevError::InvalidParam);
-        }
-        self.0
-            .write_block(block_id as u32, 1, aligned_buf)
-            .map_err(deal_sdhci_err)
+        self.0.write_block(block_id, buf)
     }
+
     fn flush(&mut self) -> DevResult {
-        Ok(())
+        self.0.flush()
     }
 
-    #[inline]
-    fn num_blocks(&self) -> u64 {
-        self.0.get_block_num()
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        self.0.read_blocks_vectored(block_id, bufs)
     }
 
-    #[inline]
-    fn block_size(&self) -> usize {
-        self.0.get_block_size()
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        self.0.write_blocks_vectored(block_id, bufs)
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> DevResult {
+        self.0.discard(start, count)
+    }
+
+    fn discard_limits(&self) -> Option<DiscardLimits> {
+        self.0.discard_limits()
+    }
+
+    fn write_zeroes(&mut self, start: u64, count: u64, may_unmap: bool) -> DevResult {
+        self.0.write_zeroes(start, count, may_unmap)
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.0.capabilities()
+    }
+
+    fn media_generation(&self) -> u64 {
+        self.0.media_generation()
     }
 }
@@ -0,0 +1,117 @@
+//! DMA memory management shared by the drivers that perform DMA.
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use driver_common::{DevError, DevResult};
+
+/// Physical address type used by DMA operations.
+pub type PhysAddr = usize;
+
+/// The size of a DMA page in bytes.
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Platform services that DMA-capable block drivers rely on.
+///
+/// It must be implemented by the kernel, since only it knows how physical
+/// memory is allocated and mapped.
+pub trait DmaHal {
+    /// Allocates `pages` physically contiguous pages for DMA.
+    ///
+    /// Returns the physical address and the virtual address of the first page,
+    /// or `None` if there is not enough memory.
+    fn dma_alloc(pages: usize) -> Option<(PhysAddr, NonNull<u8>)>;
+
+    /// Deallocates pages previously allocated by [`DmaHal::dma_alloc`].
+    ///
+    /// # Safety
+    ///
+    /// The pages must have been allocated by `dma_alloc` with the same `pages`
+    /// count, and must not be accessed anymore.
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize);
+
+    /// Translates the virtual address of a buffer that will be shared with the
+    /// device to its physical address.
+    ///
+    /// The buffer is assumed to be physically contiguous.
+    fn virt_to_phys(vaddr: usize) -> PhysAddr;
+
+    /// Maps `size` bytes of device registers at physical address `paddr`, and
+    /// returns the virtual address.
+    fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8>;
+
+    /// Waits for at least `ns` nanoseconds, for controllers that need their
+    /// register accesses spaced in time.
+    ///
+    /// The default spins once per nanosecond, which is only long enough if
+    /// a spin takes at least a nanosecond. Platforms with a timer should
+    /// override it.
+    fn delay_ns(ns: u64) {
+        for _ in 0..ns {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// A physically contiguous, zero-initialized DMA buffer.
+///
+/// The memory is returned to the platform when the buffer is dropped.
+pub struct DmaBuffer<H: DmaHal> {
+    paddr: PhysAddr,
+    vaddr: NonNull<u8>,
+    pages: usize,
+    _hal: PhantomData<H>,
+}
+
+unsafe impl<H: DmaHal> Send for DmaBuffer<H> {}
+unsafe impl<H: DmaHal> Sync for DmaBuffer<H> {}
+
+impl<H: DmaHal> DmaBuffer<H> {
+    /// Allocates a DMA buffer that spans at least `size` bytes.
+    pub fn new(size: usize) -> DevResult<Self> {
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        let (paddr, vaddr) = H::dma_alloc(pages).ok_or(DevError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(vaddr.as_ptr(), 0, pages * PAGE_SIZE) };
+        Ok(Self {
+            paddr,
+            vaddr,
+            pages,
+            _hal: PhantomData,
+        })
+    }
+
+    /// The physical address of the buffer.
+    pub const fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    /// The raw pointer to the start of the buffer.
+    pub const fn as_ptr(&self) -> *mut u8 {
+        self.vaddr.as_ptr()
+    }
+
+    /// The size of the buffer in bytes.
+    pub const fn len(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// Whether the buffer is empty (never true for an allocated buffer).
+    pub const fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    /// Returns the buffer content as a byte slice.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    /// Returns the buffer content as a mutable byte slice.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.len()) }
+    }
+}
+
+impl<H: DmaHal> Drop for DmaBuffer<H> {
+    fn drop(&mut self) {
+        unsafe { H::dma_dealloc(self.paddr, self.vaddr, self.pages) };
+    }
+}
@@ -0,0 +1,603 @@
+//! Synopsys DesignWare Mobile Storage Host (dw_mshc) driver.
+//!
+//! This is the SD/eMMC controller of many RISC-V and ARM SoCs, such as the
+//! StarFive JH7110 (VisionFive 2). Data is moved by the internal DMA controller
+//! (IDMAC) when the controller has one and the buffer is suitably aligned, and
+//! through the data FIFO otherwise.
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::dma::{DmaBuffer, DmaHal, PAGE_SIZE};
+use crate::mmc::{
+    cmd, BusTiming, BusWidth, DataBuf, MmcCard, MmcCommand, MmcData, MmcHost, Response,
+    ResponseType,
+};
+use driver_common::{DevError, DevResult};
+
+/// The maximum number of polls before a controller operation times out.
+const SPIN_LIMIT: usize = 10_000_000;
+
+const DW_CTRL: usize = 0x00;
+const DW_PWREN: usize = 0x04;
+const DW_CLKDIV: usize = 0x08;
+const DW_CLKSRC: usize = 0x0c;
+const DW_CLKENA: usize = 0x10;
+const DW_TMOUT: usize = 0x14;
+const DW_CTYPE: usize = 0x18;
+const DW_BLKSIZ: usize = 0x1c;
+const DW_BYTCNT: usize = 0x20;
+const DW_INTMASK: usize = 0x24;
+const DW_CMDARG: usize = 0x28;
+const DW_CMD: usize = 0x2c;
+const DW_RESP0: usize = 0x30;
+const DW_RINTSTS: usize = 0x44;
+const DW_STATUS: usize = 0x48;
+const DW_FIFOTH: usize = 0x4c;
+const DW_CDETECT: usize = 0x50;
+const DW_VERID: usize = 0x6c;
+const DW_HCON: usize = 0x70;
+const DW_BMOD: usize = 0x80;
+const DW_PLDMND: usize = 0x84;
+const DW_DBADDR: usize = 0x88;
+const DW_IDSTS: usize = 0x8c;
+const DW_IDINTEN: usize = 0x90;
+/// With 64-bit IDMAC addressing, the registers after DBADDR move up by 4.
+const DW_DBADDRU: usize = 0x8c;
+const DW_IDSTS64: usize = 0x90;
+const DW_IDINTEN64: usize = 0x94;
+
+const CTRL_RESET: u32 = 1 << 0;
+const CTRL_FIFO_RESET: u32 = 1 << 1;
+const CTRL_DMA_RESET: u32 = 1 << 2;
+const CTRL_ALL_RESET: u32 = CTRL_RESET | CTRL_FIFO_RESET | CTRL_DMA_RESET;
+const CTRL_DMA_ENABLE: u32 = 1 << 5;
+const CTRL_USE_IDMAC: u32 = 1 << 25;
+
+const CTYPE_4BIT: u32 = 1 << 0;
+const CTYPE_8BIT: u32 = 1 << 16;
+
+const CMD_RESP_EXP: u32 = 1 << 6;
+const CMD_RESP_LONG: u32 = 1 << 7;
+const CMD_RESP_CRC: u32 = 1 << 8;
+const CMD_DATA_EXP: u32 = 1 << 9;
+const CMD_DATA_WR: u32 = 1 << 10;
+const CMD_SEND_STOP: u32 = 1 << 12;
+const CMD_PRV_DAT_WAIT: u32 = 1 << 13;
+const CMD_INIT: u32 = 1 << 15;
+const CMD_UPD_CLK: u32 = 1 << 21;
+const CMD_USE_HOLD_REG: u32 = 1 << 29;
+const CMD_START: u32 = 1 << 31;
+
+const INT_CD: u32 = 1 << 0;
+const INT_RE: u32 = 1 << 1;
+const INT_CMD_DONE: u32 = 1 << 2;
+const INT_DATA_OVER: u32 = 1 << 3;
+const INT_RCRC: u32 = 1 << 6;
+const INT_DCRC: u32 = 1 << 7;
+const INT_RTO: u32 = 1 << 8;
+const INT_DRTO: u32 = 1 << 9;
+const INT_FRUN: u32 = 1 << 11;
+const INT_HLE: u32 = 1 << 12;
+const INT_SBE: u32 = 1 << 13;
+const INT_EBE: u32 = 1 << 15;
+const INT_CMD_ERROR: u32 = INT_RE | INT_RCRC | INT_RTO | INT_HLE;
+const INT_DATA_ERROR: u32 = INT_DCRC | INT_DRTO | INT_FRUN | INT_SBE | INT_EBE;
+const INT_ALL: u32 = 0xffff_ffff;
+/// Every status but card detect, which is kept latched for `card_present`.
+const INT_TRANSFER: u32 = INT_ALL & !INT_CD;
+
+const STATUS_FIFO_EMPTY: u32 = 1 << 2;
+const STATUS_FIFO_FULL: u32 = 1 << 3;
+const STATUS_DATA_BUSY: u32 = 1 << 9;
+
+const HCON_DATA_WIDTH_32: u32 = 1;
+const HCON_DATA_WIDTH_64: u32 = 2;
+const HCON_TRANS_MODE_IDMAC: u32 = 0;
+const HCON_ADDR_CONFIG_64: u32 = 1 << 27;
+
+const BMOD_SWR: u32 = 1 << 0;
+const BMOD_FB: u32 = 1 << 1;
+const BMOD_DE: u32 = 1 << 7;
+
+const IDSTS_ERROR: u32 = (1 << 2) | (1 << 4) | (1 << 5) | (1 << 9);
+
+const DESC_OWN: u32 = 1 << 31;
+const DESC_CH: u32 = 1 << 4;
+const DESC_FS: u32 = 1 << 3;
+const DESC_LD: u32 = 1 << 2;
+const DESC_DIC: u32 = 1 << 1;
+
+/// Version 2.40a moved the data FIFO.
+const VERSION_240A: u32 = 0x240a;
+const DATA_OFFSET: usize = 0x100;
+const DATA_OFFSET_240A: usize = 0x200;
+
+const IDENT_CLOCK: u32 = 400_000;
+
+/// A DesignWare mobile storage host controller.
+///
+/// The card detect line is often not wired (and never is for eMMC), so it is
+/// ignored unless enabled with [`DwMmcHost::set_card_detect`].
+pub struct DwMmcHost<H: DmaHal> {
+    name: String,
+    base: NonNull<u8>,
+    bus_clock: u32,
+    data_offset: usize,
+    fifo_64bit: bool,
+    fifo_depth: u32,
+    idmac: bool,
+    addr_64bit: bool,
+    use_dma: bool,
+    card_detect: bool,
+    desc: DmaBuffer<H>,
+}
+
+/// A card behind a DesignWare host controller, as a block device.
+pub type DwMmcDriver<H> = MmcCard<DwMmcHost<H>>;
+
+unsafe impl<H: DmaHal> Send for DwMmcHost<H> {}
+unsafe impl<H: DmaHal> Sync for DwMmcHost<H> {}
+
+impl<H: DmaHal> DwMmcHost<H> {
+    /// Creates a host for the controller whose registers are mapped at `base`.
+    /// `bus_clock` is the frequency of the card input clock (`ciu`) in Hz.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to the mapped controller registers, which must remain
+    /// valid for the lifetime of the host.
+    pub unsafe fn try_new(base: NonNull<u8>, name: &str, bus_clock: u32) -> DevResult<Self> {
+        let mut host = Self {
+            name: String::from(name),
+            base,
+            bus_clock,
+            data_offset: DATA_OFFSET,
+            fifo_64bit: false,
+            fifo_depth: 0,
+            idmac: false,
+            addr_64bit: false,
+            use_dma: false,
+            card_detect: false,
+            desc: DmaBuffer::new(PAGE_SIZE)?,
+        };
+        let version = host.read(DW_VERID) & 0xffff;
+        if version >= VERSION_240A {
+            host.data_offset = DATA_OFFSET_240A;
+        }
+        let hcon = host.read(DW_HCON);
+        match (hcon >> 7) & 0x7 {
+            HCON_DATA_WIDTH_32 => {}
+            HCON_DATA_WIDTH_64 => host.fifo_64bit = true,
+            width => {
+                log::warn!("{}: unsupported FIFO width {}", name, 16 << width);
+                return Err(DevError::Unsupported);
+            }
+        }
+        host.idmac = (hcon >> 16) & 0x3 == HCON_TRANS_MODE_IDMAC;
+        host.addr_64bit = hcon & HCON_ADDR_CONFIG_64 != 0;
+        host.use_dma = host.idmac;
+        host.fifo_depth = ((host.read(DW_FIFOTH) >> 16) & 0xfff) + 1;
+        log::info!(
+            "{}: version {:#x}, FIFO depth {}, IDMAC: {}",
+            name,
+            version,
+            host.fifo_depth,
+            host.idmac
+        );
+        Ok(host)
+    }
+
+    /// Enables or disables card detection through the card detect line, for
+    /// slots where it is wired.
+    pub fn set_card_detect(&mut self, enabled: bool) {
+        self.card_detect = enabled;
+    }
+
+    /// Enables or disables transfers through the internal DMA controller.
+    /// DMA cannot be enabled if the controller has no IDMAC.
+    pub fn set_dma_enabled(&mut self, enabled: bool) {
+        self.use_dma = enabled && self.idmac;
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { (self.base.as_ptr().add(offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&mut self, offset: usize, val: u32) {
+        unsafe { (self.base.as_ptr().add(offset) as *mut u32).write_volatile(val) }
+    }
+
+    fn wait(&self, offset: usize, mask: u32, set: bool) -> DevResult {
+        for _ in 0..SPIN_LIMIT {
+            if (self.read(offset) & mask != 0) == set {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        log::warn!("{}: timeout on register {:#x}", self.name, offset);
+        Err(DevError::Io)
+    }
+
+    fn reset(&mut self, mask: u32) -> DevResult {
+        let ctrl = self.read(DW_CTRL);
+        self.write(DW_CTRL, ctrl | mask);
+        self.wait(DW_CTRL, mask, false)
+    }
+
+    /// Asks the controller to load the clock registers into the card clock
+    /// domain.
+    fn update_clock(&mut self) -> DevResult {
+        self.write(
+            DW_CMD,
+            CMD_START | CMD_UPD_CLK | CMD_PRV_DAT_WAIT | CMD_USE_HOLD_REG,
+        );
+        self.wait(DW_CMD, CMD_START, false)
+    }
+
+    /// Waits for `mask` in the raw interrupt status, returns an error if one
+    /// of `errors` is raised instead.
+    fn wait_int(&mut self, mask: u32, errors: u32) -> DevResult<u32> {
+        for _ in 0..SPIN_LIMIT {
+            let status = self.read(DW_RINTSTS);
+            if status & errors != 0 {
+                self.write(DW_RINTSTS, status & INT_TRANSFER);
+                log::warn!("{}: error interrupt {:#x}", self.name, status);
+                return Err(DevError::Io);
+            }
+            if status & mask != 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        log::warn!("{}: timeout waiting for interrupt {:#x}", self.name, mask);
+        Err(DevError::Io)
+    }
+
+    /// Recovers the FIFO and DMA state machine after a failed transfer.
+    fn recover(&mut self) {
+        let ctrl = self.read(DW_CTRL);
+        self.write(DW_CTRL, ctrl & !(CTRL_DMA_ENABLE | CTRL_USE_IDMAC));
+        let _ = self.reset(CTRL_FIFO_RESET | CTRL_DMA_RESET);
+        self.write(DW_RINTSTS, INT_TRANSFER);
+    }
+
+    fn fifo_read(&mut self) -> u64 {
+        let ptr = unsafe { self.base.as_ptr().add(self.data_offset) };
+        if self.fifo_64bit {
+            unsafe { (ptr as *const u64).read_volatile() }
+        } else {
+            unsafe { (ptr as *const u32).read_volatile() as u64 }
+        }
+    }
+
+    fn fifo_write(&mut self, val: u64) {
+        let ptr = unsafe { self.base.as_ptr().add(self.data_offset) };
+        if self.fifo_64bit {
+            unsafe { (ptr as *mut u64).write_volatile(val) }
+        } else {
+            unsafe { (ptr as *mut u32).write_volatile(val as u32) }
+        }
+    }
+
+    const fn fifo_word(&self) -> usize {
+        if self.fifo_64bit {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Waits until none of `mask` is set in the status register, returns an
+    /// error if a data error is raised meanwhile.
+    fn wait_fifo(&mut self, mask: u32) -> DevResult {
+        for _ in 0..SPIN_LIMIT {
+            if self.read(DW_STATUS) & mask == 0 {
+                return Ok(());
+            }
+            let status = self.read(DW_RINTSTS);
+            if status & INT_DATA_ERROR != 0 {
+                log::warn!("{}: data error {:#x}", self.name, status);
+                return Err(DevError::Io);
+            }
+            core::hint::spin_loop();
+        }
+        log::warn!("{}: FIFO timeout", self.name);
+        Err(DevError::Io)
+    }
+
+    fn transfer_pio(&mut self, buf: DataBuf) -> DevResult {
+        let word = self.fifo_word();
+        match buf {
+            DataBuf::Read(buf) => {
+                for chunk in buf.chunks_mut(word) {
+                    self.wait_fifo(STATUS_FIFO_EMPTY)?;
+                    let val = self.fifo_read().to_le_bytes();
+                    chunk.copy_from_slice(&val[..chunk.len()]);
+                }
+            }
+            DataBuf::Write(buf) => {
+                for chunk in buf.chunks(word) {
+                    self.wait_fifo(STATUS_FIFO_FULL)?;
+                    let mut val = [0u8; 8];
+                    val[..chunk.len()].copy_from_slice(chunk);
+                    self.fifo_write(u64::from_le_bytes(val));
+                }
+            }
+        }
+        self.wait_int(INT_DATA_OVER, INT_DATA_ERROR)?;
+        Ok(())
+    }
+
+    /// Fills the IDMAC descriptor chain for the buffer at `vaddr`, one
+    /// descriptor per physical page. Returns `false` if it does not fit, or
+    /// if the buffer or the chain is out of reach of a 32-bit IDMAC.
+    fn setup_descriptors(&mut self, vaddr: usize, len: usize) -> bool {
+        let desc_size = if self.addr_64bit { 32 } else { 16 };
+        let max_descs = PAGE_SIZE / desc_size;
+        let first_page = vaddr / PAGE_SIZE;
+        let last_page = (vaddr + len - 1) / PAGE_SIZE;
+        let count = last_page - first_page + 1;
+        if count > max_descs {
+            return false;
+        }
+
+        let table = self.desc.as_ptr() as *mut u32;
+        let table_paddr = self.desc.paddr();
+        if !self.addr_64bit && table_paddr + PAGE_SIZE - 1 > u32::MAX as usize {
+            return false;
+        }
+        let mut offset = 0;
+        for i in 0..count {
+            let chunk = (PAGE_SIZE - (vaddr + offset) % PAGE_SIZE).min(len - offset);
+            let paddr = H::virt_to_phys(vaddr + offset);
+            if !self.addr_64bit && paddr + chunk - 1 > u32::MAX as usize {
+                return false;
+            }
+            let next = table_paddr + (i + 1) % count * desc_size;
+            let mut flags = DESC_OWN | DESC_CH;
+            if i == 0 {
+                flags |= DESC_FS;
+            }
+            if i == count - 1 {
+                flags |= DESC_LD;
+            } else {
+                flags |= DESC_DIC;
+            }
+            unsafe {
+                let desc = table.add(i * desc_size / 4);
+                if self.addr_64bit {
+                    desc.write_volatile(flags);
+                    desc.add(1).write_volatile(0);
+                    desc.add(2).write_volatile(chunk as u32);
+                    desc.add(3).write_volatile(0);
+                    desc.add(4).write_volatile(paddr as u32);
+                    desc.add(5).write_volatile((paddr as u64 >> 32) as u32);
+                    desc.add(6).write_volatile(next as u32);
+                    desc.add(7).write_volatile((next as u64 >> 32) as u32);
+                } else {
+                    desc.write_volatile(flags);
+                    desc.add(1).write_volatile(chunk as u32);
+                    desc.add(2).write_volatile(paddr as u32);
+                    desc.add(3).write_volatile(next as u32);
+                }
+            }
+            offset += chunk;
+        }
+        fence(Ordering::SeqCst);
+        true
+    }
+
+    fn start_dma(&mut self) {
+        let paddr = self.desc.paddr() as u64;
+        self.write(DW_DBADDR, paddr as u32);
+        if self.addr_64bit {
+            self.write(DW_DBADDRU, (paddr >> 32) as u32);
+            self.write(DW_IDINTEN64, 0);
+            self.write(DW_IDSTS64, INT_ALL);
+        } else {
+            self.write(DW_IDINTEN, 0);
+            self.write(DW_IDSTS, INT_ALL);
+        }
+        self.write(DW_BMOD, BMOD_DE | BMOD_FB);
+        let ctrl = self.read(DW_CTRL);
+        self.write(DW_CTRL, ctrl | CTRL_DMA_ENABLE | CTRL_USE_IDMAC);
+    }
+
+    fn finish_dma(&mut self) -> DevResult {
+        let result = self.wait_int(INT_DATA_OVER, INT_DATA_ERROR);
+        let idsts = if self.addr_64bit {
+            self.read(DW_IDSTS64)
+        } else {
+            self.read(DW_IDSTS)
+        };
+        let ctrl = self.read(DW_CTRL);
+        self.write(DW_CTRL, ctrl & !(CTRL_DMA_ENABLE | CTRL_USE_IDMAC));
+        result?;
+        if idsts & IDSTS_ERROR != 0 {
+            log::warn!("{}: IDMAC error {:#x}", self.name, idsts);
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    fn do_command(&mut self, cmd: &MmcCommand, data: Option<MmcData>) -> DevResult<Response> {
+        let mut command = CMD_START | CMD_USE_HOLD_REG | cmd.index as u32;
+        command |= match cmd.resp {
+            ResponseType::None => 0,
+            ResponseType::R1 | ResponseType::R1b => CMD_RESP_EXP | CMD_RESP_CRC,
+            ResponseType::R2 => CMD_RESP_EXP | CMD_RESP_LONG | CMD_RESP_CRC,
+            ResponseType::R3 => CMD_RESP_EXP,
+        };
+        if cmd.index == cmd::GO_IDLE_STATE {
+            command |= CMD_INIT;
+        } else {
+            command |= CMD_PRV_DAT_WAIT;
+        }
+
+        self.wait(DW_STATUS, STATUS_DATA_BUSY, false)?;
+        self.write(DW_RINTSTS, INT_TRANSFER);
+
+        let mut dma = false;
+        if let Some(data) = &data {
+            let (vaddr, len) = match &data.buf {
+                DataBuf::Read(buf) => (buf.as_ptr() as usize, buf.len()),
+                DataBuf::Write(buf) => (buf.as_ptr() as usize, buf.len()),
+            };
+            let bytes = data.block_size * data.blocks;
+            if len < bytes || bytes == 0 {
+                return Err(DevError::InvalidParam);
+            }
+            command |= CMD_DATA_EXP;
+            if matches!(data.buf, DataBuf::Write(_)) {
+                command |= CMD_DATA_WR;
+            }
+            if data.blocks > 1 {
+                command |= CMD_SEND_STOP;
+            }
+            self.write(DW_BLKSIZ, data.block_size as u32);
+            self.write(DW_BYTCNT, bytes as u32);
+            self.reset(CTRL_FIFO_RESET)?;
+            dma = self.use_dma && vaddr % 4 == 0 && self.setup_descriptors(vaddr, bytes);
+            if dma {
+                self.start_dma();
+            }
+        }
+
+        self.write(DW_CMDARG, cmd.arg);
+        self.write(DW_CMD, command);
+        if dma {
+            self.write(DW_PLDMND, 1);
+        }
+        self.wait_int(INT_CMD_DONE, INT_CMD_ERROR)?;
+
+        let resp = match cmd.resp {
+            ResponseType::None => Response::None,
+            ResponseType::R2 => {
+                let mut val = 0u128;
+                for i in 0..4 {
+                    val |= (self.read(DW_RESP0 + i * 4) as u128) << (i * 32);
+                }
+                Response::Long(val & !0xff)
+            }
+            _ => Response::Short(self.read(DW_RESP0)),
+        };
+
+        match data {
+            Some(_) if dma => self.finish_dma()?,
+            Some(data) => {
+                let bytes = data.block_size * data.blocks;
+                match data.buf {
+                    DataBuf::Read(buf) => self.transfer_pio(DataBuf::Read(&mut buf[..bytes]))?,
+                    DataBuf::Write(buf) => self.transfer_pio(DataBuf::Write(&buf[..bytes]))?,
+                }
+            }
+            None if cmd.resp == ResponseType::R1b => {
+                self.wait(DW_STATUS, STATUS_DATA_BUSY, false)?
+            }
+            None => {}
+        }
+        Ok(resp)
+    }
+}
+
+impl<H: DmaHal> MmcHost for DwMmcHost<H> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn init(&mut self) -> DevResult {
+        self.write(DW_PWREN, 1);
+        self.reset(CTRL_ALL_RESET)?;
+        if self.idmac {
+            self.write(DW_BMOD, BMOD_SWR);
+            self.wait(DW_BMOD, BMOD_SWR, false)?;
+        }
+
+        // Completion is polled, no interrupt is unmasked.
+        self.write(DW_INTMASK, 0);
+        self.write(DW_RINTSTS, INT_ALL);
+        self.write(DW_TMOUT, 0xffff_ffff);
+        let half = self.fifo_depth / 2;
+        self.write(DW_FIFOTH, (2 << 28) | ((half - 1) << 16) | half);
+
+        self.set_clock(IDENT_CLOCK)?;
+        self.set_bus_width(BusWidth::Width1)
+    }
+
+    fn card_present(&mut self) -> bool {
+        if !self.card_detect {
+            return true;
+        }
+        // Any change of the line is latched, report it as a removal so that
+        // a replaced card is initialized again.
+        if self.read(DW_RINTSTS) & INT_CD != 0 {
+            self.write(DW_RINTSTS, INT_CD);
+            return false;
+        }
+        self.read(DW_CDETECT) & 1 == 0
+    }
+
+    fn set_clock(&mut self, hz: u32) -> DevResult {
+        if hz == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        // The card clock is bus_clock / (2 * div), or bus_clock when div is 0.
+        let div = if self.bus_clock <= hz {
+            0
+        } else {
+            self.bus_clock.div_ceil(2 * hz).min(0xff)
+        };
+        self.write(DW_CLKENA, 0);
+        self.write(DW_CLKSRC, 0);
+        self.update_clock()?;
+        self.write(DW_CLKDIV, div);
+        self.update_clock()?;
+        self.write(DW_CLKENA, 1);
+        self.update_clock()
+    }
+
+    fn supports_bus_width(&self, width: BusWidth) -> bool {
+        !matches!(width, BusWidth::Width8)
+    }
+
+    fn set_bus_width(&mut self, width: BusWidth) -> DevResult {
+        let ctype = match width {
+            BusWidth::Width1 => 0,
+            BusWidth::Width4 => CTYPE_4BIT,
+            BusWidth::Width8 => CTYPE_8BIT,
+        };
+        self.write(DW_CTYPE, ctype);
+        Ok(())
+    }
+
+    fn supports_timing(&self, timing: BusTiming) -> bool {
+        // High speed only needs a faster clock. The UHS-I modes would need
+        // the 1.8V regulator of the board.
+        matches!(timing, BusTiming::Default | BusTiming::HighSpeed)
+    }
+
+    fn set_timing(&mut self, timing: BusTiming) -> DevResult {
+        if !self.supports_timing(timing) {
+            return Err(DevError::Unsupported);
+        }
+        Ok(())
+    }
+
+    fn reset_lines(&mut self) -> DevResult {
+        self.recover();
+        Ok(())
+    }
+
+    fn send_command(&mut self, cmd: &MmcCommand, data: Option<MmcData>) -> DevResult<Response> {
+        let has_data = data.is_some();
+        let result = self.do_command(cmd, data);
+        if result.is_err() && has_data {
+            self.recover();
+        }
+        result
+    }
+}
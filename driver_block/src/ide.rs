@@ -0,0 +1,383 @@
+//! Legacy ATA/IDE disk driver using programmed I/O (PIO).
+//!
+//! It needs no DMA and no interrupts, only the port I/O of the two legacy
+//! channels, which makes it the simplest disk to bring up on x86 (QEMU `-hda`
+//! attaches exactly this).
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::{BlockCapabilities, BlockDriverOps};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// The maximum number of status polls before a command times out.
+const SPIN_LIMIT: usize = 10_000_000;
+
+const SECTOR_SIZE: usize = 512;
+
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+const REG_ALT_STATUS: u16 = 0;
+const REG_DEVICE_CONTROL: u16 = 0;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Disables the channel interrupt, we always poll.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xe7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xea;
+const CMD_IDENTIFY: u8 = 0xec;
+
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Port I/O accessors, provided by the platform.
+pub trait PortIo {
+    /// Reads a byte from `port`.
+    fn inb(port: u16) -> u8;
+    /// Writes a byte to `port`.
+    fn outb(port: u16, val: u8);
+    /// Reads a 16-bit word from `port`.
+    fn inw(port: u16) -> u16;
+    /// Writes a 16-bit word to `port`.
+    fn outw(port: u16, val: u16);
+}
+
+/// Port I/O with the x86 `in`/`out` instructions.
+#[cfg(target_arch = "x86_64")]
+pub struct X86PortIo;
+
+#[cfg(target_arch = "x86_64")]
+impl PortIo for X86PortIo {
+    fn inb(port: u16) -> u8 {
+        let val: u8;
+        unsafe { core::arch::asm!("in al, dx", out("al") val, in("dx") port, options(nostack)) };
+        val
+    }
+
+    fn outb(port: u16, val: u8) {
+        unsafe { core::arch::asm!("out dx, al", in("dx") port, in("al") val, options(nostack)) };
+    }
+
+    fn inw(port: u16) -> u16 {
+        let val: u16;
+        unsafe { core::arch::asm!("in ax, dx", out("ax") val, in("dx") port, options(nostack)) };
+        val
+    }
+
+    fn outw(port: u16, val: u16) {
+        unsafe { core::arch::asm!("out dx, ax", in("dx") port, in("ax") val, options(nostack)) };
+    }
+}
+
+/// One of the two legacy ATA channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdeChannel {
+    /// The primary channel, at ports `0x1f0`/`0x3f6`.
+    Primary,
+    /// The secondary channel, at ports `0x170`/`0x376`.
+    Secondary,
+}
+
+impl IdeChannel {
+    const fn ports(self) -> (u16, u16) {
+        match self {
+            Self::Primary => (0x1f0, 0x3f6),
+            Self::Secondary => (0x170, 0x376),
+        }
+    }
+}
+
+/// A disk attached to a legacy ATA channel.
+pub struct IdeDisk<P: PortIo> {
+    name: &'static str,
+    io_base: u16,
+    ctrl_base: u16,
+    slave: bool,
+    lba48: bool,
+    num_blocks: u64,
+    caps: BlockCapabilities,
+    _port_io: PhantomData<fn() -> P>,
+}
+
+impl<P: PortIo> IdeDisk<P> {
+    /// Probes the given drive (master or slave) of `channel`, and returns the
+    /// disk if an ATA disk answers IDENTIFY DEVICE.
+    pub fn try_new(channel: IdeChannel, slave: bool) -> DevResult<Self> {
+        let (io_base, ctrl_base) = channel.ports();
+        let name = match (channel, slave) {
+            (IdeChannel::Primary, false) => "ide-hda",
+            (IdeChannel::Primary, true) => "ide-hdb",
+            (IdeChannel::Secondary, false) => "ide-hdc",
+            (IdeChannel::Secondary, true) => "ide-hdd",
+        };
+        let mut disk = Self {
+            name,
+            io_base,
+            ctrl_base,
+            slave,
+            lba48: false,
+            num_blocks: 0,
+            caps: BlockCapabilities::new(0, SECTOR_SIZE),
+            _port_io: PhantomData,
+        };
+        disk.identify()?;
+        log::info!(
+            "{}: ATA disk, {} sectors, lba48={}",
+            name,
+            disk.num_blocks,
+            disk.lba48
+        );
+        Ok(disk)
+    }
+
+    /// Probes all four legacy drive positions and returns the disks found.
+    pub fn probe_all() -> Vec<Self> {
+        [IdeChannel::Primary, IdeChannel::Secondary]
+            .into_iter()
+            .flat_map(|channel| [false, true].map(|slave| (channel, slave)))
+            .filter_map(|(channel, slave)| Self::try_new(channel, slave).ok())
+            .collect()
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        P::inb(self.io_base + reg)
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        P::outb(self.io_base + reg, val)
+    }
+
+    /// Waits 400ns for the status to become valid after a drive select or a
+    /// command, by reading the alternate status 4 times.
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            P::inb(self.ctrl_base + REG_ALT_STATUS);
+        }
+    }
+
+    fn wait_not_busy(&self) -> DevResult<u8> {
+        for _ in 0..SPIN_LIMIT {
+            let status = self.read(REG_STATUS);
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(DevError::Io)
+    }
+
+    /// Waits until the drive is ready to transfer a sector.
+    fn wait_drq(&self) -> DevResult {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            log::warn!(
+                "{}: status {:#x}, error {:#x}",
+                self.name,
+                status,
+                self.read(REG_ERROR)
+            );
+            return Err(DevError::Io);
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    fn select(&mut self, head: u8) -> DevResult {
+        let drive = head | if self.slave { 1 << 4 } else { 0 };
+        self.write(REG_DRIVE, drive);
+        self.delay_400ns();
+        self.wait_not_busy().map(|_| ())
+    }
+
+    fn identify(&mut self) -> DevResult {
+        P::outb(self.ctrl_base + REG_DEVICE_CONTROL, CONTROL_NIEN);
+        // A floating bus reads as 0xff.
+        if self.read(REG_STATUS) == 0xff {
+            return Err(DevError::Unsupported);
+        }
+        self.select(0xa0)?;
+        for reg in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.write(reg, 0);
+        }
+        self.write(REG_COMMAND, CMD_IDENTIFY);
+        self.delay_400ns();
+        if self.read(REG_STATUS) == 0 {
+            return Err(DevError::Unsupported);
+        }
+        self.wait_not_busy()?;
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            // ATAPI or SATA signature, not a PATA disk.
+            return Err(DevError::Unsupported);
+        }
+        self.wait_drq()?;
+
+        let mut id = [0u16; SECTOR_SIZE / 2];
+        for word in id.iter_mut() {
+            *word = P::inw(self.io_base + REG_DATA);
+        }
+        if id[49] & (1 << 9) == 0 {
+            // LBA is not supported.
+            return Err(DevError::Unsupported);
+        }
+        self.lba48 = id[83] & (1 << 10) != 0;
+        self.num_blocks = if self.lba48 {
+            (0..4).fold(0, |acc, i| acc | (id[100 + i] as u64) << (16 * i))
+        } else {
+            id[60] as u64 | (id[61] as u64) << 16
+        };
+        self.caps = BlockCapabilities {
+            max_transfer: self.max_sectors() * SECTOR_SIZE,
+            ..crate::ata::identify_capabilities(&id, self.num_blocks)
+        };
+        Ok(())
+    }
+
+    /// Programs the task file for a transfer of `count` sectors at `lba`, and
+    /// issues the command. Fails if LBA48 is needed but unsupported.
+    fn start(&mut self, lba: u64, count: usize, lba28_cmd: u8, lba48_cmd: u8) -> DevResult {
+        if lba + count as u64 <= LBA28_LIMIT && count <= 256 {
+            self.select(0xe0 | ((lba >> 24) & 0xf) as u8)?;
+            self.write(REG_SECTOR_COUNT, count as u8);
+            self.write(REG_LBA_LOW, lba as u8);
+            self.write(REG_LBA_MID, (lba >> 8) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            self.write(REG_COMMAND, lba28_cmd);
+        } else if self.lba48 {
+            self.select(0x40)?;
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+            self.write(REG_SECTOR_COUNT, count as u8);
+            self.write(REG_LBA_LOW, lba as u8);
+            self.write(REG_LBA_MID, (lba >> 8) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            self.write(REG_COMMAND, lba48_cmd);
+        } else {
+            return Err(DevError::Unsupported);
+        }
+        self.delay_400ns();
+        Ok(())
+    }
+
+    fn check_range(&self, block_id: u64, len: usize) -> DevResult {
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        match block_id.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.num_blocks => Ok(()),
+            _ => Err(DevError::Io),
+        }
+    }
+
+    /// The maximum number of sectors per command.
+    const fn max_sectors(&self) -> usize {
+        if self.lba48 {
+            65536
+        } else {
+            256
+        }
+    }
+}
+
+impl<P: PortIo> BaseDriverOps for IdeDisk<P> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        self.name
+    }
+}
+
+impl<P: PortIo> BlockDriverOps for IdeDisk<P> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.check_range(block_id, buf.len())?;
+        let mut lba = block_id;
+        for chunk in buf.chunks_mut(self.max_sectors() * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.start(lba, count, CMD_READ_SECTORS, CMD_READ_SECTORS_EXT)?;
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                self.wait_drq()?;
+                for word in sector.chunks_exact_mut(2) {
+                    word.copy_from_slice(&P::inw(self.io_base + REG_DATA).to_le_bytes());
+                }
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.check_range(block_id, buf.len())?;
+        let mut lba = block_id;
+        for chunk in buf.chunks(self.max_sectors() * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.start(lba, count, CMD_WRITE_SECTORS, CMD_WRITE_SECTORS_EXT)?;
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                self.wait_drq()?;
+                for word in sector.chunks_exact(2) {
+                    P::outw(
+                        self.io_base + REG_DATA,
+                        u16::from_le_bytes([word[0], word[1]]),
+                    );
+                }
+            }
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(DevError::Io);
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.select(0xa0)?;
+        let cmd = if self.lba48 {
+            CMD_CACHE_FLUSH_EXT
+        } else {
+            CMD_CACHE_FLUSH
+        };
+        self.write(REG_COMMAND, cmd);
+        self.delay_400ns();
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.caps
+    }
+}
@@ -4,14 +4,196 @@
 #![feature(doc_auto_cfg)]
 #![feature(const_trait_impl)]
 
+pub mod async_ops;
+pub mod dma;
 pub mod ramdisk;
+pub mod recovery;
+pub mod request_queue;
+
+#[cfg(feature = "pci")]
+pub mod pci;
 
 #[cfg(feature = "bcm2835-sdhci")]
 pub mod bcm2835sdhci;
 
+#[cfg(feature = "virtio-blk")]
+pub mod virtio_blk;
+
+#[cfg(feature = "nvme")]
+pub mod nvme;
+
+#[cfg(feature = "ahci")]
+pub mod ahci;
+
+#[cfg(any(feature = "ahci", feature = "ide"))]
+mod ata;
+
+#[cfg(feature = "ide")]
+pub mod ide;
+
+#[cfg(any(feature = "sdhci", feature = "dw-mmc", feature = "sd-spi"))]
+pub mod mmc;
+
+#[cfg(feature = "sdhci")]
+pub mod sdhci;
+
+#[cfg(feature = "dw-mmc")]
+pub mod dw_mmc;
+
+#[cfg(feature = "sd-spi")]
+pub mod sd_spi;
+
 #[doc(no_inline)]
 pub use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
 
+/// The limits of the discard operation of a device, in blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiscardLimits {
+    /// The unit in which blocks are discarded. Only the whole units inside a
+    /// discarded range are guaranteed to be discarded.
+    pub granularity: u64,
+    /// The maximum number of blocks discarded by one request.
+    pub max_blocks: u64,
+}
+
+/// The capabilities and the geometry of a block device, for callers to size
+/// and align their I/O.
+///
+/// Sizes are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockCapabilities {
+    /// The number of blocks, as [`BlockDriverOps::num_blocks`].
+    pub num_blocks: u64,
+    /// The size of each block, as [`BlockDriverOps::block_size`].
+    pub block_size: usize,
+    /// The unit the device writes internally. Writing a part of it may be
+    /// slower, or less reliable across power loss.
+    pub physical_block_size: usize,
+    /// The offset of the first physical block boundary from the start of the
+    /// device.
+    pub alignment_offset: usize,
+    /// The smallest I/O size without a performance penalty.
+    pub min_io_size: usize,
+    /// The preferred size of sustained I/O, 0 if the device does not tell.
+    pub optimal_io_size: usize,
+    /// The largest transfer done by one device command, larger ones are
+    /// split by the driver.
+    pub max_transfer: usize,
+    /// The largest number of buffers of a vectored transfer done by one
+    /// device command.
+    pub max_segments: usize,
+    /// Whether the medium is rotational, i.e. seeks are expensive.
+    pub rotational: bool,
+    /// Whether the medium can be removed.
+    pub removable: bool,
+    /// Whether the device rejects writes.
+    pub read_only: bool,
+    /// Whether the device caches writes, which are durable only after
+    /// [`BlockDriverOps::flush`].
+    pub write_cache: bool,
+    /// Whether single writes can bypass the write cache (forced unit
+    /// access).
+    pub fua: bool,
+    /// The limits of [`BlockDriverOps::discard`], `None` if the device
+    /// cannot discard blocks.
+    pub discard: Option<DiscardLimits>,
+    /// The number of requests the device can keep in flight, 1 if it takes
+    /// one command at a time.
+    pub queue_depth: usize,
+}
+
+impl BlockCapabilities {
+    /// The capabilities of a device of `num_blocks` blocks of `block_size`
+    /// bytes about which nothing else is known: physical blocks are logical
+    /// blocks, transfers are not limited, vectored transfers are split,
+    /// writes may be cached, and requests are queued one at a time.
+    pub const fn new(num_blocks: u64, block_size: usize) -> Self {
+        Self {
+            num_blocks,
+            block_size,
+            physical_block_size: block_size,
+            alignment_offset: 0,
+            min_io_size: block_size,
+            optimal_io_size: 0,
+            max_transfer: usize::MAX,
+            max_segments: 1,
+            rotational: false,
+            removable: false,
+            read_only: false,
+            write_cache: true,
+            fua: false,
+            discard: None,
+            queue_depth: 1,
+        }
+    }
+}
+
+/// The size of the buffer of zeros written by [`write_zeroes_fallback`].
+const ZERO_BUF_SIZE: usize = 0x10000;
+
+/// A buffer of zeros shared by all devices, aligned to a page so that DMA
+/// engines can use it directly.
+#[repr(align(4096))]
+struct ZeroBuf([u8; ZERO_BUF_SIZE]);
+
+static ZEROES: ZeroBuf = ZeroBuf([0; ZERO_BUF_SIZE]);
+
+/// Zeroes `count` blocks starting at `start` by writing them with a shared
+/// buffer of zeros, for devices without a native write-zeroes command.
+///
+/// Blocks larger than 64 KiB are not supported.
+pub fn write_zeroes_fallback<D: BlockDriverOps + ?Sized>(
+    dev: &mut D,
+    start: u64,
+    count: u64,
+) -> DevResult {
+    let block_size = dev.block_size();
+    if count == 0 || block_size == 0 {
+        return Err(DevError::InvalidParam);
+    }
+    if block_size > ZERO_BUF_SIZE {
+        return Err(DevError::Unsupported);
+    }
+    let end = match start.checked_add(count) {
+        Some(end) if end <= dev.num_blocks() => end,
+        _ => return Err(DevError::Io),
+    };
+    let chunk_blocks = (ZERO_BUF_SIZE / block_size) as u64;
+    let mut block_id = start;
+    while block_id < end {
+        let blocks = (end - block_id).min(chunk_blocks);
+        dev.write_block(block_id, &ZEROES.0[..blocks as usize * block_size])?;
+        block_id += blocks;
+    }
+    Ok(())
+}
+
+/// Checks the segments of a vectored request for `block_id`, given their
+/// lengths, and returns the number of blocks it spans.
+///
+/// Every segment must be a non-empty multiple of the block size.
+pub(crate) fn vectored_blocks<D: BlockDriverOps + ?Sized>(
+    dev: &D,
+    block_id: u64,
+    lens: impl Iterator<Item = usize>,
+) -> DevResult<u64> {
+    let block_size = dev.block_size();
+    let mut blocks = 0u64;
+    for len in lens {
+        if len == 0 || len % block_size != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        blocks += (len / block_size) as u64;
+    }
+    if blocks == 0 {
+        return Err(DevError::InvalidParam);
+    }
+    match block_id.checked_add(blocks) {
+        Some(end) if end <= dev.num_blocks() => Ok(blocks),
+        _ => Err(DevError::Io),
+    }
+}
+
 /// Operations that require a block storage device driver to implement.
 pub trait BlockDriverOps: BaseDriverOps {
     /// The number of blocks in this storage device.
@@ -35,4 +217,83 @@ pub trait BlockDriverOps: BaseDriverOps {
 
     /// Flushes the device to write all pending data to the storage.
     fn flush(&mut self) -> DevResult;
+
+    /// Reads contiguous blocks starting at `block_id` into a list of
+    /// buffers, filled in order.
+    ///
+    /// Each buffer must be a multiple of the block size. The default reads
+    /// each buffer with [`read_block`](Self::read_block), devices that can
+    /// scatter one transfer into several buffers override it.
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        vectored_blocks(self, block_id, bufs.iter().map(|buf| buf.len()))?;
+        let mut block_id = block_id;
+        for buf in bufs.iter_mut() {
+            self.read_block(block_id, buf)?;
+            block_id += (buf.len() / self.block_size()) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes a list of buffers, in order, to contiguous blocks starting at
+    /// `block_id`.
+    ///
+    /// Each buffer must be a multiple of the block size. The default writes
+    /// each buffer with [`write_block`](Self::write_block), devices that can
+    /// gather one transfer from several buffers override it.
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        vectored_blocks(self, block_id, bufs.iter().map(|buf| buf.len()))?;
+        let mut block_id = block_id;
+        for buf in bufs {
+            self.write_block(block_id, buf)?;
+            block_id += (buf.len() / self.block_size()) as u64;
+        }
+        Ok(())
+    }
+
+    /// Discards `count` blocks starting at `start`: the device is told their
+    /// content is no longer needed, and may read back anything for them
+    /// until they are written again.
+    ///
+    /// Devices that cannot discard blocks fail with
+    /// [`DevError::Unsupported`], which is the default.
+    fn discard(&mut self, _start: u64, _count: u64) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// The limits of [`discard`](Self::discard), `None` if the device cannot
+    /// discard blocks.
+    fn discard_limits(&self) -> Option<DiscardLimits> {
+        None
+    }
+
+    /// Writes zeros to `count` blocks starting at `start`. They read back as
+    /// zeros until they are written again.
+    ///
+    /// If `may_unmap` is true, the device may deallocate the blocks instead
+    /// of writing them, as long as they still read back as zeros.
+    ///
+    /// The default writes them with [`write_zeroes_fallback`], devices with
+    /// a native command override it.
+    fn write_zeroes(&mut self, start: u64, count: u64, _may_unmap: bool) -> DevResult {
+        write_zeroes_fallback(self, start, count)
+    }
+
+    /// The capabilities and the geometry of the device.
+    ///
+    /// The default is [`BlockCapabilities::new`] with the
+    /// [`discard_limits`](Self::discard_limits) of the device, drivers that
+    /// know more about it override it.
+    fn capabilities(&self) -> BlockCapabilities {
+        BlockCapabilities {
+            discard: self.discard_limits(),
+            ..BlockCapabilities::new(self.num_blocks(), self.block_size())
+        }
+    }
+
+    /// The generation of the medium, which changes each time the medium is
+    /// removed or replaced. Data cached from the device must be dropped when
+    /// it changes. Devices with fixed media always return 0.
+    fn media_generation(&self) -> u64 {
+        0
+    }
 }
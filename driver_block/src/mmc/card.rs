@@ -0,0 +1,1017 @@
+//! SD/MMC card identification and block transfers.
+
+use super::{
+    cmd, BusTiming, BusWidth, Cid, Csd, DataBuf, HwPartition, MmcCommand, MmcData, MmcHost,
+    Response, ResponseType, Scr, SdStatus, BLOCK_SIZE,
+};
+use crate::recovery::{RecoveryPolicy, RecoveryStats};
+use crate::{BlockCapabilities, BlockDriverOps, DiscardLimits};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+const OCR_BUSY: u32 = 1 << 31;
+/// Card capacity status (SD) / sector access mode (MMC).
+const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+/// Switching to 1.8V signaling requested (ACMD41) / accepted (OCR).
+const OCR_S18: u32 = 1 << 24;
+/// 2.7V to 3.6V.
+const OCR_VOLTAGE_WINDOW: u32 = 0x00ff_8000;
+const MMC_OCR_SECTOR_MODE: u32 = 2 << 29;
+
+/// The voltage (2.7-3.6V) and check pattern of SEND_IF_COND.
+const IF_COND_ARG: u32 = 0x1aa;
+
+/// Error bits of the R1 card status.
+const R1_ERRORS: u32 = 0xfdf9_8008;
+/// The last MMC SWITCH was rejected.
+const R1_SWITCH_ERROR: u32 = 1 << 7;
+const R1_READY_FOR_DATA: u32 = 1 << 8;
+const R1_STATE_SHIFT: u32 = 9;
+const R1_STATE_TRAN: u32 = 4;
+/// SET_BLOCK_COUNT flag requesting a reliable write.
+const RELIABLE_WRITE: u32 = 1 << 31;
+
+/// The maximum number of SEND_OP_COND/SEND_STATUS polls.
+const POLL_RETRIES: usize = 10_000;
+/// The number of SEND_STATUS commands a card gets to answer during recovery
+/// before it is taken as removed.
+const PROBE_RETRIES: usize = 3;
+
+const SD_DEFAULT_CLOCK: u32 = 25_000_000;
+const SD_HIGH_SPEED_CLOCK: u32 = 50_000_000;
+const SD_SDR50_CLOCK: u32 = 100_000_000;
+const SD_SDR104_CLOCK: u32 = 208_000_000;
+const MMC_DEFAULT_CLOCK: u32 = 20_000_000;
+const MMC_HS26_CLOCK: u32 = 26_000_000;
+const MMC_HS52_CLOCK: u32 = 52_000_000;
+
+/// Function group 1 (access mode) of SD SWITCH_FUNC, by decreasing speed.
+const SD_ACCESS_MODES: [(BusTiming, u32, u32); 3] = [
+    (BusTiming::Sdr104, 3, SD_SDR104_CLOCK),
+    (BusTiming::Sdr50, 2, SD_SDR50_CLOCK),
+    (BusTiming::HighSpeed, 1, SD_HIGH_SPEED_CLOCK),
+];
+
+const EXT_CSD_GP_SIZE_MULT: usize = 143;
+const EXT_CSD_PARTITION_SETTING: usize = 155;
+const EXT_CSD_RPMB_SIZE_MULT: usize = 168;
+const EXT_CSD_PARTITION_CONFIG: u8 = 179;
+const EXT_CSD_BUS_WIDTH: u8 = 183;
+const EXT_CSD_HS_TIMING: u8 = 185;
+const EXT_CSD_CARD_TYPE: usize = 196;
+const EXT_CSD_SEC_COUNT: usize = 212;
+const EXT_CSD_HC_WP_GRP_SIZE: usize = 221;
+const EXT_CSD_HC_ERASE_GRP_SIZE: usize = 224;
+const EXT_CSD_BOOT_SIZE_MULT: usize = 226;
+const EXT_CSD_SEC_FEATURE_SUPPORT: usize = 231;
+const PARTITION_ACCESS_MASK: u8 = 0x7;
+const PARTITION_SETTING_COMPLETED: u8 = 1 << 0;
+const CARD_TYPE_HS26: u8 = 1 << 0;
+const CARD_TYPE_HS52: u8 = 1 << 1;
+/// SEC_GB_CL_EN, set by devices that support TRIM.
+const SEC_FEATURE_TRIM: u8 = 1 << 4;
+/// The erase command class, which all SD cards support.
+const CCC_ERASE: u16 = 1 << 5;
+/// The ERASE argument trimming the blocks of an MMC device, rather than
+/// erasing whole erase groups.
+const ERASE_ARG_TRIM: u32 = 1;
+/// The maximum number of blocks erased by one command, to bound the time the
+/// card stays busy.
+const ERASE_MAX_BLOCKS: u64 = 0x2000;
+
+/// The family of the card.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardKind {
+    /// SD memory card (SDSC, SDHC or SDXC).
+    Sd,
+    /// MMC card or eMMC device.
+    Mmc,
+}
+
+/// An SD/MMC card behind a host controller, as a block device.
+pub struct MmcCard<H: MmcHost> {
+    host: H,
+    kind: CardKind,
+    rca: u16,
+    high_capacity: bool,
+    cid: u128,
+    csd: u128,
+    scr: Option<u64>,
+    num_blocks: u64,
+    timing: BusTiming,
+    bus_width: BusWidth,
+    /// The size of each hardware partition, by PARTITION_ACCESS value.
+    part_blocks: [u64; 8],
+    /// PARTITION_CONFIG, whose low bits select the accessed partition.
+    part_config: u8,
+    /// Whether the MMC device supports TRIM.
+    trim: bool,
+    /// Incremented on each removal and insertion of a card.
+    generation: u64,
+    removed: bool,
+    policy: RecoveryPolicy,
+    stats: RecoveryStats,
+    /// Failed transfer attempts since the last successful one.
+    error_run: u32,
+}
+
+impl<H: MmcHost> MmcCard<H> {
+    /// Initializes the host and identifies the card, returns `Ok` if
+    /// successful.
+    pub fn try_new(host: H) -> DevResult<Self> {
+        let mut card = Self {
+            host,
+            kind: CardKind::Sd,
+            rca: 0,
+            high_capacity: false,
+            cid: 0,
+            csd: 0,
+            scr: None,
+            num_blocks: 0,
+            timing: BusTiming::Default,
+            bus_width: BusWidth::Width1,
+            part_blocks: [0; 8],
+            part_config: 0,
+            trim: false,
+            generation: 0,
+            removed: false,
+            policy: RecoveryPolicy::default(),
+            stats: RecoveryStats::default(),
+            error_run: 0,
+        };
+        card.identify()?;
+        Ok(card)
+    }
+
+    fn identify(&mut self) -> DevResult {
+        match self.init() {
+            Ok(()) => {
+                log::info!(
+                    "{}: {:?} card {}, {} blocks, high capacity: {}, {:?} timing, {:?} bus",
+                    self.host.name(),
+                    self.kind,
+                    self.cid(),
+                    self.num_blocks,
+                    self.high_capacity,
+                    self.timing,
+                    self.bus_width
+                );
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("{}: card init failed: {:?}", self.host.name(), e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Polls the card detect state of the host. A removed card is forgotten,
+    /// a newly inserted one is initialized. Returns whether a card is ready.
+    ///
+    /// This is also done before each transfer, so that a replaced card is
+    /// never accessed as the previous one.
+    pub fn poll_media(&mut self) -> bool {
+        if !self.host.card_present() {
+            if !self.removed {
+                log::info!("{}: card removed", self.host.name());
+                self.forget();
+            }
+            return false;
+        }
+        if self.removed {
+            if self.identify().is_err() {
+                return false;
+            }
+            self.removed = false;
+            self.generation += 1;
+        }
+        true
+    }
+
+    /// Forgets a removed card: it is initialized again as a new card, with a
+    /// new generation, once present.
+    fn forget(&mut self) {
+        self.removed = true;
+        self.num_blocks = 0;
+        self.part_blocks = [0; 8];
+        self.generation += 1;
+    }
+
+    /// The family of the card.
+    pub const fn kind(&self) -> CardKind {
+        self.kind
+    }
+
+    /// The relative card address assigned during identification.
+    pub const fn rca(&self) -> u16 {
+        self.rca
+    }
+
+    /// Whether the card is block addressed (SDHC/SDXC or sector mode MMC),
+    /// rather than byte addressed (SDSC or small MMC).
+    pub const fn high_capacity(&self) -> bool {
+        self.high_capacity
+    }
+
+    /// The bus timing negotiated with the card.
+    pub const fn timing(&self) -> BusTiming {
+        self.timing
+    }
+
+    /// The data bus width negotiated with the card.
+    pub const fn bus_width(&self) -> BusWidth {
+        self.bus_width
+    }
+
+    /// The size of a hardware partition in blocks, 0 if the device does not
+    /// have it. SD cards only have the user area.
+    pub fn partition_blocks(&self, part: HwPartition) -> u64 {
+        match part.access() {
+            Some(access) => self.part_blocks[access as usize],
+            None => 0,
+        }
+    }
+
+    /// Reads contiguous blocks from a hardware partition, switching to it if
+    /// needed. The RPMB partition cannot be read this way.
+    pub fn read_partition(
+        &mut self,
+        part: HwPartition,
+        block_id: u64,
+        buf: &mut [u8],
+    ) -> DevResult {
+        self.check_range(part, block_id, buf.len())?;
+        self.select_partition(part)?;
+        let max_bytes = self.host.max_blocks() * BLOCK_SIZE;
+        for (i, chunk) in buf.chunks_mut(max_bytes).enumerate() {
+            let block = block_id + (i * max_bytes / BLOCK_SIZE) as u64;
+            self.transfer_recover(block, DataBuf::Read(chunk))?;
+        }
+        Ok(())
+    }
+
+    /// Writes contiguous blocks to a hardware partition, switching to it if
+    /// needed. The RPMB partition cannot be written this way.
+    pub fn write_partition(&mut self, part: HwPartition, block_id: u64, buf: &[u8]) -> DevResult {
+        self.check_range(part, block_id, buf.len())?;
+        self.select_partition(part)?;
+        let max_bytes = self.host.max_blocks() * BLOCK_SIZE;
+        for (i, chunk) in buf.chunks(max_bytes).enumerate() {
+            let block = block_id + (i * max_bytes / BLOCK_SIZE) as u64;
+            self.transfer_recover(block, DataBuf::Write(chunk))?;
+        }
+        Ok(())
+    }
+
+    /// Discards blocks of a hardware partition, switching to it if needed.
+    /// SD cards erase them, MMC devices trim them if they support it.
+    pub fn discard_partition(&mut self, part: HwPartition, start: u64, count: u64) -> DevResult {
+        if !self.can_discard() {
+            return Err(DevError::Unsupported);
+        }
+        self.check_blocks(part, start, count)?;
+        self.select_partition(part)?;
+        let (start_cmd, end_cmd, arg) = match self.kind {
+            CardKind::Sd => (cmd::ERASE_WR_BLK_START, cmd::ERASE_WR_BLK_END, 0),
+            CardKind::Mmc => (cmd::ERASE_GROUP_START, cmd::ERASE_GROUP_END, ERASE_ARG_TRIM),
+        };
+        // Only the whole erase units of the range are erased, erasing the
+        // others would erase the blocks around the range too.
+        let unit = self.discard_granularity();
+        let end = (start + count) / unit * unit;
+        let mut block = start.div_ceil(unit) * unit;
+        while block < end {
+            let blocks = (end - block).min(ERASE_MAX_BLOCKS / unit * unit);
+            let first = self.block_arg(block)?;
+            let last = self.block_arg(block + blocks - 1)?;
+            self.cmd(start_cmd, first, ResponseType::R1)?;
+            self.cmd(end_cmd, last, ResponseType::R1)?;
+            self.cmd(cmd::ERASE, arg, ResponseType::R1b)?;
+            self.wait_ready()?;
+            block += blocks;
+        }
+        Ok(())
+    }
+
+    /// The limits of discards in a hardware partition, `None` if the card
+    /// cannot discard blocks or does not have the partition.
+    pub fn partition_discard_limits(&self, part: HwPartition) -> Option<DiscardLimits> {
+        let blocks = self.partition_blocks(part);
+        (self.can_discard() && blocks != 0 && part != HwPartition::Rpmb).then_some(DiscardLimits {
+            granularity: self.discard_granularity(),
+            max_blocks: blocks,
+        })
+    }
+
+    /// The capabilities of a hardware partition. SD cards are removable
+    /// and eMMC devices are not, neither caches writes.
+    pub fn partition_capabilities(&self, part: HwPartition) -> BlockCapabilities {
+        let csd = self.csd();
+        BlockCapabilities {
+            max_transfer: self.host.max_blocks() * BLOCK_SIZE,
+            removable: self.kind == CardKind::Sd,
+            read_only: csd.perm_write_protect || csd.tmp_write_protect,
+            write_cache: false,
+            discard: self.partition_discard_limits(part),
+            ..BlockCapabilities::new(self.partition_blocks(part), BLOCK_SIZE)
+        }
+    }
+
+    /// The raw card identification register.
+    pub const fn raw_cid(&self) -> u128 {
+        self.cid
+    }
+
+    /// The raw card specific data register.
+    pub const fn raw_csd(&self) -> u128 {
+        self.csd
+    }
+
+    /// The card identification register: manufacturer, product name, serial
+    /// number and manufacturing date.
+    pub const fn cid(&self) -> Cid {
+        Cid::from_raw(self.cid, self.kind)
+    }
+
+    /// The card specific data register: capacity, speed and write protection.
+    pub const fn csd(&self) -> Csd {
+        Csd::from_raw(self.csd, self.kind)
+    }
+
+    /// The SD configuration register: specification version and bus widths.
+    /// `None` for MMC devices.
+    pub const fn scr(&self) -> Option<Scr> {
+        match self.scr {
+            Some(scr) => Some(Scr::from_raw(scr)),
+            None => None,
+        }
+    }
+
+    /// Reads the SD status of an SD card: its speed class and performance
+    /// ratings. It fails with [`DevError::Unsupported`] for MMC devices.
+    pub fn sd_status(&mut self) -> DevResult<SdStatus> {
+        if self.kind != CardKind::Sd {
+            return Err(DevError::Unsupported);
+        }
+        if !self.poll_media() {
+            return Err(DevError::BadState);
+        }
+        let mut buf = [0u8; 64];
+        let data = MmcData {
+            block_size: buf.len(),
+            blocks: 1,
+            buf: DataBuf::Read(&mut buf),
+        };
+        self.cmd(cmd::APP_CMD, (self.rca as u32) << 16, ResponseType::R1)?;
+        self.data_cmd(cmd::APP_SD_STATUS, 0, data)?;
+        Ok(SdStatus::from_raw(&buf))
+    }
+
+    /// The policy applied to recover from failed transfers.
+    pub const fn recovery_policy(&self) -> RecoveryPolicy {
+        self.policy
+    }
+
+    /// Sets the policy applied to recover from failed transfers. The RPMB
+    /// requests are never retried, as a retried write would be a replay.
+    pub fn set_recovery_policy(&mut self, policy: RecoveryPolicy) {
+        self.policy = policy;
+    }
+
+    /// The errors seen and the recovery actions taken so far.
+    pub const fn recovery_stats(&self) -> RecoveryStats {
+        self.stats
+    }
+
+    /// Returns a reference to the host controller driver.
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    /// Returns a mutable reference to the host controller driver.
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    fn cmd(&mut self, index: u8, arg: u32, resp: ResponseType) -> DevResult<Response> {
+        let resp = self
+            .host
+            .send_command(&MmcCommand::new(index, arg, resp), None)?;
+        Self::check_r1(index, resp)
+    }
+
+    fn app_cmd(&mut self, index: u8, arg: u32, resp: ResponseType) -> DevResult<Response> {
+        self.cmd(cmd::APP_CMD, (self.rca as u32) << 16, ResponseType::R1)?;
+        self.cmd(index, arg, resp)
+    }
+
+    fn data_cmd(&mut self, index: u8, arg: u32, data: MmcData) -> DevResult<Response> {
+        let resp = self
+            .host
+            .send_command(&MmcCommand::new(index, arg, ResponseType::R1), Some(data))?;
+        Self::check_r1(index, resp)
+    }
+
+    fn check_r1(index: u8, resp: Response) -> DevResult<Response> {
+        // R3 (OCR) and R6 (RCA) responses do not carry a card status.
+        let has_status = !matches!(
+            index,
+            cmd::SEND_OP_COND | cmd::SEND_RELATIVE_ADDR | cmd::APP_SD_SEND_OP_COND
+        );
+        if let Response::Short(status) = resp {
+            if has_status && status & R1_ERRORS != 0 {
+                log::warn!("mmc: CMD{} failed, card status {:#x}", index, status);
+                return Err(DevError::Io);
+            }
+        }
+        Ok(resp)
+    }
+
+    fn init(&mut self) -> DevResult {
+        self.kind = CardKind::Sd;
+        self.rca = 0;
+        self.scr = None;
+        self.num_blocks = 0;
+        self.timing = BusTiming::Default;
+        self.bus_width = BusWidth::Width1;
+        self.part_blocks = [0; 8];
+        self.part_config = 0;
+        self.trim = false;
+        self.host.init()?;
+        self.cmd(cmd::GO_IDLE_STATE, 0, ResponseType::None)?;
+
+        // Only SD 2.0+ cards answer SEND_IF_COND.
+        let sd_v2 = match self.cmd(cmd::SEND_IF_COND, IF_COND_ARG, ResponseType::R1) {
+            Ok(resp) => resp.short() & 0xfff == IF_COND_ARG,
+            Err(_) => false,
+        };
+        // UHS-I cards switch to 1.8V signaling if asked to.
+        let uhs = sd_v2 && self.host.supports_timing(BusTiming::Sdr50);
+        let ocr = match self.sd_send_op_cond(sd_v2, uhs) {
+            Ok(ocr) => ocr,
+            Err(_) => {
+                // Not an SD card, retry as MMC.
+                self.host.init()?;
+                self.cmd(cmd::GO_IDLE_STATE, 0, ResponseType::None)?;
+                self.kind = CardKind::Mmc;
+                self.mmc_send_op_cond()?
+            }
+        };
+        self.high_capacity = ocr & OCR_HIGH_CAPACITY != 0;
+        let signal_1v8 = self.kind == CardKind::Sd && uhs && ocr & OCR_S18 != 0;
+        if signal_1v8 {
+            self.cmd(cmd::VOLTAGE_SWITCH, 0, ResponseType::R1)?;
+            self.host.switch_signal_voltage()?;
+        }
+
+        self.cid = self.cmd(cmd::ALL_SEND_CID, 0, ResponseType::R2)?.long();
+        self.rca = match self.kind {
+            CardKind::Sd => {
+                let resp = self.cmd(cmd::SEND_RELATIVE_ADDR, 0, ResponseType::R1)?;
+                (resp.short() >> 16) as u16
+            }
+            CardKind::Mmc => {
+                self.cmd(cmd::SEND_RELATIVE_ADDR, 1 << 16, ResponseType::R1)?;
+                1
+            }
+        };
+        let rca_arg = (self.rca as u32) << 16;
+        self.csd = self.cmd(cmd::SEND_CSD, rca_arg, ResponseType::R2)?.long();
+
+        let clock = match self.kind {
+            CardKind::Sd => SD_DEFAULT_CLOCK,
+            CardKind::Mmc => MMC_DEFAULT_CLOCK,
+        };
+        self.host.set_clock(clock)?;
+        self.cmd(cmd::SELECT_CARD, rca_arg, ResponseType::R1b)?;
+
+        let mut ext_csd = [0u8; BLOCK_SIZE];
+        let has_ext_csd = self.kind == CardKind::Mmc && self.csd().mmc_spec_version >= 4;
+        if has_ext_csd {
+            self.read_ext_csd(&mut ext_csd)?;
+        }
+        match self.kind {
+            CardKind::Sd => self.sd_set_bus(signal_1v8)?,
+            CardKind::Mmc if has_ext_csd => self.mmc_set_bus(&ext_csd)?,
+            CardKind::Mmc => {}
+        }
+        if !self.high_capacity {
+            self.cmd(cmd::SET_BLOCKLEN, BLOCK_SIZE as u32, ResponseType::R1)?;
+        }
+        // MMC devices larger than 2 GiB report their size in the EXT_CSD.
+        self.num_blocks = if has_ext_csd && self.high_capacity {
+            let sec_count = &ext_csd[EXT_CSD_SEC_COUNT..EXT_CSD_SEC_COUNT + 4];
+            u32::from_le_bytes(sec_count.try_into().unwrap()) as u64
+        } else {
+            self.csd().num_blocks
+        };
+        self.part_blocks[0] = self.num_blocks;
+        if has_ext_csd {
+            self.parse_partitions(&ext_csd);
+            self.trim = ext_csd[EXT_CSD_SEC_FEATURE_SUPPORT] & SEC_FEATURE_TRIM != 0;
+        }
+        Ok(())
+    }
+
+    /// Computes the sizes of the other hardware partitions of an MMC device.
+    fn parse_partitions(&mut self, ext_csd: &[u8; BLOCK_SIZE]) {
+        self.part_config = ext_csd[EXT_CSD_PARTITION_CONFIG as usize];
+        // Boot and RPMB partitions come in units of 128 KiB.
+        let boot_blocks = ext_csd[EXT_CSD_BOOT_SIZE_MULT] as u64 * 256;
+        self.part_blocks[1] = boot_blocks;
+        self.part_blocks[2] = boot_blocks;
+        self.part_blocks[3] = ext_csd[EXT_CSD_RPMB_SIZE_MULT] as u64 * 256;
+        if ext_csd[EXT_CSD_PARTITION_SETTING] & PARTITION_SETTING_COMPLETED == 0 {
+            return;
+        }
+        // General purpose partitions come in units of high capacity write
+        // protect groups, each a number of 512 KiB erase groups.
+        let unit = ext_csd[EXT_CSD_HC_WP_GRP_SIZE] as u64
+            * ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE] as u64
+            * 1024;
+        for (i, mult) in ext_csd[EXT_CSD_GP_SIZE_MULT..EXT_CSD_GP_SIZE_MULT + 12]
+            .chunks_exact(3)
+            .enumerate()
+        {
+            let mult = u32::from_le_bytes([mult[0], mult[1], mult[2], 0]);
+            self.part_blocks[4 + i] = mult as u64 * unit;
+        }
+    }
+
+    /// Selects the hardware partition accessed by the following transfers.
+    fn select_partition(&mut self, part: HwPartition) -> DevResult {
+        let access = part.access().ok_or(DevError::InvalidParam)?;
+        if self.part_config & PARTITION_ACCESS_MASK == access {
+            return Ok(());
+        }
+        let config = (self.part_config & !PARTITION_ACCESS_MASK) | access;
+        self.mmc_switch(EXT_CSD_PARTITION_CONFIG, config)?;
+        self.part_config = config;
+        Ok(())
+    }
+
+    /// Switches an SD card to a 4-bit bus and to the fastest timing supported
+    /// by both the card and the host.
+    fn sd_set_bus(&mut self, signal_1v8: bool) -> DevResult {
+        let scr = Scr::from_raw(self.read_scr()?);
+        if scr.bus_width_4 && self.host.supports_bus_width(BusWidth::Width4) {
+            self.app_cmd(cmd::APP_SET_BUS_WIDTH, 2, ResponseType::R1)?;
+            self.host.set_bus_width(BusWidth::Width4)?;
+            self.bus_width = BusWidth::Width4;
+        }
+        // SWITCH_FUNC appeared in version 1.10.
+        if scr.spec_version < (1, 1) || !self.host.supports_timing(BusTiming::HighSpeed) {
+            return Ok(());
+        }
+        let mut status = [0u8; 64];
+        self.sd_switch(false, 0xf, &mut status)?;
+        let supported = u16::from_be_bytes([status[12], status[13]]);
+        // The UHS-I modes need 1.8V signaling and a 4-bit bus.
+        let uhs = signal_1v8 && self.bus_width == BusWidth::Width4;
+        let mode = SD_ACCESS_MODES.into_iter().find(|&(timing, func, _)| {
+            (uhs || timing == BusTiming::HighSpeed)
+                && supported & (1 << func) != 0
+                && self.host.supports_timing(timing)
+        });
+        match mode {
+            Some((timing, func, clock)) => self.sd_set_timing(timing, func, clock),
+            None => Ok(()),
+        }
+    }
+
+    /// Switches an SD card to the timing of access mode function `func`,
+    /// then the host. The timing is left unchanged if the card refuses.
+    fn sd_set_timing(&mut self, timing: BusTiming, func: u32, clock: u32) -> DevResult {
+        let mut status = [0u8; 64];
+        self.sd_switch(true, func, &mut status)?;
+        if (status[16] & 0xf) as u32 != func {
+            log::warn!("{}: card refused {:?} timing", self.host.name(), timing);
+            return Ok(());
+        }
+        self.host.set_timing(timing)?;
+        self.host.set_clock(clock)?;
+        if timing >= BusTiming::Sdr50 {
+            self.host.execute_tuning(cmd::SEND_TUNING_BLOCK)?;
+        }
+        self.timing = timing;
+        Ok(())
+    }
+
+    /// Slows the bus down to the next slower timing supported by the card
+    /// and the host, after repeated transfer errors.
+    fn downshift(&mut self) -> DevResult {
+        let from = self.timing;
+        match self.kind {
+            CardKind::Sd => {
+                let mut status = [0u8; 64];
+                self.sd_switch(false, 0xf, &mut status)?;
+                let supported = u16::from_be_bytes([status[12], status[13]]);
+                let (timing, func, clock) = SD_ACCESS_MODES
+                    .into_iter()
+                    .find(|&(timing, func, _)| {
+                        timing < from
+                            && supported & (1 << func) != 0
+                            && self.host.supports_timing(timing)
+                    })
+                    .unwrap_or((BusTiming::Default, 0, SD_DEFAULT_CLOCK));
+                self.sd_set_timing(timing, func, clock)?;
+            }
+            CardKind::Mmc => {
+                self.mmc_switch(EXT_CSD_HS_TIMING, 0)?;
+                self.host.set_timing(BusTiming::Default)?;
+                self.host.set_clock(MMC_DEFAULT_CLOCK)?;
+                self.timing = BusTiming::Default;
+            }
+        }
+        if self.timing == from {
+            return Err(DevError::Io);
+        }
+        log::warn!(
+            "{}: slowed down from {:?} to {:?} timing",
+            self.host.name(),
+            from,
+            self.timing
+        );
+        Ok(())
+    }
+
+    /// Checks (`set` false) or switches to (`set` true) function `func` of
+    /// the access mode group, returns the 64-byte switch function status.
+    fn sd_switch(&mut self, set: bool, func: u32, status: &mut [u8; 64]) -> DevResult {
+        let arg = (set as u32) << 31 | 0x00ff_fff0 | func;
+        let data = MmcData {
+            block_size: status.len(),
+            blocks: 1,
+            buf: DataBuf::Read(status),
+        };
+        self.data_cmd(cmd::SWITCH, arg, data).map(|_| ())
+    }
+
+    /// Switches an MMC device to the widest bus and to high speed timing if
+    /// supported by both the device and the host.
+    fn mmc_set_bus(&mut self, ext_csd: &[u8; BLOCK_SIZE]) -> DevResult {
+        let width = [(BusWidth::Width8, 2), (BusWidth::Width4, 1)]
+            .into_iter()
+            .find(|&(width, _)| self.host.supports_bus_width(width));
+        if let Some((width, value)) = width {
+            self.mmc_switch(EXT_CSD_BUS_WIDTH, value)?;
+            self.host.set_bus_width(width)?;
+            self.bus_width = width;
+        }
+        let card_type = ext_csd[EXT_CSD_CARD_TYPE];
+        if card_type & (CARD_TYPE_HS26 | CARD_TYPE_HS52) != 0
+            && self.host.supports_timing(BusTiming::HighSpeed)
+        {
+            self.mmc_switch(EXT_CSD_HS_TIMING, 1)?;
+            self.host.set_timing(BusTiming::HighSpeed)?;
+            let clock = if card_type & CARD_TYPE_HS52 != 0 {
+                MMC_HS52_CLOCK
+            } else {
+                MMC_HS26_CLOCK
+            };
+            self.host.set_clock(clock)?;
+            self.timing = BusTiming::HighSpeed;
+        }
+        Ok(())
+    }
+
+    /// Writes a byte of the EXT_CSD of an MMC device with SWITCH.
+    fn mmc_switch(&mut self, index: u8, value: u8) -> DevResult {
+        let arg = 3 << 24 | (index as u32) << 16 | (value as u32) << 8;
+        self.cmd(cmd::SWITCH, arg, ResponseType::R1b)?;
+        if self.wait_ready()? & R1_SWITCH_ERROR != 0 {
+            log::warn!("{}: SWITCH of EXT_CSD[{}] failed", self.host.name(), index);
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    /// Runs ACMD41 until the card leaves the busy state, returns the OCR.
+    fn sd_send_op_cond(&mut self, sd_v2: bool, s18r: bool) -> DevResult<u32> {
+        let mut arg = OCR_VOLTAGE_WINDOW;
+        if sd_v2 {
+            arg |= OCR_HIGH_CAPACITY;
+        }
+        if s18r {
+            arg |= OCR_S18;
+        }
+        for _ in 0..POLL_RETRIES {
+            let ocr = self
+                .app_cmd(cmd::APP_SD_SEND_OP_COND, arg, ResponseType::R3)?
+                .short();
+            if ocr & OCR_BUSY != 0 {
+                return Ok(ocr);
+            }
+        }
+        Err(DevError::Io)
+    }
+
+    /// Runs CMD1 until the card leaves the busy state, returns the OCR.
+    fn mmc_send_op_cond(&mut self) -> DevResult<u32> {
+        let arg = OCR_VOLTAGE_WINDOW | MMC_OCR_SECTOR_MODE;
+        for _ in 0..POLL_RETRIES {
+            let ocr = self.cmd(cmd::SEND_OP_COND, arg, ResponseType::R3)?.short();
+            if ocr & OCR_BUSY != 0 {
+                return Ok(ocr);
+            }
+        }
+        Err(DevError::Io)
+    }
+
+    /// Reads the 64-bit SD configuration register of an SD card.
+    fn read_scr(&mut self) -> DevResult<u64> {
+        let mut buf = [0u8; 8];
+        let data = MmcData {
+            block_size: buf.len(),
+            blocks: 1,
+            buf: DataBuf::Read(&mut buf),
+        };
+        self.cmd(cmd::APP_CMD, (self.rca as u32) << 16, ResponseType::R1)?;
+        self.data_cmd(cmd::APP_SEND_SCR, 0, data)?;
+        let scr = u64::from_be_bytes(buf);
+        self.scr = Some(scr);
+        Ok(scr)
+    }
+
+    /// Reads the 512-byte extended CSD register of an MMC device.
+    fn read_ext_csd(&mut self, buf: &mut [u8; BLOCK_SIZE]) -> DevResult {
+        let data = MmcData {
+            block_size: BLOCK_SIZE,
+            blocks: 1,
+            buf: DataBuf::Read(buf),
+        };
+        self.data_cmd(cmd::SEND_EXT_CSD, 0, data).map(|_| ())
+    }
+
+    /// Polls the card status until it is ready for data in transfer state,
+    /// returns the last status.
+    fn wait_ready(&mut self) -> DevResult<u32> {
+        let rca_arg = (self.rca as u32) << 16;
+        for _ in 0..POLL_RETRIES {
+            let status = self
+                .cmd(cmd::SEND_STATUS, rca_arg, ResponseType::R1)?
+                .short();
+            if status & R1_READY_FOR_DATA != 0 && (status >> R1_STATE_SHIFT) & 0xf == R1_STATE_TRAN
+            {
+                return Ok(status);
+            }
+        }
+        Err(DevError::Io)
+    }
+
+    /// Whether the card answers SEND_STATUS at its address.
+    fn card_responds(&mut self) -> bool {
+        let rca_arg = (self.rca as u32) << 16;
+        (0..PROBE_RETRIES).any(|_| {
+            self.cmd(cmd::SEND_STATUS, rca_arg, ResponseType::R1)
+                .is_ok()
+        })
+    }
+
+    /// Transfers contiguous blocks starting at `block_id`, retrying after
+    /// I/O errors as the recovery policy says.
+    ///
+    /// A transfer is never retried on another card than the one it started
+    /// on: it fails with [`DevError::BadState`] once the media generation
+    /// changed.
+    fn transfer_recover(&mut self, block_id: u64, mut buf: DataBuf) -> DevResult {
+        let generation = self.generation;
+        let mut attempt = 0;
+        loop {
+            match self.transfer(block_id, buf.reborrow()) {
+                Ok(()) => {
+                    self.error_run = 0;
+                    return Ok(());
+                }
+                Err(DevError::Io) => {}
+                Err(e) => return Err(e),
+            }
+            self.stats.errors += 1;
+            self.error_run += 1;
+            if attempt == self.policy.max_retries {
+                self.stats.failures += 1;
+                return Err(DevError::Io);
+            }
+            if let Err(e) = self.recover(attempt) {
+                log::warn!("{}: recovery failed: {:?}", self.host.name(), e);
+                self.stats.failures += 1;
+                return Err(e);
+            }
+            if self.generation != generation {
+                self.stats.failures += 1;
+                return Err(DevError::BadState);
+            }
+            attempt += 1;
+            self.stats.retries += 1;
+        }
+    }
+
+    /// Brings the host and the card back to a state where a failed transfer
+    /// can be retried: resets the command and data lines, then slows the bus
+    /// down after repeated errors or tunes the sampling clock again, and
+    /// waits before the retry.
+    ///
+    /// A card that no longer answers SEND_STATUS was removed, or replaced by
+    /// one that is not initialized, which hosts without card detect cannot
+    /// tell otherwise. It is forgotten and recovery fails.
+    fn recover(&mut self, attempt: u32) -> DevResult {
+        if !self.poll_media() {
+            return Err(DevError::BadState);
+        }
+        self.host.reset_lines()?;
+        self.stats.line_resets += 1;
+        if !self.card_responds() {
+            log::info!(
+                "{}: card not responding, assuming it was removed",
+                self.host.name()
+            );
+            self.forget();
+            return Err(DevError::BadState);
+        }
+        let downshift_after = self.policy.downshift_after;
+        if downshift_after != 0
+            && self.error_run >= downshift_after
+            && self.timing > BusTiming::Default
+        {
+            self.downshift()?;
+            self.stats.downshifts += 1;
+            self.error_run = 0;
+        } else if self.timing >= BusTiming::Sdr50 {
+            self.host.execute_tuning(cmd::SEND_TUNING_BLOCK)?;
+            self.stats.retunes += 1;
+        }
+        self.policy.backoff(attempt);
+        // The card may still be receiving or programming the failed data.
+        self.wait_ready().map(|_| ())
+    }
+
+    /// Transfers contiguous blocks starting at `block_id` with a single
+    /// command, multiple block transfers are stopped by the host (auto CMD12).
+    fn transfer(&mut self, block_id: u64, buf: DataBuf) -> DevResult {
+        let (blocks, write) = match &buf {
+            DataBuf::Read(buf) => (buf.len() / BLOCK_SIZE, false),
+            DataBuf::Write(buf) => (buf.len() / BLOCK_SIZE, true),
+        };
+        let index = match (blocks > 1, write) {
+            (false, false) => cmd::READ_SINGLE_BLOCK,
+            (true, false) => cmd::READ_MULTIPLE_BLOCK,
+            (false, true) => cmd::WRITE_BLOCK,
+            (true, true) => cmd::WRITE_MULTIPLE_BLOCK,
+        };
+        let data = MmcData {
+            block_size: BLOCK_SIZE,
+            blocks,
+            buf,
+        };
+        let arg = self.block_arg(block_id)?;
+        if let Err(e) = self.data_cmd(index, arg, data) {
+            if blocks > 1 {
+                // Bring the card back to the transfer state.
+                let _ = self.cmd(cmd::STOP_TRANSMISSION, 0, ResponseType::R1b);
+            }
+            return Err(e);
+        }
+        if write {
+            self.wait_ready()?;
+        }
+        Ok(())
+    }
+
+    /// Transfers one frame to or from the RPMB partition, switching to it if
+    /// needed. The block count is set beforehand, as the RPMB requires, with
+    /// the reliable write flag if `reliable`.
+    pub(super) fn rpmb_transfer(&mut self, buf: DataBuf, reliable: bool) -> DevResult {
+        if self.partition_blocks(HwPartition::Rpmb) == 0 {
+            return Err(DevError::Unsupported);
+        }
+        if !self.poll_media() {
+            return Err(DevError::BadState);
+        }
+        self.select_partition(HwPartition::Rpmb)?;
+        let (index, write) = match buf {
+            DataBuf::Read(_) => (cmd::READ_MULTIPLE_BLOCK, false),
+            DataBuf::Write(_) => (cmd::WRITE_MULTIPLE_BLOCK, true),
+        };
+        let count = if reliable { RELIABLE_WRITE | 1 } else { 1 };
+        self.cmd(cmd::SET_BLOCK_COUNT, count, ResponseType::R1)?;
+        // A single block, so the host does not send CMD12 after it.
+        let data = MmcData {
+            block_size: BLOCK_SIZE,
+            blocks: 1,
+            buf,
+        };
+        self.data_cmd(index, 0, data)?;
+        if write {
+            self.wait_ready()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the card can discard blocks: SD cards by erasing them, MMC
+    /// devices by trimming them.
+    fn can_discard(&self) -> bool {
+        match self.kind {
+            CardKind::Sd => self.csd().command_classes & CCC_ERASE != 0,
+            CardKind::Mmc => self.trim,
+        }
+    }
+
+    /// The number of blocks discarded as one unit: the erase unit of SD
+    /// cards, a single block for MMC devices, which trim.
+    fn discard_granularity(&self) -> u64 {
+        match self.kind {
+            CardKind::Sd => self.csd().erase_blocks as u64,
+            CardKind::Mmc => 1,
+        }
+    }
+
+    /// Converts a block number to the command argument: the block number
+    /// itself on high capacity cards, its byte address otherwise. Fails if it
+    /// does not fit in 32 bits, rather than wrapping to another block.
+    fn block_arg(&self, block_id: u64) -> DevResult<u32> {
+        let addr = if self.high_capacity {
+            Some(block_id)
+        } else {
+            block_id.checked_mul(BLOCK_SIZE as u64)
+        };
+        addr.and_then(|addr| u32::try_from(addr).ok())
+            .ok_or(DevError::Io)
+    }
+
+    /// Checks that a card is present and that `len` bytes starting at
+    /// `block_id` are whole blocks inside partition `part`, all of which are
+    /// addressable.
+    fn check_range(&mut self, part: HwPartition, block_id: u64, len: usize) -> DevResult {
+        if len % BLOCK_SIZE != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        self.check_blocks(part, block_id, (len / BLOCK_SIZE) as u64)
+    }
+
+    /// Checks that a card is present and that `count` blocks starting at
+    /// `block_id` are inside partition `part`, all of which are addressable.
+    fn check_blocks(&mut self, part: HwPartition, block_id: u64, count: u64) -> DevResult {
+        if part == HwPartition::Rpmb {
+            return Err(DevError::Unsupported);
+        }
+        if count == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        if !self.poll_media() {
+            return Err(DevError::BadState);
+        }
+        match block_id.checked_add(count) {
+            Some(end) if end <= self.partition_blocks(part) => self.block_arg(end - 1).map(|_| ()),
+            _ => Err(DevError::Io),
+        }
+    }
+}
+
+impl<H: MmcHost> BaseDriverOps for MmcCard<H> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        self.host.name()
+    }
+}
+
+impl<H: MmcHost> BlockDriverOps for MmcCard<H> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn media_generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.read_partition(HwPartition::User, block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.write_partition(HwPartition::User, block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> DevResult {
+        self.discard_partition(HwPartition::User, start, count)
+    }
+
+    fn discard_limits(&self) -> Option<DiscardLimits> {
+        self.partition_discard_limits(HwPartition::User)
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.partition_capabilities(HwPartition::User)
+    }
+}
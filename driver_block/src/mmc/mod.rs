@@ -0,0 +1,276 @@
+//! SD/MMC card support shared by the SD host controller drivers.
+//!
+//! A host controller driver implements [`MmcHost`], which only knows how to
+//! send commands and move data. The card protocol (identification, addressing,
+//! block transfers) lives in [`MmcCard`], which turns any host into a block
+//! device.
+
+mod card;
+#[cfg(any(feature = "sdhci", feature = "dw-mmc"))]
+mod partition;
+mod regs;
+pub mod rpmb;
+
+pub use self::card::{CardKind, MmcCard};
+#[cfg(any(feature = "sdhci", feature = "dw-mmc"))]
+pub use self::partition::MmcPartition;
+pub use self::regs::{Cid, Csd, Scr, SdStatus};
+
+use driver_common::{DevError, DevResult};
+
+/// The size of a data block on SD/MMC cards.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Commands used by the card protocol.
+pub(crate) mod cmd {
+    pub const GO_IDLE_STATE: u8 = 0;
+    pub const SEND_OP_COND: u8 = 1;
+    pub const ALL_SEND_CID: u8 = 2;
+    pub const SEND_RELATIVE_ADDR: u8 = 3;
+    pub const SWITCH: u8 = 6;
+    pub const SELECT_CARD: u8 = 7;
+    pub const SEND_IF_COND: u8 = 8;
+    pub const SEND_EXT_CSD: u8 = 8;
+    pub const SEND_CSD: u8 = 9;
+    pub const VOLTAGE_SWITCH: u8 = 11;
+    pub const STOP_TRANSMISSION: u8 = 12;
+    pub const SEND_STATUS: u8 = 13;
+    pub const SET_BLOCKLEN: u8 = 16;
+    pub const READ_SINGLE_BLOCK: u8 = 17;
+    pub const READ_MULTIPLE_BLOCK: u8 = 18;
+    pub const SEND_TUNING_BLOCK: u8 = 19;
+    pub const SET_BLOCK_COUNT: u8 = 23;
+    pub const WRITE_BLOCK: u8 = 24;
+    pub const WRITE_MULTIPLE_BLOCK: u8 = 25;
+    pub const ERASE_WR_BLK_START: u8 = 32;
+    pub const ERASE_WR_BLK_END: u8 = 33;
+    pub const ERASE_GROUP_START: u8 = 35;
+    pub const ERASE_GROUP_END: u8 = 36;
+    pub const ERASE: u8 = 38;
+    pub const APP_CMD: u8 = 55;
+
+    pub const APP_SET_BUS_WIDTH: u8 = 6;
+    pub const APP_SD_STATUS: u8 = 13;
+    pub const APP_SD_SEND_OP_COND: u8 = 41;
+    pub const APP_SEND_SCR: u8 = 51;
+}
+
+/// The format of the response expected for a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseType {
+    /// No response.
+    None,
+    /// 48-bit response with CRC (R1, R6, R7).
+    R1,
+    /// R1 followed by a busy signal on DAT0.
+    R1b,
+    /// 136-bit response (R2, CID/CSD).
+    R2,
+    /// 48-bit response without CRC (R3, OCR).
+    R3,
+}
+
+/// A command sent to the card.
+#[derive(Clone, Copy, Debug)]
+pub struct MmcCommand {
+    /// Command index.
+    pub index: u8,
+    /// Command argument.
+    pub arg: u32,
+    /// Expected response.
+    pub resp: ResponseType,
+}
+
+impl MmcCommand {
+    /// Creates a command.
+    pub const fn new(index: u8, arg: u32, resp: ResponseType) -> Self {
+        Self { index, arg, resp }
+    }
+}
+
+/// The response of a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// No response.
+    None,
+    /// The 32-bit card status or register of a 48-bit response.
+    Short(u32),
+    /// The 128-bit register of an R2 response. Bit `i` is bit `i` of the
+    /// register as numbered in the specifications, the CRC byte is zero.
+    Long(u128),
+}
+
+impl Response {
+    /// The 32-bit content of a short response, 0 for other responses.
+    pub const fn short(&self) -> u32 {
+        match self {
+            Self::Short(val) => *val,
+            _ => 0,
+        }
+    }
+
+    /// The 128-bit content of a long response, 0 for other responses.
+    pub const fn long(&self) -> u128 {
+        match self {
+            Self::Long(val) => *val,
+            _ => 0,
+        }
+    }
+}
+
+/// The data buffer of a command with a data phase.
+pub enum DataBuf<'a> {
+    /// Data read from the card.
+    Read(&'a mut [u8]),
+    /// Data written to the card.
+    Write(&'a [u8]),
+}
+
+impl DataBuf<'_> {
+    /// Borrows the buffer again, to use it for another command.
+    pub fn reborrow(&mut self) -> DataBuf<'_> {
+        match self {
+            Self::Read(buf) => DataBuf::Read(buf),
+            Self::Write(buf) => DataBuf::Write(buf),
+        }
+    }
+}
+
+/// The data phase of a command.
+pub struct MmcData<'a> {
+    /// The block size of the transfer.
+    pub block_size: usize,
+    /// The number of blocks to transfer.
+    pub blocks: usize,
+    /// The data buffer, of `block_size * blocks` bytes.
+    pub buf: DataBuf<'a>,
+}
+
+/// The data bus width.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusWidth {
+    /// 1-bit (DAT0 only).
+    Width1,
+    /// 4-bit.
+    Width4,
+    /// 8-bit (MMC only).
+    Width8,
+}
+
+/// A hardware partition of an eMMC device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HwPartition {
+    /// The user data area, the only partition of SD cards.
+    User,
+    /// The first boot partition.
+    Boot0,
+    /// The second boot partition.
+    Boot1,
+    /// The replay protected memory block, only accessed with authenticated
+    /// requests.
+    Rpmb,
+    /// One of the four general purpose partitions (0 to 3).
+    GeneralPurpose(u8),
+}
+
+impl HwPartition {
+    /// The PARTITION_ACCESS value selecting the partition, `None` for a
+    /// general purpose partition that cannot exist.
+    pub const fn access(self) -> Option<u8> {
+        match self {
+            Self::User => Some(0),
+            Self::Boot0 => Some(1),
+            Self::Boot1 => Some(2),
+            Self::Rpmb => Some(3),
+            Self::GeneralPurpose(n) if n < 4 => Some(4 + n),
+            Self::GeneralPurpose(_) => None,
+        }
+    }
+}
+
+/// The bus speed mode of the card interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BusTiming {
+    /// Default speed: up to 25 MHz (SD) or 26 MHz (MMC).
+    Default,
+    /// High speed: up to 50 MHz (SD) or 52 MHz (MMC). SDR25 when the SD card
+    /// signals at 1.8V.
+    HighSpeed,
+    /// UHS-I SDR50: up to 100 MHz, 1.8V signaling.
+    Sdr50,
+    /// UHS-I SDR104: up to 208 MHz, 1.8V signaling, with sampling tuning.
+    Sdr104,
+}
+
+/// Operations that an SD/MMC host controller driver must implement.
+pub trait MmcHost: Send + Sync {
+    /// The name of the host controller.
+    fn name(&self) -> &str;
+
+    /// Resets the controller, powers up the card and sets the identification
+    /// clock (400 kHz) and a 1-bit bus.
+    fn init(&mut self) -> DevResult;
+
+    /// Whether a card is inserted. Hosts without card detect always return
+    /// `true`. After a removal, returns `false` at least once, even if a card
+    /// was inserted again since.
+    fn card_present(&mut self) -> bool {
+        true
+    }
+
+    /// Sets the card clock frequency, in Hz. The host may choose a lower one.
+    fn set_clock(&mut self, hz: u32) -> DevResult;
+
+    /// Whether the host and board support the given bus width.
+    fn supports_bus_width(&self, width: BusWidth) -> bool;
+
+    /// Sets the data bus width on the host side.
+    fn set_bus_width(&mut self, width: BusWidth) -> DevResult;
+
+    /// Whether the host and board support the given bus timing. Hosts that
+    /// support the UHS-I modes must implement [`switch_signal_voltage`] and
+    /// [`execute_tuning`].
+    ///
+    /// [`switch_signal_voltage`]: MmcHost::switch_signal_voltage
+    /// [`execute_tuning`]: MmcHost::execute_tuning
+    fn supports_timing(&self, timing: BusTiming) -> bool {
+        timing == BusTiming::Default
+    }
+
+    /// Sets the bus timing on the host side. The clock is set afterwards.
+    fn set_timing(&mut self, timing: BusTiming) -> DevResult {
+        match timing {
+            BusTiming::Default => Ok(()),
+            _ => Err(DevError::Unsupported),
+        }
+    }
+
+    /// Switches the signaling voltage to 1.8V, after the card accepted
+    /// VOLTAGE_SWITCH (CMD11).
+    fn switch_signal_voltage(&mut self) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// Tunes the sampling clock with the given tuning command, after
+    /// switching to a UHS-I timing. Hosts that do not need tuning in the
+    /// current timing return `Ok` directly.
+    fn execute_tuning(&mut self, _index: u8) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// Resets the command and data lines after a failed transfer, keeping
+    /// the clock and bus settings.
+    fn reset_lines(&mut self) -> DevResult {
+        Ok(())
+    }
+
+    /// The maximum number of blocks in one data transfer.
+    fn max_blocks(&self) -> usize {
+        u16::MAX as usize
+    }
+
+    /// Sends a command, with an optional data phase, and waits for the
+    /// response and the end of the data transfer. Transfers of more than one
+    /// block are stopped by the host with CMD12 (auto CMD12).
+    fn send_command(&mut self, cmd: &MmcCommand, data: Option<MmcData>) -> DevResult<Response>;
+}
@@ -0,0 +1,133 @@
+//! The hardware partitions of an eMMC device as separate block devices.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::rpmb::{RpmbFrame, RpmbTransport};
+use super::{HwPartition, MmcCard, MmcHost, BLOCK_SIZE};
+use crate::recovery::RecoveryStats;
+use crate::{BlockCapabilities, BlockDriverOps, DiscardLimits};
+use driver_common::{BaseDriverOps, DevResult, DeviceType};
+
+/// A hardware partition of an eMMC device, as a block device.
+///
+/// The partitions of a device share it, each request switches to its
+/// partition first if another one was accessed last.
+pub struct MmcPartition<H: MmcHost> {
+    name: String,
+    card: Arc<Mutex<MmcCard<H>>>,
+    part: HwPartition,
+}
+
+impl<H: MmcHost> MmcCard<H> {
+    /// Returns a block device for each hardware partition of the device, the
+    /// user area first. The RPMB partition is left out, as it only accepts
+    /// authenticated requests.
+    ///
+    /// They are named after the host, e.g. `sdhci` for the user area and
+    /// `sdhciboot0` or `sdhcigp1` for the others.
+    pub fn into_partitions(self) -> Vec<MmcPartition<H>> {
+        let parts = [
+            (HwPartition::User, ""),
+            (HwPartition::Boot0, "boot0"),
+            (HwPartition::Boot1, "boot1"),
+            (HwPartition::GeneralPurpose(0), "gp0"),
+            (HwPartition::GeneralPurpose(1), "gp1"),
+            (HwPartition::GeneralPurpose(2), "gp2"),
+            (HwPartition::GeneralPurpose(3), "gp3"),
+        ];
+        let parts: Vec<_> = parts
+            .into_iter()
+            .filter(|&(part, _)| self.partition_blocks(part) != 0)
+            .map(|(part, suffix)| (part, alloc::format!("{}{}", self.host().name(), suffix)))
+            .collect();
+        let card = Arc::new(Mutex::new(self));
+        parts
+            .into_iter()
+            .map(|(part, name)| MmcPartition {
+                name,
+                card: card.clone(),
+                part,
+            })
+            .collect()
+    }
+}
+
+impl<H: MmcHost> MmcPartition<H> {
+    /// The hardware partition accessed by this device.
+    pub const fn partition(&self) -> HwPartition {
+        self.part
+    }
+
+    /// The errors seen and the recovery actions taken so far on the device,
+    /// in all its partitions.
+    pub fn recovery_stats(&self) -> RecoveryStats {
+        self.card.lock().recovery_stats()
+    }
+}
+
+impl<H: MmcHost> BaseDriverOps for MmcPartition<H> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<H: MmcHost> BlockDriverOps for MmcPartition<H> {
+    fn num_blocks(&self) -> u64 {
+        self.card.lock().partition_blocks(self.part)
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn media_generation(&self) -> u64 {
+        self.card.lock().media_generation()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.card.lock().read_partition(self.part, block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.card.lock().write_partition(self.part, block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> DevResult {
+        self.card.lock().discard_partition(self.part, start, count)
+    }
+
+    fn discard_limits(&self) -> Option<DiscardLimits> {
+        self.card.lock().partition_discard_limits(self.part)
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.card.lock().partition_capabilities(self.part)
+    }
+}
+
+/// Any partition of a device gives access to its RPMB partition. Requests to
+/// other partitions of the device must not come between an RPMB request and
+/// its response.
+impl<H: MmcHost> RpmbTransport for MmcPartition<H> {
+    fn send_frame(&mut self, frame: &RpmbFrame, reliable: bool) -> DevResult {
+        self.card.lock().send_frame(frame, reliable)
+    }
+
+    fn recv_frame(&mut self, frame: &mut RpmbFrame) -> DevResult {
+        self.card.lock().recv_frame(frame)
+    }
+}
@@ -0,0 +1,291 @@
+//! Parsing of the card registers: CID, CSD, SCR and the SD status.
+
+use core::fmt;
+
+use super::{BusWidth, CardKind, BLOCK_SIZE};
+
+/// Extracts bits `hi..=lo` of a 128-bit card register.
+const fn bits(reg: u128, hi: u32, lo: u32) -> u32 {
+    ((reg >> lo) & ((1 << (hi - lo + 1)) - 1)) as u32
+}
+
+/// The card identification register (CID).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cid {
+    /// Manufacturer ID, assigned by the SD Association or JEDEC.
+    pub manufacturer_id: u8,
+    /// OEM/application ID: two ASCII characters on SD cards, one byte on MMC.
+    pub oem_id: u16,
+    /// Product name, ASCII padded with NULs (5 characters on SD cards).
+    pub product_name: [u8; 6],
+    /// Product revision, as (major, minor).
+    pub revision: (u8, u8),
+    /// Product serial number.
+    pub serial: u32,
+    /// Manufacturing date, as (year, month). MMC devices count years from
+    /// 1997, those of EXT_CSD revision 5 or later may report 16 years less.
+    pub date: (u16, u8),
+}
+
+impl Cid {
+    /// Parses a raw CID register of a card of the given family.
+    pub const fn from_raw(cid: u128, kind: CardKind) -> Self {
+        let mut product_name = [0; 6];
+        match kind {
+            CardKind::Sd => {
+                let mut i = 0;
+                while i < 5 {
+                    product_name[i] = (cid >> (96 - 8 * i)) as u8;
+                    i += 1;
+                }
+                Self {
+                    manufacturer_id: bits(cid, 127, 120) as u8,
+                    oem_id: bits(cid, 119, 104) as u16,
+                    product_name,
+                    revision: (bits(cid, 63, 60) as u8, bits(cid, 59, 56) as u8),
+                    serial: bits(cid, 55, 24),
+                    date: (2000 + bits(cid, 19, 12) as u16, bits(cid, 11, 8) as u8),
+                }
+            }
+            CardKind::Mmc => {
+                let mut i = 0;
+                while i < 6 {
+                    product_name[i] = (cid >> (96 - 8 * i)) as u8;
+                    i += 1;
+                }
+                Self {
+                    manufacturer_id: bits(cid, 127, 120) as u8,
+                    oem_id: bits(cid, 111, 104) as u16,
+                    product_name,
+                    revision: (bits(cid, 55, 52) as u8, bits(cid, 51, 48) as u8),
+                    serial: bits(cid, 47, 16),
+                    date: (1997 + bits(cid, 11, 8) as u16, bits(cid, 15, 12) as u8),
+                }
+            }
+        }
+    }
+
+    /// The product name, without padding. Empty if it is not ASCII.
+    pub fn name(&self) -> &str {
+        let len = self.product_name.iter().position(|&c| c == 0);
+        let name = &self.product_name[..len.unwrap_or(self.product_name.len())];
+        match core::str::from_utf8(name) {
+            Ok(name) if name.is_ascii() => name.trim_end(),
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} rev {}.{} (manufacturer {:#04x}, OEM {:#06x}), serial {:#010x}, {}-{:02}",
+            self.name(),
+            self.revision.0,
+            self.revision.1,
+            self.manufacturer_id,
+            self.oem_id,
+            self.serial,
+            self.date.0,
+            self.date.1
+        )
+    }
+}
+
+/// The card specific data register (CSD).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Csd {
+    /// CSD structure version, as stored in the register.
+    pub version: u8,
+    /// The version of the MMC system specification (SPEC_VERS), 0 on SD
+    /// cards. Devices of version 4 or later have an EXT_CSD.
+    pub mmc_spec_version: u8,
+    /// The capacity in 512-byte blocks. MMC devices larger than 2 GiB report
+    /// their size in the EXT_CSD instead, this is meaningless for them.
+    pub num_blocks: u64,
+    /// The maximum bus clock frequency (TRAN_SPEED), in Hz.
+    pub max_clock: u32,
+    /// The supported card command classes (CCC), one bit per class.
+    pub command_classes: u16,
+    /// Whether the content is a copy (COPY).
+    pub copy: bool,
+    /// Whether the whole card is permanently write protected.
+    pub perm_write_protect: bool,
+    /// Whether the whole card is temporarily write protected.
+    pub tmp_write_protect: bool,
+    /// Whether write protection of groups of sectors is supported.
+    pub group_write_protect: bool,
+    /// The number of 512-byte blocks erased as one unit: 1 for SD cards that
+    /// erase single blocks (ERASE_BLK_EN), otherwise the erase sector size of
+    /// SD cards (SECTOR_SIZE) or the erase group size of MMC devices.
+    pub erase_blocks: u32,
+}
+
+impl Csd {
+    /// Parses a raw CSD register of a card of the given family.
+    pub const fn from_raw(csd: u128, kind: CardKind) -> Self {
+        Self {
+            version: bits(csd, 127, 126) as u8,
+            mmc_spec_version: match kind {
+                CardKind::Sd => 0,
+                CardKind::Mmc => bits(csd, 125, 122) as u8,
+            },
+            num_blocks: csd_num_blocks(csd, kind),
+            max_clock: tran_speed(bits(csd, 103, 96)),
+            command_classes: bits(csd, 95, 84) as u16,
+            copy: bits(csd, 14, 14) != 0,
+            perm_write_protect: bits(csd, 13, 13) != 0,
+            tmp_write_protect: bits(csd, 12, 12) != 0,
+            group_write_protect: bits(csd, 31, 31) != 0,
+            erase_blocks: csd_erase_blocks(csd, kind),
+        }
+    }
+
+    /// Whether the card must not be written to.
+    pub const fn write_protected(&self) -> bool {
+        self.perm_write_protect || self.tmp_write_protect
+    }
+}
+
+/// Computes the number of 512-byte blocks from a CSD register.
+const fn csd_num_blocks(csd: u128, kind: CardKind) -> u64 {
+    if matches!(kind, CardKind::Sd) && bits(csd, 127, 126) == 1 {
+        // CSD version 2.0: C_SIZE in units of 512 KiB.
+        return (bits(csd, 69, 48) as u64 + 1) * 1024;
+    }
+    let c_size = bits(csd, 73, 62) as u64;
+    let c_size_mult = bits(csd, 49, 47);
+    let read_bl_len = bits(csd, 83, 80);
+    let bytes = (c_size + 1) << (c_size_mult + 2 + read_bl_len);
+    bytes / BLOCK_SIZE as u64
+}
+
+/// Computes the erase unit in 512-byte blocks from a CSD register.
+const fn csd_erase_blocks(csd: u128, kind: CardKind) -> u32 {
+    let units = match kind {
+        CardKind::Sd if bits(csd, 46, 46) != 0 => return 1,
+        CardKind::Sd => bits(csd, 45, 39) + 1,
+        CardKind::Mmc => (bits(csd, 46, 42) + 1) * (bits(csd, 41, 37) + 1),
+    };
+    // In units of the write block length (WRITE_BL_LEN).
+    let blocks = (units << bits(csd, 25, 22)) / BLOCK_SIZE as u32;
+    if blocks == 0 {
+        1
+    } else {
+        blocks
+    }
+}
+
+/// Decodes the TRAN_SPEED field to a frequency in Hz, 0 if reserved.
+const fn tran_speed(val: u32) -> u32 {
+    // Tenths of the mantissa.
+    const MULT: [u32; 16] = [
+        0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+    ];
+    let unit = val & 0x7;
+    if unit > 3 {
+        return 0;
+    }
+    // 100 kbit/s for unit 0.
+    10_000 * 10u32.pow(unit) * MULT[(val >> 3) as usize & 0xf]
+}
+
+/// The SD configuration register (SCR), which MMC devices do not have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scr {
+    /// The version of the physical layer specification the card complies
+    /// with, as (major, minor). Only the major version is known after 3.0.
+    pub spec_version: (u8, u8),
+    /// Whether the card supports a 4-bit data bus.
+    pub bus_width_4: bool,
+    /// The value of erased bytes.
+    pub erased_byte: u8,
+    /// The security version (CPRM): 0 if none, 2 for SDSC, 3 for SDHC and 4
+    /// for SDXC.
+    pub security: u8,
+    /// Whether the card supports SET_BLOCK_COUNT (CMD23).
+    pub set_block_count: bool,
+}
+
+impl Scr {
+    /// Parses a raw SCR register.
+    pub const fn from_raw(scr: u64) -> Self {
+        let scr = scr as u128;
+        let spec_x = bits(scr, 41, 38) as u8;
+        let spec_version = match (bits(scr, 59, 56), bits(scr, 47, 47), bits(scr, 42, 42)) {
+            (0, ..) => (1, 0),
+            (1, ..) => (1, 1),
+            (2, 0, _) => (2, 0),
+            (2, 1, _) if spec_x != 0 => (spec_x + 4, 0),
+            (2, 1, 1) => (4, 0),
+            _ => (3, 0),
+        };
+        Self {
+            spec_version,
+            bus_width_4: bits(scr, 50, 50) != 0,
+            erased_byte: if bits(scr, 55, 55) != 0 { 0xff } else { 0 },
+            security: bits(scr, 54, 52) as u8,
+            set_block_count: bits(scr, 33, 33) != 0,
+        }
+    }
+
+    /// Whether the card supports the given data bus width.
+    pub const fn supports_bus_width(&self, width: BusWidth) -> bool {
+        match width {
+            BusWidth::Width1 => true,
+            BusWidth::Width4 => self.bus_width_4,
+            BusWidth::Width8 => false,
+        }
+    }
+}
+
+/// The SD status, which MMC devices do not have. It rates the performance
+/// of the card.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SdStatus {
+    /// The speed class, the minimum sequential write speed in MB/s (2, 4, 6
+    /// or 10), 0 if the card is not rated.
+    pub speed_class: u8,
+    /// The UHS speed grade, the minimum sequential write speed in units of
+    /// 10 MB/s (1 for U1, 3 for U3), 0 if the card is not rated.
+    pub uhs_speed_grade: u8,
+    /// The video speed class, the minimum sequential write speed in MB/s
+    /// (6, 10, 30, 60 or 90), 0 if the card is not rated.
+    pub video_speed_class: u8,
+    /// The application performance class (1 for A1, 2 for A2), 0 if the
+    /// card is not rated.
+    pub app_perf_class: u8,
+    /// The size of the allocation unit in bytes, 0 if not defined.
+    pub au_size: u32,
+}
+
+impl SdStatus {
+    /// Parses a raw SD status, as read from the card (most significant
+    /// byte first).
+    pub const fn from_raw(status: &[u8; 64]) -> Self {
+        let speed_class = match status[8] {
+            1 => 2,
+            2 => 4,
+            3 => 6,
+            4 => 10,
+            _ => 0,
+        };
+        let au_size = match status[10] >> 4 {
+            0 => 0,
+            n @ 1..=10 => 0x4000 << (n - 1),
+            11 => 12 << 20,
+            12 => 16 << 20,
+            13 => 24 << 20,
+            14 => 32 << 20,
+            _ => 64 << 20,
+        };
+        Self {
+            speed_class,
+            uhs_speed_grade: status[14] >> 4,
+            video_speed_class: status[15],
+            app_perf_class: status[21] & 0xf,
+            au_size,
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! An RPMB partition emulated on the device side of [`RpmbTransport`].
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::*;
+
+/// An emulated RPMB partition, backed by memory.
+///
+/// It implements [`RpmbTransport`] itself, so it can be handed directly to
+/// [`Rpmb::new`]. Requests are checked as a device would: MACs of writes
+/// with the programmed key, write counters, addresses and the reliable
+/// write flag.
+pub struct RpmbEmulator {
+    data: Vec<[u8; DATA_SIZE]>,
+    key: Option<[u8; KEY_SIZE]>,
+    counter: u32,
+    /// The response to the last key programming or write request, returned
+    /// by a result read request.
+    result: Option<RpmbFrame>,
+    /// The response to the last request, sent by the next receive.
+    response: Option<RpmbFrame>,
+}
+
+impl RpmbEmulator {
+    /// Creates an emulated partition of `num_frames` 256-byte frames, zeroed,
+    /// with no key programmed.
+    pub fn new(num_frames: usize) -> Self {
+        Self {
+            data: vec![[0; DATA_SIZE]; num_frames.min(u16::MAX as usize + 1)],
+            key: None,
+            counter: 0,
+            result: None,
+            response: None,
+        }
+    }
+
+    /// The content of the partition.
+    pub fn data(&self) -> &[[u8; DATA_SIZE]] {
+        &self.data
+    }
+
+    /// The mutable content of the partition, to emulate tampering.
+    pub fn data_mut(&mut self) -> &mut [[u8; DATA_SIZE]] {
+        &mut self.data
+    }
+
+    /// The write counter.
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Sets the write counter, to emulate a device near its end of life.
+    pub fn set_counter(&mut self, counter: u32) {
+        self.counter = counter;
+    }
+
+    /// Whether the authentication key was programmed.
+    pub fn key_programmed(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// The response frame to a request, with the result and the expired flag.
+    fn response(&self, req: u16, result: u16) -> RpmbFrame {
+        let mut resp = RpmbFrame::new();
+        resp.set_req_resp(req << RESP_SHIFT);
+        let expired = if self.counter == u32::MAX {
+            RESULT_COUNTER_EXPIRED
+        } else {
+            0
+        };
+        resp.set_result(result | expired);
+        resp
+    }
+
+    fn program_key(&mut self, req: &RpmbFrame, reliable: bool) -> RpmbFrame {
+        if self.key.is_some() || !reliable {
+            return self.response(REQ_PROGRAM_KEY, RESULT_GENERAL_FAILURE);
+        }
+        self.key = Some(*req.key_mac());
+        self.response(REQ_PROGRAM_KEY, RESULT_OK)
+    }
+
+    fn read_counter(&self, req: &RpmbFrame) -> RpmbFrame {
+        let Some(key) = self.key else {
+            return self.response(REQ_READ_COUNTER, RESULT_KEY_NOT_PROGRAMMED);
+        };
+        let mut resp = self.response(REQ_READ_COUNTER, RESULT_OK);
+        *resp.nonce_mut() = *req.nonce();
+        resp.set_write_counter(self.counter);
+        resp.sign(&key);
+        resp
+    }
+
+    fn auth_write(&mut self, req: &RpmbFrame, reliable: bool) -> RpmbFrame {
+        let Some(key) = self.key else {
+            return self.response(REQ_AUTH_WRITE, RESULT_KEY_NOT_PROGRAMMED);
+        };
+        let address = req.address() as usize;
+        let result = if !reliable || req.block_count() != 1 {
+            RESULT_GENERAL_FAILURE
+        } else if !req.verify(&key) {
+            RESULT_AUTH_FAILURE
+        } else if req.write_counter() != self.counter {
+            RESULT_COUNTER_FAILURE
+        } else if address >= self.data.len() {
+            RESULT_ADDRESS_FAILURE
+        } else if self.counter == u32::MAX {
+            RESULT_WRITE_FAILURE
+        } else {
+            self.data[address] = *req.data();
+            self.counter += 1;
+            RESULT_OK
+        };
+        let mut resp = self.response(REQ_AUTH_WRITE, result);
+        resp.set_write_counter(self.counter);
+        resp.set_address(req.address());
+        resp.sign(&key);
+        resp
+    }
+
+    fn auth_read(&self, req: &RpmbFrame) -> RpmbFrame {
+        let Some(key) = self.key else {
+            return self.response(REQ_AUTH_READ, RESULT_KEY_NOT_PROGRAMMED);
+        };
+        let address = req.address() as usize;
+        let mut resp = match self.data.get(address) {
+            Some(data) => {
+                let mut resp = self.response(REQ_AUTH_READ, RESULT_OK);
+                *resp.data_mut() = *data;
+                resp.set_block_count(1);
+                resp
+            }
+            None => self.response(REQ_AUTH_READ, RESULT_ADDRESS_FAILURE),
+        };
+        *resp.nonce_mut() = *req.nonce();
+        resp.set_address(req.address());
+        resp.sign(&key);
+        resp
+    }
+}
+
+impl RpmbTransport for RpmbEmulator {
+    fn send_frame(&mut self, frame: &RpmbFrame, reliable: bool) -> DevResult {
+        self.response = None;
+        match frame.req_resp() {
+            REQ_PROGRAM_KEY => self.result = Some(self.program_key(frame, reliable)),
+            REQ_AUTH_WRITE => self.result = Some(self.auth_write(frame, reliable)),
+            REQ_READ_COUNTER => self.response = Some(self.read_counter(frame)),
+            REQ_AUTH_READ => self.response = Some(self.auth_read(frame)),
+            REQ_RESULT_READ => self.response = self.result.take(),
+            req => {
+                log::warn!("rpmb emulator: unknown request type {:#06x}", req);
+                return Err(DevError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn recv_frame(&mut self, frame: &mut RpmbFrame) -> DevResult {
+        *frame = self.response.take().ok_or(DevError::Io)?;
+        Ok(())
+    }
+}
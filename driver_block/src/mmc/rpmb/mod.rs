@@ -0,0 +1,457 @@
+//! Authenticated access to the replay protected memory block (RPMB) of eMMC
+//! devices.
+//!
+//! The RPMB partition is only accessed with 512-byte request and response
+//! frames. Writes and reads are authenticated with an HMAC-SHA256 over the
+//! frame content, keyed with a 256-bit key programmed once into the device.
+//! Writes carry the device write counter, which makes replayed frames fail,
+//! and reads carry a caller-chosen nonce, which makes replayed responses
+//! detectable.
+//!
+//! [`Rpmb`] implements the protocol on top of an [`RpmbTransport`], which
+//! only moves frames: [`MmcCard`](super::MmcCard) for real devices, or
+//! [`RpmbEmulator`] to test without one.
+
+mod emu;
+mod sha256;
+
+pub use self::emu::RpmbEmulator;
+pub use self::sha256::{HmacSha256, Sha256};
+
+use super::{DataBuf, HwPartition, MmcCard, MmcHost, BLOCK_SIZE};
+use driver_common::{DevError, DevResult};
+
+/// The size of an RPMB frame.
+pub const FRAME_SIZE: usize = 512;
+/// The size of the data carried by an RPMB frame.
+pub const DATA_SIZE: usize = 256;
+/// The size of the authentication key and of the MAC.
+pub const KEY_SIZE: usize = 32;
+/// The size of the nonce of read requests.
+pub const NONCE_SIZE: usize = 16;
+
+const KEY_MAC_OFFSET: usize = 196;
+const DATA_OFFSET: usize = 228;
+const NONCE_OFFSET: usize = 484;
+const WRITE_COUNTER_OFFSET: usize = 500;
+const ADDRESS_OFFSET: usize = 504;
+const BLOCK_COUNT_OFFSET: usize = 506;
+const RESULT_OFFSET: usize = 508;
+const REQ_RESP_OFFSET: usize = 510;
+
+const REQ_PROGRAM_KEY: u16 = 0x0001;
+const REQ_READ_COUNTER: u16 = 0x0002;
+const REQ_AUTH_WRITE: u16 = 0x0003;
+const REQ_AUTH_READ: u16 = 0x0004;
+const REQ_RESULT_READ: u16 = 0x0005;
+/// Response types are their request type shifted by 8 bits.
+const RESP_SHIFT: u32 = 8;
+
+const RESULT_OK: u16 = 0x00;
+const RESULT_GENERAL_FAILURE: u16 = 0x01;
+const RESULT_AUTH_FAILURE: u16 = 0x02;
+const RESULT_COUNTER_FAILURE: u16 = 0x03;
+const RESULT_ADDRESS_FAILURE: u16 = 0x04;
+const RESULT_WRITE_FAILURE: u16 = 0x05;
+const RESULT_READ_FAILURE: u16 = 0x06;
+const RESULT_KEY_NOT_PROGRAMMED: u16 = 0x07;
+const RESULT_MASK: u16 = 0x7f;
+/// Set in all results once the write counter reached its maximum value.
+const RESULT_COUNTER_EXPIRED: u16 = 0x80;
+
+/// A request or response frame of the RPMB protocol.
+///
+/// Multi-byte fields are big-endian. The MAC covers the frame from the data
+/// field to the end, 284 bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpmbFrame {
+    bytes: [u8; FRAME_SIZE],
+}
+
+impl RpmbFrame {
+    /// Creates a frame with all fields zero.
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; FRAME_SIZE],
+        }
+    }
+
+    /// Creates a request frame of the given type, other fields zero.
+    fn request(req: u16) -> Self {
+        let mut frame = Self::new();
+        frame.set_req_resp(req);
+        frame
+    }
+
+    /// The raw frame, as transferred.
+    pub const fn as_bytes(&self) -> &[u8; FRAME_SIZE] {
+        &self.bytes
+    }
+
+    /// The mutable raw frame.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8; FRAME_SIZE] {
+        &mut self.bytes
+    }
+
+    /// The authentication key (key programming requests) or the MAC.
+    pub fn key_mac(&self) -> &[u8; KEY_SIZE] {
+        self.bytes[KEY_MAC_OFFSET..DATA_OFFSET].try_into().unwrap()
+    }
+
+    /// The mutable authentication key or MAC.
+    pub fn key_mac_mut(&mut self) -> &mut [u8; KEY_SIZE] {
+        (&mut self.bytes[KEY_MAC_OFFSET..DATA_OFFSET])
+            .try_into()
+            .unwrap()
+    }
+
+    /// The data read or written.
+    pub fn data(&self) -> &[u8; DATA_SIZE] {
+        self.bytes[DATA_OFFSET..NONCE_OFFSET].try_into().unwrap()
+    }
+
+    /// The mutable data read or written.
+    pub fn data_mut(&mut self) -> &mut [u8; DATA_SIZE] {
+        (&mut self.bytes[DATA_OFFSET..NONCE_OFFSET])
+            .try_into()
+            .unwrap()
+    }
+
+    /// The nonce of read requests, returned in their responses.
+    pub fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        self.bytes[NONCE_OFFSET..WRITE_COUNTER_OFFSET]
+            .try_into()
+            .unwrap()
+    }
+
+    /// The mutable nonce.
+    pub fn nonce_mut(&mut self) -> &mut [u8; NONCE_SIZE] {
+        (&mut self.bytes[NONCE_OFFSET..WRITE_COUNTER_OFFSET])
+            .try_into()
+            .unwrap()
+    }
+
+    /// The write counter.
+    pub fn write_counter(&self) -> u32 {
+        let counter = &self.bytes[WRITE_COUNTER_OFFSET..ADDRESS_OFFSET];
+        u32::from_be_bytes(counter.try_into().unwrap())
+    }
+
+    /// Sets the write counter.
+    pub fn set_write_counter(&mut self, counter: u32) {
+        self.set_field(WRITE_COUNTER_OFFSET, &counter.to_be_bytes());
+    }
+
+    /// The address of the data, in 256-byte units.
+    pub fn address(&self) -> u16 {
+        self.u16_at(ADDRESS_OFFSET)
+    }
+
+    /// Sets the address of the data.
+    pub fn set_address(&mut self, address: u16) {
+        self.set_field(ADDRESS_OFFSET, &address.to_be_bytes());
+    }
+
+    /// The number of frames of the request or response.
+    pub fn block_count(&self) -> u16 {
+        self.u16_at(BLOCK_COUNT_OFFSET)
+    }
+
+    /// Sets the number of frames of the request or response.
+    pub fn set_block_count(&mut self, count: u16) {
+        self.set_field(BLOCK_COUNT_OFFSET, &count.to_be_bytes());
+    }
+
+    /// The operation result of a response.
+    pub fn result(&self) -> u16 {
+        self.u16_at(RESULT_OFFSET)
+    }
+
+    /// Sets the operation result.
+    pub fn set_result(&mut self, result: u16) {
+        self.set_field(RESULT_OFFSET, &result.to_be_bytes());
+    }
+
+    /// The request or response type.
+    pub fn req_resp(&self) -> u16 {
+        self.u16_at(REQ_RESP_OFFSET)
+    }
+
+    /// Sets the request or response type.
+    pub fn set_req_resp(&mut self, req_resp: u16) {
+        self.set_field(REQ_RESP_OFFSET, &req_resp.to_be_bytes());
+    }
+
+    /// Computes the MAC of the frame with `key`.
+    pub fn compute_mac(&self, key: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+        let mut mac = HmacSha256::new(key);
+        mac.update(&self.bytes[DATA_OFFSET..]);
+        mac.finish()
+    }
+
+    /// Computes the MAC of the frame with `key` and stores it in the frame.
+    pub fn sign(&mut self, key: &[u8; KEY_SIZE]) {
+        *self.key_mac_mut() = self.compute_mac(key);
+    }
+
+    /// Whether the MAC of the frame is the one computed with `key`. The
+    /// comparison takes the same time wherever the MACs differ.
+    pub fn verify(&self, key: &[u8; KEY_SIZE]) -> bool {
+        let mac = self.compute_mac(key);
+        mac.iter()
+            .zip(self.key_mac())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    fn set_field(&mut self, offset: usize, val: &[u8]) {
+        self.bytes[offset..offset + val.len()].copy_from_slice(val);
+    }
+}
+
+impl Default for RpmbFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Moves RPMB frames to and from a device.
+///
+/// Requests and responses of one frame only are supported, which is all
+/// [`Rpmb`] uses.
+pub trait RpmbTransport {
+    /// Sends a request frame. `reliable` is set for key programming and
+    /// authenticated writes, which the device must complete atomically.
+    fn send_frame(&mut self, frame: &RpmbFrame, reliable: bool) -> DevResult;
+
+    /// Receives the response frame of the last request.
+    fn recv_frame(&mut self, frame: &mut RpmbFrame) -> DevResult;
+}
+
+impl<T: RpmbTransport + ?Sized> RpmbTransport for &mut T {
+    fn send_frame(&mut self, frame: &RpmbFrame, reliable: bool) -> DevResult {
+        (**self).send_frame(frame, reliable)
+    }
+
+    fn recv_frame(&mut self, frame: &mut RpmbFrame) -> DevResult {
+        (**self).recv_frame(frame)
+    }
+}
+
+/// The RPMB partition of a card is accessed through the card itself, which
+/// switches back to another partition on the next block request. Fails with
+/// [`DevError::Unsupported`] if the device has no RPMB partition.
+impl<H: MmcHost> RpmbTransport for MmcCard<H> {
+    fn send_frame(&mut self, frame: &RpmbFrame, reliable: bool) -> DevResult {
+        self.rpmb_transfer(DataBuf::Write(&frame.bytes), reliable)
+    }
+
+    fn recv_frame(&mut self, frame: &mut RpmbFrame) -> DevResult {
+        self.rpmb_transfer(DataBuf::Read(&mut frame.bytes), false)
+    }
+}
+
+impl<H: MmcHost> MmcCard<H> {
+    /// The size of the RPMB partition in 256-byte frames, 0 if the device
+    /// does not have one.
+    pub fn rpmb_frames(&self) -> u64 {
+        self.partition_blocks(HwPartition::Rpmb) * (BLOCK_SIZE / DATA_SIZE) as u64
+    }
+}
+
+/// Authenticated requests to an RPMB partition.
+///
+/// The key is passed to each request rather than kept here. Nonces must be
+/// unpredictable for reads to be protected against replayed responses, so
+/// they are chosen by the caller.
+pub struct Rpmb<T: RpmbTransport> {
+    transport: T,
+    /// The write counter of the last authenticated response.
+    counter: Option<u32>,
+}
+
+impl<T: RpmbTransport> Rpmb<T> {
+    /// Creates an RPMB client over `transport`.
+    pub const fn new(transport: T) -> Self {
+        Self {
+            transport,
+            counter: None,
+        }
+    }
+
+    /// Returns a reference to the transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns a mutable reference to the transport.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Consumes the client, returning the transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Programs the authentication key. This can be done only once in the
+    /// lifetime of a device: fails with [`DevError::AlreadyExists`] if a key
+    /// was programmed already.
+    pub fn program_key(&mut self, key: &[u8; KEY_SIZE]) -> DevResult {
+        let mut req = RpmbFrame::request(REQ_PROGRAM_KEY);
+        *req.key_mac_mut() = *key;
+        self.transport.send_frame(&req, true)?;
+        let resp = self.read_result(REQ_PROGRAM_KEY)?;
+        match resp.result() & RESULT_MASK {
+            RESULT_GENERAL_FAILURE => Err(DevError::AlreadyExists),
+            _ => check_result(&resp),
+        }
+    }
+
+    /// Reads the write counter, authenticated with `key`. The response must
+    /// carry `nonce`.
+    pub fn read_counter(
+        &mut self,
+        key: &[u8; KEY_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+    ) -> DevResult<u32> {
+        let mut req = RpmbFrame::request(REQ_READ_COUNTER);
+        *req.nonce_mut() = *nonce;
+        self.transport.send_frame(&req, false)?;
+        let resp = self.recv(REQ_READ_COUNTER)?;
+        check_response(&resp, key)?;
+        if resp.nonce() != nonce {
+            log::warn!("rpmb: counter response nonce mismatch");
+            return Err(DevError::Io);
+        }
+        self.counter = Some(resp.write_counter());
+        Ok(resp.write_counter())
+    }
+
+    /// Reads the 256 bytes at `address`, authenticated with `key`. The
+    /// response must carry `nonce`.
+    pub fn read(
+        &mut self,
+        key: &[u8; KEY_SIZE],
+        address: u16,
+        nonce: &[u8; NONCE_SIZE],
+        buf: &mut [u8; DATA_SIZE],
+    ) -> DevResult {
+        let mut req = RpmbFrame::request(REQ_AUTH_READ);
+        *req.nonce_mut() = *nonce;
+        req.set_address(address);
+        self.transport.send_frame(&req, false)?;
+        let resp = self.recv(REQ_AUTH_READ)?;
+        check_response(&resp, key)?;
+        if resp.nonce() != nonce || resp.address() != address {
+            log::warn!("rpmb: read response does not match the request");
+            return Err(DevError::Io);
+        }
+        buf.copy_from_slice(resp.data());
+        Ok(())
+    }
+
+    /// Writes 256 bytes at `address`, authenticated with `key`, and returns
+    /// the new write counter.
+    ///
+    /// The write counter is read first, with a zero nonce, if no response
+    /// carried it yet. A counter mismatch (the device was written by someone
+    /// else) fails the write and forgets the counter, retrying reads it again.
+    pub fn write(
+        &mut self,
+        key: &[u8; KEY_SIZE],
+        address: u16,
+        data: &[u8; DATA_SIZE],
+    ) -> DevResult<u32> {
+        let counter = match self.counter {
+            Some(counter) => counter,
+            None => self.read_counter(key, &[0; NONCE_SIZE])?,
+        };
+        let mut req = RpmbFrame::request(REQ_AUTH_WRITE);
+        *req.data_mut() = *data;
+        req.set_write_counter(counter);
+        req.set_address(address);
+        req.set_block_count(1);
+        req.sign(key);
+        self.transport.send_frame(&req, true)?;
+        let resp = self
+            .read_result(REQ_AUTH_WRITE)
+            .and_then(|resp| check_response(&resp, key).map(|_| resp));
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.counter = None;
+                return Err(e);
+            }
+        };
+        if resp.address() != address || resp.write_counter() != counter.wrapping_add(1) {
+            log::warn!("rpmb: write response does not match the request");
+            self.counter = None;
+            return Err(DevError::Io);
+        }
+        self.counter = Some(resp.write_counter());
+        Ok(resp.write_counter())
+    }
+
+    /// Reads the response to the last key programming or write request.
+    fn read_result(&mut self, req: u16) -> DevResult<RpmbFrame> {
+        self.transport
+            .send_frame(&RpmbFrame::request(REQ_RESULT_READ), false)?;
+        self.recv(req)
+    }
+
+    /// Receives the response to a request and checks its type.
+    fn recv(&mut self, req: u16) -> DevResult<RpmbFrame> {
+        let mut resp = RpmbFrame::new();
+        self.transport.recv_frame(&mut resp)?;
+        if resp.req_resp() != req << RESP_SHIFT {
+            log::warn!(
+                "rpmb: unexpected response type {:#06x} to request {:#06x}",
+                resp.req_resp(),
+                req
+            );
+            return Err(DevError::Io);
+        }
+        Ok(resp)
+    }
+}
+
+/// Checks the result and the MAC of the response to an authenticated
+/// request.
+fn check_response(resp: &RpmbFrame, key: &[u8; KEY_SIZE]) -> DevResult {
+    check_result(resp)?;
+    if !resp.verify(key) {
+        log::warn!("rpmb: response MAC mismatch");
+        return Err(DevError::Io);
+    }
+    Ok(())
+}
+
+/// Converts the operation result of a response to an error.
+fn check_result(resp: &RpmbFrame) -> DevResult {
+    let result = resp.result();
+    if result & RESULT_COUNTER_EXPIRED != 0 {
+        log::warn!("rpmb: write counter expired");
+    }
+    match result & RESULT_MASK {
+        RESULT_OK => Ok(()),
+        RESULT_KEY_NOT_PROGRAMMED => Err(DevError::BadState),
+        RESULT_ADDRESS_FAILURE => Err(DevError::InvalidParam),
+        code => {
+            let reason = match code {
+                RESULT_GENERAL_FAILURE => "general failure",
+                RESULT_AUTH_FAILURE => "authentication failure",
+                RESULT_COUNTER_FAILURE => "counter failure",
+                RESULT_WRITE_FAILURE => "write failure",
+                RESULT_READ_FAILURE => "read failure",
+                _ => "unknown result",
+            };
+            log::warn!("rpmb: request failed: {} ({:#x})", reason, result);
+            Err(DevError::Io)
+        }
+    }
+}
@@ -0,0 +1,562 @@
+//! NVM Express (NVMe) controller driver.
+//!
+//! The controller is driven with one admin queue pair and one I/O queue pair
+//! in polling mode. Each active namespace is exposed as its own block device
+//! through [`NvmeNamespace`].
+
+mod queue;
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use spin::Mutex;
+
+use self::queue::{Command, QueuePair};
+use crate::dma::{DmaBuffer, DmaHal, PAGE_SIZE};
+use crate::{BlockCapabilities, BlockDriverOps};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// The maximum number of polls before a controller operation times out.
+const SPIN_LIMIT: usize = 100_000_000;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+/// The upper limit of one transfer, bounded by the size of the PRP list page.
+const MAX_TRANSFER_PAGES: usize = 256;
+
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_INTMS: usize = 0x0c;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const REG_DOORBELLS: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+const CC_CSS_NVM: u32 = 0 << 4;
+const CC_MPS_4K: u32 = 0 << 7;
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+const IO_WRITE_ZEROES: u8 = 0x08;
+
+/// The maximum number of blocks of one Write Zeroes command.
+const WRITE_ZEROES_MAX_BLOCKS: u64 = 0x1_0000;
+/// Deallocate the blocks of a Write Zeroes command (DEAC).
+const WRITE_ZEROES_DEALLOCATE: u32 = 1 << 25;
+
+/// Write Zeroes is supported, in ONCS of the Identify Controller data.
+const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 2;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// NPWG and NOWS are valid, in NSFEAT of the Identify Namespace data.
+const NSFEAT_OPTIMAL_IO: u8 = 1 << 4;
+
+/// The controller state shared by all namespaces.
+struct Controller<H: DmaHal> {
+    regs: NonNull<u8>,
+    admin: QueuePair<H>,
+    io: QueuePair<H>,
+    prp_list: DmaBuffer<H>,
+    identify: DmaBuffer<H>,
+    volatile_write_cache: bool,
+    write_zeroes: bool,
+    max_transfer: usize,
+}
+
+unsafe impl<H: DmaHal> Send for Controller<H> {}
+
+/// Information about an active namespace.
+#[derive(Clone, Copy, Debug)]
+struct NamespaceInfo {
+    nsid: u32,
+    num_blocks: u64,
+    block_size: usize,
+    /// The preferred write granularity, in bytes.
+    write_granularity: usize,
+    /// The preferred write size, in bytes, 0 if unknown.
+    optimal_write_size: usize,
+}
+
+/// NVMe controller driver.
+///
+/// It brings up the controller and discovers its namespaces, the block I/O
+/// goes through the [`NvmeNamespace`]s returned by
+/// [`NvmeController::namespaces`].
+pub struct NvmeController<H: DmaHal> {
+    name: String,
+    ctrl: Arc<Mutex<Controller<H>>>,
+    namespaces: Vec<NamespaceInfo>,
+}
+
+/// A namespace of an NVMe controller, as a block device.
+pub struct NvmeNamespace<H: DmaHal> {
+    name: String,
+    ctrl: Arc<Mutex<Controller<H>>>,
+    info: NamespaceInfo,
+}
+
+unsafe impl<H: DmaHal> Send for NvmeNamespace<H> {}
+unsafe impl<H: DmaHal> Sync for NvmeNamespace<H> {}
+
+impl<H: DmaHal> Controller<H> {
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe { (self.regs.as_ptr().add(reg) as *const u32).read_volatile() }
+    }
+
+    fn write32(&mut self, reg: usize, val: u32) {
+        unsafe { (self.regs.as_ptr().add(reg) as *mut u32).write_volatile(val) }
+    }
+
+    fn write64(&mut self, reg: usize, val: u64) {
+        self.write32(reg, val as u32);
+        self.write32(reg + 4, (val >> 32) as u32);
+    }
+
+    fn wait_ready(&self, ready: bool) -> DevResult {
+        for _ in 0..SPIN_LIMIT {
+            let csts = self.read32(REG_CSTS);
+            if csts & CSTS_CFS != 0 {
+                return Err(DevError::Io);
+            }
+            if (csts & CSTS_RDY != 0) == ready {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(DevError::Io)
+    }
+
+    /// Resets and enables the controller, then creates the I/O queues.
+    fn init(regs: NonNull<u8>) -> DevResult<Self> {
+        let read64 = |reg: usize| unsafe {
+            let lo = (regs.as_ptr().add(reg) as *const u32).read_volatile() as u64;
+            let hi = (regs.as_ptr().add(reg + 4) as *const u32).read_volatile() as u64;
+            hi << 32 | lo
+        };
+        let cap = read64(REG_CAP);
+        let mqes = (cap & 0xffff) as u16;
+        let stride = 4 << ((cap >> 32) & 0xf);
+        let mps_min = (cap >> 48) & 0xf;
+        if mps_min != 0 {
+            // 4 KiB pages must be supported.
+            return Err(DevError::Unsupported);
+        }
+        let doorbells = unsafe { NonNull::new_unchecked(regs.as_ptr().add(REG_DOORBELLS)) };
+
+        let admin = QueuePair::new(0, ADMIN_QUEUE_SIZE.min(mqes + 1), doorbells, stride)?;
+        let io = QueuePair::new(1, IO_QUEUE_SIZE.min(mqes + 1), doorbells, stride)?;
+        let mut ctrl = Self {
+            regs,
+            admin,
+            io,
+            prp_list: DmaBuffer::new(PAGE_SIZE)?,
+            identify: DmaBuffer::new(PAGE_SIZE)?,
+            volatile_write_cache: false,
+            write_zeroes: false,
+            max_transfer: MAX_TRANSFER_PAGES * PAGE_SIZE,
+        };
+
+        let cc = ctrl.read32(REG_CC);
+        if cc & CC_EN != 0 {
+            ctrl.write32(REG_CC, cc & !CC_EN);
+        }
+        ctrl.wait_ready(false)?;
+
+        let aqa = (ctrl.admin.size() as u32 - 1) << 16 | (ctrl.admin.size() as u32 - 1);
+        ctrl.write32(REG_AQA, aqa);
+        ctrl.write64(REG_ASQ, ctrl.admin.sq_paddr() as u64);
+        ctrl.write64(REG_ACQ, ctrl.admin.cq_paddr() as u64);
+        ctrl.write32(REG_INTMS, !0);
+        ctrl.write32(
+            REG_CC,
+            CC_EN | CC_CSS_NVM | CC_MPS_4K | CC_IOSQES | CC_IOCQES,
+        );
+        ctrl.wait_ready(true)?;
+
+        let id = ctrl.identify(IDENTIFY_CONTROLLER, 0)?;
+        let (mdts, vwc) = (id[77], id[525]);
+        let oncs = u16::from_le_bytes([id[520], id[521]]);
+        ctrl.volatile_write_cache = vwc & 1 != 0;
+        ctrl.write_zeroes = oncs & ONCS_WRITE_ZEROES != 0;
+        if mdts != 0 {
+            let mdts = 1usize
+                .checked_shl(mdts as u32)
+                .and_then(|pages| pages.checked_mul(PAGE_SIZE))
+                .unwrap_or(usize::MAX);
+            ctrl.max_transfer = ctrl.max_transfer.min(mdts);
+        }
+
+        let mut cmd = Command::new(ADMIN_SET_FEATURES);
+        cmd.cdw10 = FEATURE_NUMBER_OF_QUEUES;
+        cmd.cdw11 = 0; // One submission and one completion queue.
+        ctrl.admin.submit_and_wait(cmd)?;
+
+        let (qid, qsize) = (ctrl.io.qid() as u32, ctrl.io.size() as u32 - 1);
+        let mut cmd = Command::new(ADMIN_CREATE_IO_CQ);
+        cmd.prp1 = ctrl.io.cq_paddr() as u64;
+        cmd.cdw10 = qsize << 16 | qid;
+        cmd.cdw11 = 1; // Physically contiguous, interrupts disabled.
+        ctrl.admin.submit_and_wait(cmd)?;
+
+        let mut cmd = Command::new(ADMIN_CREATE_IO_SQ);
+        cmd.prp1 = ctrl.io.sq_paddr() as u64;
+        cmd.cdw10 = qsize << 16 | qid;
+        cmd.cdw11 = qid << 16 | 1; // Bound to the CQ, physically contiguous.
+        ctrl.admin.submit_and_wait(cmd)?;
+        Ok(ctrl)
+    }
+
+    /// Disables the controller, which drops all commands in flight, and
+    /// brings it up again with new queues.
+    fn reset(&mut self) -> DevResult {
+        log::warn!("nvme: resetting the controller");
+        let cc = self.read32(REG_CC);
+        self.write32(REG_CC, cc & !CC_EN);
+        self.wait_ready(false)?;
+        *self = Self::init(self.regs)?;
+        Ok(())
+    }
+
+    /// Submits an I/O command and waits for its completion.
+    ///
+    /// A command that times out is aborted and reaped, or the controller is
+    /// reset, so that the device no longer accesses its buffers once this
+    /// returns.
+    fn io_command(&mut self, cmd: Command) -> DevResult {
+        let result = self.io.submit_and_wait(cmd).map(|_| ());
+        if let Some(cid) = self.io.pending() {
+            log::warn!("nvme: command {} timed out, aborting it", cid);
+            let mut abort = Command::new(ADMIN_ABORT);
+            abort.cdw10 = (cid as u32) << 16 | self.io.qid() as u32;
+            let aborted =
+                self.admin.submit_and_wait(abort).is_ok() && self.io.wait_pending().is_ok();
+            if !aborted {
+                self.reset()?;
+            }
+        }
+        result
+    }
+
+    /// Issues an Identify command and returns the 4 KiB data structure.
+    fn identify(&mut self, cns: u32, nsid: u32) -> DevResult<&[u8]> {
+        let mut cmd = Command::new(ADMIN_IDENTIFY);
+        cmd.nsid = nsid;
+        cmd.prp1 = self.identify.paddr() as u64;
+        cmd.cdw10 = cns;
+        self.admin.submit_and_wait(cmd)?;
+        Ok(&self.identify.as_slice()[..PAGE_SIZE])
+    }
+
+    fn identify_namespace(&mut self, nsid: u32) -> DevResult<Option<NamespaceInfo>> {
+        let id = self.identify(IDENTIFY_NAMESPACE, nsid)?;
+        let num_blocks = u64::from_le_bytes(id[0..8].try_into().unwrap());
+        if num_blocks == 0 {
+            return Ok(None);
+        }
+        let format = (id[26] & 0xf) as usize;
+        let lbaf = u32::from_le_bytes(id[128 + format * 4..132 + format * 4].try_into().unwrap());
+        let lba_shift = (lbaf >> 16) & 0xff;
+        let metadata_size = lbaf & 0xffff;
+        if metadata_size != 0 || !(9..=12).contains(&lba_shift) {
+            log::warn!("nvme: namespace {} has an unsupported format", nsid);
+            return Ok(None);
+        }
+        let block_size = 1 << lba_shift;
+        let (write_granularity, optimal_write_size) = if id[24] & NSFEAT_OPTIMAL_IO != 0 {
+            let npwg = u16::from_le_bytes([id[64], id[65]]) as usize;
+            let nows = u16::from_le_bytes([id[72], id[73]]) as usize;
+            ((npwg + 1) * block_size, (nows + 1) * block_size)
+        } else {
+            (block_size, 0)
+        };
+        Ok(Some(NamespaceInfo {
+            nsid,
+            num_blocks,
+            block_size,
+            write_granularity,
+            optimal_write_size,
+        }))
+    }
+
+    fn active_namespaces(&mut self) -> DevResult<Vec<NamespaceInfo>> {
+        let nn = {
+            let id = self.identify(IDENTIFY_CONTROLLER, 0)?;
+            u32::from_le_bytes(id[516..520].try_into().unwrap())
+        };
+        let nsids: Vec<u32> = match self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0) {
+            Ok(list) => list
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .take_while(|&nsid| nsid != 0)
+                .collect(),
+            // Before NVMe 1.1, every namespace up to NN may be active.
+            Err(_) => (1..=nn).collect(),
+        };
+        let mut namespaces = Vec::new();
+        for nsid in nsids {
+            if let Some(info) = self.identify_namespace(nsid)? {
+                namespaces.push(info);
+            }
+        }
+        Ok(namespaces)
+    }
+
+    /// Fills the PRP entries of `cmd` to describe the buffer at `vaddr`.
+    fn setup_prps(&mut self, cmd: &mut Command, vaddr: usize, len: usize) -> DevResult {
+        if vaddr % 4 != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        cmd.prp1 = H::virt_to_phys(vaddr) as u64;
+        let first_len = PAGE_SIZE - vaddr % PAGE_SIZE;
+        if len <= first_len {
+            return Ok(());
+        }
+        let next_page = vaddr - vaddr % PAGE_SIZE + PAGE_SIZE;
+        let pages = (len - first_len).div_ceil(PAGE_SIZE);
+        if pages == 1 {
+            cmd.prp2 = H::virt_to_phys(next_page) as u64;
+        } else {
+            let list = self.prp_list.as_ptr() as *mut u64;
+            for i in 0..pages {
+                let paddr = H::virt_to_phys(next_page + i * PAGE_SIZE) as u64;
+                unsafe { list.add(i).write_volatile(paddr) };
+            }
+            cmd.prp2 = self.prp_list.paddr() as u64;
+        }
+        Ok(())
+    }
+
+    fn read_write(
+        &mut self,
+        opcode: u8,
+        ns: &NamespaceInfo,
+        block_id: u64,
+        vaddr: usize,
+        len: usize,
+    ) -> DevResult {
+        if len == 0 || len % ns.block_size != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let count = (len / ns.block_size) as u64;
+        match block_id.checked_add(count) {
+            Some(end) if end <= ns.num_blocks => {}
+            _ => return Err(DevError::Io),
+        }
+        let mut lba = block_id;
+        let mut offset = 0;
+        while offset < len {
+            let chunk = (len - offset).min(self.max_transfer);
+            let blocks = (chunk / ns.block_size) as u32;
+            let mut cmd = Command::new(opcode);
+            cmd.nsid = ns.nsid;
+            cmd.cdw10 = lba as u32;
+            cmd.cdw11 = (lba >> 32) as u32;
+            cmd.cdw12 = blocks - 1;
+            self.setup_prps(&mut cmd, vaddr + offset, chunk)?;
+            self.io_command(cmd)?;
+            lba += blocks as u64;
+            offset += chunk;
+        }
+        Ok(())
+    }
+
+    /// Zeroes blocks with Write Zeroes commands, which transfer no data.
+    fn write_zeroes(
+        &mut self,
+        ns: &NamespaceInfo,
+        block_id: u64,
+        count: u64,
+        may_unmap: bool,
+    ) -> DevResult {
+        if count == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let end = match block_id.checked_add(count) {
+            Some(end) if end <= ns.num_blocks => end,
+            _ => return Err(DevError::Io),
+        };
+        let mut lba = block_id;
+        while lba < end {
+            let blocks = (end - lba).min(WRITE_ZEROES_MAX_BLOCKS);
+            let mut cmd = Command::new(IO_WRITE_ZEROES);
+            cmd.nsid = ns.nsid;
+            cmd.cdw10 = lba as u32;
+            cmd.cdw11 = (lba >> 32) as u32;
+            cmd.cdw12 = blocks as u32 - 1;
+            if may_unmap {
+                cmd.cdw12 |= WRITE_ZEROES_DEALLOCATE;
+            }
+            self.io_command(cmd)?;
+            lba += blocks;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, nsid: u32) -> DevResult {
+        if !self.volatile_write_cache {
+            return Ok(());
+        }
+        let mut cmd = Command::new(IO_FLUSH);
+        cmd.nsid = nsid;
+        self.io_command(cmd)
+    }
+}
+
+impl<H: DmaHal> NvmeController<H> {
+    /// Initializes the controller whose registers are mapped at `regs`.
+    ///
+    /// `name` is used as the prefix of the namespace device names.
+    ///
+    /// # Safety
+    ///
+    /// `regs` must point to the mapped controller registers, including the
+    /// doorbells (usually 16 KiB).
+    pub unsafe fn try_new(regs: NonNull<u8>, name: &str) -> DevResult<Self> {
+        let version = (regs.as_ptr().add(REG_VS) as *const u32).read_volatile();
+        let mut ctrl = match Controller::<H>::init(regs) {
+            Ok(ctrl) => ctrl,
+            Err(e) => {
+                log::warn!("{}: init failed: {:?}", name, e);
+                return Err(e);
+            }
+        };
+        let namespaces = ctrl.active_namespaces()?;
+        log::info!(
+            "{}: NVMe {}.{} controller, {} namespace(s)",
+            name,
+            version >> 16,
+            (version >> 8) & 0xff,
+            namespaces.len()
+        );
+        Ok(Self {
+            name: String::from(name),
+            ctrl: Arc::new(Mutex::new(ctrl)),
+            namespaces,
+        })
+    }
+
+    /// Initializes the controller at PCI function `pci`.
+    ///
+    /// The namespaces are named after the PCI address, e.g.
+    /// `nvme-0000:00:05.0n1`.
+    pub fn try_new_pci<C: crate::pci::PciConfigSpace>(
+        mut pci: crate::pci::PciDevice<C>,
+    ) -> DevResult<Self> {
+        let (paddr, size) = pci.memory_bar(0)?;
+        pci.enable();
+        let regs = H::mmio_phys_to_virt(paddr, size);
+        let name = alloc::format!("nvme-{}", pci.bdf());
+        unsafe { Self::try_new(regs, &name) }
+    }
+
+    /// Returns a block device for each active namespace.
+    pub fn namespaces(&self) -> Vec<NvmeNamespace<H>> {
+        self.namespaces
+            .iter()
+            .map(|&info| NvmeNamespace {
+                name: alloc::format!("{}n{}", self.name, info.nsid),
+                ctrl: self.ctrl.clone(),
+                info,
+            })
+            .collect()
+    }
+}
+
+impl<H: DmaHal> NvmeNamespace<H> {
+    /// The namespace ID.
+    pub const fn nsid(&self) -> u32 {
+        self.info.nsid
+    }
+}
+
+impl<H: DmaHal> BaseDriverOps for NvmeNamespace<H> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<H: DmaHal> BlockDriverOps for NvmeNamespace<H> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.info.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.info.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.ctrl.lock().read_write(
+            IO_READ,
+            &self.info,
+            block_id,
+            buf.as_mut_ptr() as usize,
+            buf.len(),
+        )
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.ctrl.lock().read_write(
+            IO_WRITE,
+            &self.info,
+            block_id,
+            buf.as_ptr() as usize,
+            buf.len(),
+        )
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.ctrl.lock().flush(self.info.nsid)
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        let ctrl = self.ctrl.lock();
+        BlockCapabilities {
+            physical_block_size: self.info.write_granularity,
+            min_io_size: self.info.write_granularity,
+            optimal_io_size: self.info.optimal_write_size,
+            max_transfer: ctrl.max_transfer,
+            write_cache: ctrl.volatile_write_cache,
+            // A full queue keeps one entry free.
+            queue_depth: ctrl.io.size() as usize - 1,
+            ..BlockCapabilities::new(self.info.num_blocks, self.info.block_size)
+        }
+    }
+
+    fn write_zeroes(&mut self, start: u64, count: u64, may_unmap: bool) -> DevResult {
+        if !self.ctrl.lock().write_zeroes {
+            return crate::write_zeroes_fallback(self, start, count);
+        }
+        self.ctrl
+            .lock()
+            .write_zeroes(&self.info, start, count, may_unmap)
+    }
+}
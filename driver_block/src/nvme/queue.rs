@@ -0,0 +1,213 @@
+//! NVMe submission/completion queue pairs.
+
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+
+use super::SPIN_LIMIT;
+use crate::dma::{DmaBuffer, DmaHal, PhysAddr};
+use driver_common::{DevError, DevResult};
+
+/// A submission queue entry.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub(super) struct Command {
+    pub cdw0: u32,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl Command {
+    pub fn new(opcode: u8) -> Self {
+        Self {
+            cdw0: opcode as u32,
+            ..Default::default()
+        }
+    }
+}
+
+/// A completion queue entry.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(super) struct Completion {
+    pub dw0: u32,
+    pub dw1: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    pub status: u16,
+}
+
+impl Completion {
+    /// The status code type and status code, 0 on success.
+    pub const fn status_code(&self) -> u16 {
+        (self.status >> 1) & 0x7ff
+    }
+}
+
+/// A submission queue and its dedicated completion queue.
+pub(super) struct QueuePair<H: DmaHal> {
+    qid: u16,
+    size: u16,
+    sq: DmaBuffer<H>,
+    cq: DmaBuffer<H>,
+    sq_doorbell: NonNull<u32>,
+    cq_doorbell: NonNull<u32>,
+    sq_tail: u16,
+    cq_head: u16,
+    phase: bool,
+    next_cid: u16,
+    /// The ID of the command in flight, which the device may still access
+    /// after its wait timed out.
+    pending: Option<u16>,
+}
+
+impl<H: DmaHal> QueuePair<H> {
+    /// Allocates queue pair `qid` with `size` entries. `doorbells` points to
+    /// the controller doorbell registers, `stride` is their distance in bytes.
+    pub fn new(qid: u16, size: u16, doorbells: NonNull<u8>, stride: usize) -> DevResult<Self> {
+        let sq = DmaBuffer::new(size as usize * size_of::<Command>())?;
+        let cq = DmaBuffer::new(size as usize * size_of::<Completion>())?;
+        let doorbell = |idx: usize| unsafe {
+            NonNull::new_unchecked(doorbells.as_ptr().add(idx * stride) as *mut u32)
+        };
+        Ok(Self {
+            qid,
+            size,
+            sq,
+            cq,
+            sq_doorbell: doorbell(2 * qid as usize),
+            cq_doorbell: doorbell(2 * qid as usize + 1),
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_cid: 0,
+            pending: None,
+        })
+    }
+
+    pub const fn qid(&self) -> u16 {
+        self.qid
+    }
+
+    pub const fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn sq_paddr(&self) -> PhysAddr {
+        self.sq.paddr()
+    }
+
+    pub fn cq_paddr(&self) -> PhysAddr {
+        self.cq.paddr()
+    }
+
+    /// Submits `cmd` and busy-waits for its completion.
+    ///
+    /// If the wait times out, the command stays in flight and no other one
+    /// can be submitted until [`wait_pending`](Self::wait_pending) reaps it.
+    pub fn submit_and_wait(&mut self, mut cmd: Command) -> DevResult<Completion> {
+        if self.pending.is_some() {
+            return Err(DevError::BadState);
+        }
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        cmd.cdw0 = (cmd.cdw0 & 0xffff) | (cid as u32) << 16;
+
+        unsafe {
+            let slot = (self.sq.as_ptr() as *mut Command).add(self.sq_tail as usize);
+            slot.write_volatile(cmd);
+        }
+        self.sq_tail = (self.sq_tail + 1) % self.size;
+        fence(Ordering::SeqCst);
+        unsafe {
+            self.sq_doorbell
+                .as_ptr()
+                .write_volatile(self.sq_tail as u32)
+        };
+        self.pending = Some(cid);
+
+        let completion = self.wait_pending()?;
+        match completion.status_code() {
+            0 => Ok(completion),
+            code => {
+                log::warn!("nvme: command {:#x} failed: {:#x}", cmd.cdw0 & 0xff, code);
+                Err(status_to_error(code))
+            }
+        }
+    }
+
+    /// The ID of the command whose wait timed out, if it has not completed
+    /// since.
+    pub const fn pending(&self) -> Option<u16> {
+        self.pending
+    }
+
+    /// Busy-waits for the completion of the command in flight, dropping the
+    /// late completions of other commands.
+    pub fn wait_pending(&mut self) -> DevResult<Completion> {
+        let cid = self.pending.ok_or(DevError::BadState)?;
+        loop {
+            let completion = self.wait_completion()?;
+            if completion.cid == cid {
+                self.pending = None;
+                return Ok(completion);
+            }
+            log::warn!(
+                "nvme: dropping completion {} while waiting for {}",
+                completion.cid,
+                cid
+            );
+        }
+    }
+
+    fn wait_completion(&mut self) -> DevResult<Completion> {
+        let entry = unsafe { (self.cq.as_ptr() as *const Completion).add(self.cq_head as usize) };
+        let mut spins = 0;
+        let completion = loop {
+            fence(Ordering::SeqCst);
+            let completion = unsafe { entry.read_volatile() };
+            if (completion.status & 1 == 1) == self.phase {
+                break completion;
+            }
+            spins += 1;
+            if spins > SPIN_LIMIT {
+                return Err(DevError::Io);
+            }
+            core::hint::spin_loop();
+        };
+        self.cq_head += 1;
+        if self.cq_head == self.size {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        unsafe {
+            self.cq_doorbell
+                .as_ptr()
+                .write_volatile(self.cq_head as u32)
+        };
+        Ok(completion)
+    }
+}
+
+fn status_to_error(code: u16) -> DevError {
+    match code {
+        // Generic: invalid opcode / invalid field / invalid namespace.
+        0x01 | 0x02 | 0x0b => DevError::InvalidParam,
+        // Generic: LBA out of range.
+        0x80 => DevError::InvalidParam,
+        // Generic: namespace not ready.
+        0x82 => DevError::Again,
+        _ => DevError::Io,
+    }
+}
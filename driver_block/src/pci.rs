@@ -0,0 +1,256 @@
+//! Minimal PCI configuration space support for PCI block controllers.
+//!
+//! Bus enumeration and BAR allocation are left to the kernel, drivers only
+//! need to inspect and enable an already enumerated function.
+
+use core::fmt;
+use core::ptr::NonNull;
+
+use crate::dma::PhysAddr;
+use driver_common::{DevError, DevResult};
+
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_CLASS: u16 = 0x08;
+const REG_BAR0: u16 = 0x10;
+const REG_CAP_PTR: u16 = 0x34;
+
+const STATUS_CAP_LIST: u32 = 1 << (16 + 4);
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+/// The address of a PCI function (bus/device/function).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciBdf {
+    /// Bus number.
+    pub bus: u8,
+    /// Device number (0..32).
+    pub device: u8,
+    /// Function number (0..8).
+    pub function: u8,
+}
+
+impl fmt::Display for PciBdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "0000:{:02x}:{:02x}.{}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Access to the configuration space of one PCI function.
+///
+/// It is implemented by the platform, e.g. through ECAM or the `0xcf8`/`0xcfc`
+/// I/O ports on x86.
+pub trait PciConfigSpace {
+    /// Reads the 32-bit register at `offset` (must be 4-byte aligned).
+    fn read_u32(&self, offset: u16) -> u32;
+    /// Writes the 32-bit register at `offset` (must be 4-byte aligned).
+    fn write_u32(&mut self, offset: u16, val: u32);
+}
+
+/// Configuration space access through the PCIe enhanced configuration access
+/// mechanism (ECAM), as used by QEMU `virt` machines.
+pub struct EcamConfigSpace {
+    base: NonNull<u8>,
+}
+
+unsafe impl Send for EcamConfigSpace {}
+unsafe impl Sync for EcamConfigSpace {}
+
+impl EcamConfigSpace {
+    /// Creates the accessor for function `bdf` in the ECAM region mapped at
+    /// `ecam_base`.
+    ///
+    /// # Safety
+    ///
+    /// The ECAM region covering `bdf` must be mapped at `ecam_base`.
+    pub unsafe fn new(ecam_base: NonNull<u8>, bdf: PciBdf) -> Self {
+        let offset = ((bdf.bus as usize) << 20)
+            | ((bdf.device as usize) << 15)
+            | ((bdf.function as usize) << 12);
+        Self {
+            base: NonNull::new_unchecked(ecam_base.as_ptr().add(offset)),
+        }
+    }
+}
+
+impl PciConfigSpace for EcamConfigSpace {
+    fn read_u32(&self, offset: u16) -> u32 {
+        unsafe { (self.base.as_ptr().add(offset as usize) as *const u32).read_volatile() }
+    }
+
+    fn write_u32(&mut self, offset: u16, val: u32) {
+        unsafe { (self.base.as_ptr().add(offset as usize) as *mut u32).write_volatile(val) }
+    }
+}
+
+/// A memory or I/O region decoded by a base address register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciBar {
+    /// A memory-mapped region.
+    Memory {
+        /// Physical base address.
+        paddr: PhysAddr,
+        /// Size in bytes.
+        size: usize,
+        /// Whether the region is prefetchable.
+        prefetchable: bool,
+    },
+    /// An I/O port region.
+    Io {
+        /// The first port.
+        port: u32,
+        /// Size in ports.
+        size: u32,
+    },
+}
+
+/// A PCI function, identified by its address and configuration space.
+pub struct PciDevice<C: PciConfigSpace> {
+    bdf: PciBdf,
+    config: C,
+}
+
+impl<C: PciConfigSpace> PciDevice<C> {
+    /// Wraps the function at `bdf` whose configuration space is `config`.
+    pub const fn new(bdf: PciBdf, config: C) -> Self {
+        Self { bdf, config }
+    }
+
+    /// The address of the function.
+    pub const fn bdf(&self) -> PciBdf {
+        self.bdf
+    }
+
+    /// The configuration space of the function.
+    pub fn config(&mut self) -> &mut C {
+        &mut self.config
+    }
+
+    /// The vendor ID, 0xffff if there is no function.
+    pub fn vendor_id(&self) -> u16 {
+        self.config.read_u32(REG_VENDOR_ID) as u16
+    }
+
+    /// The device ID.
+    pub fn device_id(&self) -> u16 {
+        (self.config.read_u32(REG_VENDOR_ID) >> 16) as u16
+    }
+
+    /// The (class, subclass, programming interface) triple.
+    pub fn class(&self) -> (u8, u8, u8) {
+        let val = self.config.read_u32(REG_CLASS);
+        ((val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8)
+    }
+
+    /// Reads a 32-bit value of the configuration space.
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        self.config.read_u32(offset)
+    }
+
+    /// Reads a byte of the configuration space.
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.config.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Reads a 16-bit value of the configuration space.
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.config.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Returns an iterator over the capabilities as `(offset, id)` pairs.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        let mut next = if self.config.read_u32(REG_COMMAND) & STATUS_CAP_LIST != 0 {
+            self.read_u8(REG_CAP_PTR) & !3
+        } else {
+            0
+        };
+        // Bounded, in case of a looping list.
+        let mut budget = 48;
+        core::iter::from_fn(move || {
+            if next == 0 || budget == 0 {
+                return None;
+            }
+            budget -= 1;
+            let offset = next as u16;
+            let header = self.read_u16(offset);
+            next = (header >> 8) as u8 & !3;
+            Some((offset, header as u8))
+        })
+    }
+
+    /// Decodes base address register `index` (0..6).
+    ///
+    /// Returns `None` if the BAR is not implemented, or is the upper half of a
+    /// 64-bit BAR. Memory decoding is temporarily disabled while sizing.
+    pub fn bar(&mut self, index: u8) -> Option<PciBar> {
+        if index >= 6 {
+            return None;
+        }
+        let reg = REG_BAR0 + index as u16 * 4;
+        let command = self.config.read_u32(REG_COMMAND) & 0xffff;
+        self.config.write_u32(
+            REG_COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let orig = self.config.read_u32(reg);
+        self.config.write_u32(reg, !0);
+        let mask = self.config.read_u32(reg);
+        self.config.write_u32(reg, orig);
+
+        let bar = if orig & 1 == 1 {
+            let size = (!(mask & !3)).wrapping_add(1) & 0xffff;
+            (mask != 0).then_some(PciBar::Io {
+                port: orig & !3,
+                size,
+            })
+        } else {
+            let is_64bit = (orig >> 1) & 3 == 2;
+            let mut paddr = (orig & !0xf) as u64;
+            let mut size_mask = (mask & !0xf) as u64 | 0xffff_ffff_0000_0000;
+            if is_64bit && index < 5 {
+                let orig_high = self.config.read_u32(reg + 4);
+                self.config.write_u32(reg + 4, !0);
+                let mask_high = self.config.read_u32(reg + 4);
+                self.config.write_u32(reg + 4, orig_high);
+                paddr |= (orig_high as u64) << 32;
+                size_mask = (size_mask & 0xffff_ffff) | (mask_high as u64) << 32;
+            }
+            let size = (!size_mask).wrapping_add(1);
+            (mask & !0xf != 0).then_some(PciBar::Memory {
+                paddr: paddr as PhysAddr,
+                size: size as usize,
+                prefetchable: orig & 8 != 0,
+            })
+        };
+        self.config.write_u32(REG_COMMAND, command);
+        bar
+    }
+
+    /// Returns the physical address and size of memory BAR `index`.
+    ///
+    /// Fails if it is not a memory BAR, or it has not been assigned an
+    /// address.
+    pub fn memory_bar(&mut self, index: u8) -> DevResult<(PhysAddr, usize)> {
+        match self.bar(index) {
+            Some(PciBar::Memory { paddr, size, .. }) if paddr != 0 => Ok((paddr, size)),
+            Some(PciBar::Memory { .. }) => Err(DevError::BadState),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    /// Enables memory space decoding and bus mastering (DMA).
+    pub fn enable(&mut self) {
+        let command = self.config.read_u32(REG_COMMAND) & 0xffff;
+        self.config.write_u32(
+            REG_COMMAND,
+            command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+}
@@ -2,7 +2,7 @@
 
 extern crate alloc;
 
-use crate::BlockDriverOps;
+use crate::{BlockCapabilities, BlockDriverOps, DiscardLimits};
 use alloc::{vec, vec::Vec};
 use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
 
@@ -43,6 +43,21 @@ impl RamDisk {
     pub const fn size(&self) -> usize {
         self.size
     }
+
+    /// Fills `count` blocks starting at `start` with zeros.
+    fn zero(&mut self, start: u64, count: u64) -> DevResult {
+        if count == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        match start.checked_add(count) {
+            Some(end) if end <= self.num_blocks() => {
+                let range = start as usize * BLOCK_SIZE..end as usize * BLOCK_SIZE;
+                self.data[range].fill(0);
+                Ok(())
+            }
+            _ => Err(DevError::Io),
+        }
+    }
 }
 
 impl const BaseDriverOps for RamDisk {
@@ -93,6 +108,31 @@ impl BlockDriverOps for RamDisk {
     fn flush(&mut self) -> DevResult {
         Ok(())
     }
+
+    /// Discarded blocks read back as zeros.
+    fn discard(&mut self, start: u64, count: u64) -> DevResult {
+        self.zero(start, count)
+    }
+
+    fn discard_limits(&self) -> Option<DiscardLimits> {
+        Some(DiscardLimits {
+            granularity: 1,
+            max_blocks: self.num_blocks(),
+        })
+    }
+
+    fn write_zeroes(&mut self, start: u64, count: u64, _may_unmap: bool) -> DevResult {
+        self.zero(start, count)
+    }
+
+    /// Writes are never cached, and memory is not removable.
+    fn capabilities(&self) -> BlockCapabilities {
+        BlockCapabilities {
+            write_cache: false,
+            discard: self.discard_limits(),
+            ..BlockCapabilities::new(self.num_blocks(), BLOCK_SIZE)
+        }
+    }
 }
 
 const fn align_up(val: usize) -> usize {
@@ -0,0 +1,71 @@
+//! Recovery from transient transfer errors, shared by the SD drivers.
+//!
+//! A CRC error or a timeout on the bus is usually transient: the request can
+//! be retried once the controller and the card are brought back to a sane
+//! state. Drivers retry failed transfers as a [`RecoveryPolicy`] says and
+//! count what they did in [`RecoveryStats`], so that failing media or a
+//! marginal bus can be diagnosed.
+//!
+//! Before each retry, the SD/MMC card driver resets the command and data
+//! lines, then either slows the bus down after repeated errors or tunes the
+//! sampling clock again in the UHS-I modes. The card is never initialized
+//! again, since it may have been replaced in the meantime.
+
+/// How a driver recovers from failed transfers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// The number of times a failed transfer is retried before the error is
+    /// returned, 0 to return it directly.
+    pub max_retries: u32,
+    /// The number of spins before the first retry, doubled before each
+    /// following one.
+    pub backoff_spins: u32,
+    /// The number of failed attempts in a row after which the bus is slowed
+    /// down by one speed mode, 0 to keep its speed.
+    pub downshift_after: u32,
+}
+
+impl RecoveryPolicy {
+    /// Errors are returned as they happen.
+    pub const NONE: Self = Self {
+        max_retries: 0,
+        backoff_spins: 0,
+        downshift_after: 0,
+    };
+
+    /// Waits before retry number `attempt`, counting from 0.
+    pub fn backoff(&self, attempt: u32) {
+        let spins = self.backoff_spins.saturating_mul(1 << attempt.min(16));
+        for _ in 0..spins {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Three retries, and a slower bus after three failed attempts in a row.
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff_spins: 1000,
+            downshift_after: 3,
+        }
+    }
+}
+
+/// Counters of the errors seen and the recovery actions taken by a driver.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Failed transfer attempts.
+    pub errors: u64,
+    /// Transfers retried.
+    pub retries: u64,
+    /// Software resets of the command and data lines.
+    pub line_resets: u64,
+    /// Tunings of the sampling clock.
+    pub retunes: u64,
+    /// Switches to a slower bus speed mode.
+    pub downshifts: u64,
+    /// Transfers that failed for good, after the retries.
+    pub failures: u64,
+}
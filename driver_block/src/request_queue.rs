@@ -0,0 +1,203 @@
+//! Asynchronous submission and completion of block requests.
+//!
+//! [`BlockDriverOps`] runs one request at a time. Devices with hardware
+//! queues can keep several requests in flight through [`BlockQueueOps`]:
+//! requests are submitted with a tag, and their completions are polled
+//! later, in any order. Any synchronous driver can be used through this
+//! interface with [`SyncQueue`].
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::BlockDriverOps;
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// The operation of a block request.
+///
+/// Buffers are owned by the request while it is in flight, and handed back
+/// with its completion.
+#[derive(Debug)]
+pub enum BlockOp {
+    /// Reads blocks starting at `block_id` into `buf`, a multiple of the
+    /// block size.
+    Read { block_id: u64, buf: Vec<u8> },
+    /// Writes `buf`, a multiple of the block size, to blocks starting at
+    /// `block_id`.
+    Write { block_id: u64, buf: Vec<u8> },
+    /// Flushes the requests completed before it to the storage.
+    Flush,
+    /// Discards `count` blocks starting at `start`.
+    Discard { start: u64, count: u64 },
+}
+
+/// A block request, identified by a tag chosen by the submitter.
+#[derive(Debug)]
+pub struct BlockRequest {
+    /// The tag returned with the completion.
+    pub tag: u64,
+    /// The operation to perform.
+    pub op: BlockOp,
+}
+
+/// The completion of a block request.
+#[derive(Debug)]
+pub struct BlockCompletion {
+    /// The tag of the request.
+    pub tag: u64,
+    /// The operation of the request, with the data read for a
+    /// [`BlockOp::Read`].
+    pub op: BlockOp,
+    /// The result of the request.
+    pub result: DevResult,
+}
+
+/// Operations of a block device that keeps several requests in flight.
+pub trait BlockQueueOps: BaseDriverOps {
+    /// The number of blocks in this storage device.
+    fn num_blocks(&self) -> u64;
+    /// The size of each block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// The maximum number of requests in flight, submitted but whose
+    /// completion is not polled yet.
+    fn queue_depth(&self) -> usize;
+
+    /// The number of requests in flight.
+    fn in_flight(&self) -> usize;
+
+    /// Submits a request.
+    ///
+    /// The request is handed back if [`queue_depth`](Self::queue_depth)
+    /// requests are already in flight. Any other error is reported by its
+    /// completion.
+    fn submit(&mut self, req: BlockRequest) -> Result<(), BlockRequest>;
+
+    /// Returns the next completed request, `None` if no request has
+    /// completed yet.
+    ///
+    /// Requests may complete in any order, except that a
+    /// [`BlockOp::Flush`] completes after the requests submitted before it.
+    fn poll_completion(&mut self) -> Option<BlockCompletion>;
+
+    /// Sets the function called when a request completes, which may be
+    /// from the interrupt handler of the device.
+    ///
+    /// Devices that cannot notify completions fail with
+    /// [`DevError::Unsupported`], which is the default, and must be polled.
+    fn set_notifier(&mut self, _notify: fn()) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+}
+
+/// Runs a synchronous [`BlockDriverOps`] driver behind [`BlockQueueOps`].
+///
+/// Requests are performed as they are submitted, and their completions are
+/// queued until polled. The notifier, if any, is called once per request
+/// before [`submit`](BlockQueueOps::submit) returns.
+///
+/// Nothing is ever in flight on the device: the requests counted by
+/// [`in_flight`](BlockQueueOps::in_flight) are done, and the queue depth
+/// only bounds the completions not polled yet.
+pub struct SyncQueue<D: BlockDriverOps> {
+    dev: D,
+    depth: usize,
+    completions: VecDeque<BlockCompletion>,
+    notify: Option<fn()>,
+}
+
+impl<D: BlockDriverOps> SyncQueue<D> {
+    /// Wraps `dev`, with as many requests in flight as the device can keep,
+    /// its [`queue_depth`](crate::BlockCapabilities::queue_depth).
+    pub fn new(dev: D) -> Self {
+        let depth = dev.capabilities().queue_depth;
+        Self::with_depth(dev, depth)
+    }
+
+    /// Wraps `dev`, with at most `depth` requests in flight (at least 1).
+    pub fn with_depth(dev: D, depth: usize) -> Self {
+        let depth = depth.max(1);
+        Self {
+            dev,
+            depth,
+            completions: VecDeque::with_capacity(depth),
+            notify: None,
+        }
+    }
+
+    /// The wrapped driver.
+    pub fn inner(&self) -> &D {
+        &self.dev
+    }
+
+    /// The mutable wrapped driver.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    /// Returns the wrapped driver. The completions not polled yet are lost.
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for SyncQueue<D> {
+    fn device_type(&self) -> DeviceType {
+        self.dev.device_type()
+    }
+
+    fn device_name(&self) -> &str {
+        self.dev.device_name()
+    }
+}
+
+impl<D: BlockDriverOps> BlockQueueOps for SyncQueue<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.dev.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.depth
+    }
+
+    fn in_flight(&self) -> usize {
+        self.completions.len()
+    }
+
+    fn submit(&mut self, mut req: BlockRequest) -> Result<(), BlockRequest> {
+        if self.completions.len() >= self.depth {
+            return Err(req);
+        }
+        let result = match &mut req.op {
+            BlockOp::Read { block_id, buf } => self.dev.read_block(*block_id, buf),
+            BlockOp::Write { block_id, buf } => self.dev.write_block(*block_id, buf),
+            BlockOp::Flush => self.dev.flush(),
+            BlockOp::Discard { start, count } => self.dev.discard(*start, *count),
+        };
+        self.completions.push_back(BlockCompletion {
+            tag: req.tag,
+            op: req.op,
+            result,
+        });
+        if let Some(notify) = self.notify {
+            notify();
+        }
+        Ok(())
+    }
+
+    fn poll_completion(&mut self) -> Option<BlockCompletion> {
+        self.completions.pop_front()
+    }
+
+    fn set_notifier(&mut self, notify: fn()) -> DevResult {
+        self.notify = Some(notify);
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+//! An SD card emulated on the bus side of [`SpiBus`], backed by memory.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{
+    cmd, crc16, crc7, SpiBus, BLOCK_SIZE, CRC_ON_OFF, DATA_RESP_ACCEPTED, OCR_CCS, R1_CRC_ERROR,
+    R1_IDLE, R1_ILLEGAL_COMMAND, READ_OCR, SEND_CID, TOKEN_START_BLOCK, TOKEN_START_MULTI_WRITE,
+    TOKEN_STOP_TRAN,
+};
+
+const R1_ERASE_SEQ_ERROR: u8 = 1 << 4;
+const R1_PARAM_ERROR: u8 = 1 << 6;
+const DATA_RESP_CRC_ERROR: u8 = 0x0b;
+const DATA_RESP_WRITE_ERROR: u8 = 0x0d;
+
+/// Busy bytes the emulated card holds MISO low for after programming.
+const BUSY_BYTES: usize = 2;
+
+/// An emulated SD card speaking the SPI protocol.
+///
+/// It implements [`SpiBus`] itself, so it can be handed directly to
+/// [`SdSpiCard::try_new`](super::SdSpiCard::try_new). Command and data CRCs
+/// sent by the host are checked, as a real card would once CRC checking is
+/// enabled.
+///
+/// The card can be removed from and inserted into its emulated slot, to
+/// exercise media change detection.
+pub struct MockSdCard {
+    storage: Vec<u8>,
+    high_capacity: bool,
+    present: bool,
+    /// Whether the card was removed since the last card detect poll.
+    removed: bool,
+    selected: bool,
+    out: VecDeque<u8>,
+    cmd: [u8; 6],
+    cmd_len: usize,
+    idle: bool,
+    app_cmd: bool,
+    crc_enabled: bool,
+    op_cond_polls: usize,
+    /// The next block of an open-ended multiple block read.
+    read_next: Option<u64>,
+    /// The next block to write, and whether this is a multiple block write.
+    write_next: Option<(u64, bool)>,
+    /// The data block being received, with its CRC.
+    rx: Option<Vec<u8>>,
+    /// The first and last blocks of the next erase.
+    erase_start: Option<u64>,
+    erase_end: Option<u64>,
+}
+
+impl MockSdCard {
+    /// Creates a card of `num_blocks` 512-byte blocks, filled with zeros.
+    /// A high capacity (SDHC) card is block addressed, its size is rounded
+    /// down to a multiple of 1024 blocks. A standard capacity (SDSC) card is
+    /// byte addressed, its size is rounded down to a multiple of 512 blocks.
+    pub fn new(num_blocks: usize, high_capacity: bool) -> Self {
+        let unit = if high_capacity { 1024 } else { 512 };
+        let num_blocks = num_blocks / unit * unit;
+        Self {
+            storage: vec![0; num_blocks * BLOCK_SIZE],
+            high_capacity,
+            present: true,
+            removed: false,
+            selected: false,
+            out: VecDeque::new(),
+            cmd: [0; 6],
+            cmd_len: 0,
+            idle: true,
+            app_cmd: false,
+            crc_enabled: false,
+            op_cond_polls: 0,
+            read_next: None,
+            write_next: None,
+            rx: None,
+            erase_start: None,
+            erase_end: None,
+        }
+    }
+
+    /// The content of the card.
+    pub fn storage(&self) -> &[u8] {
+        &self.storage
+    }
+
+    /// The mutable content of the card.
+    pub fn storage_mut(&mut self) -> &mut [u8] {
+        &mut self.storage
+    }
+
+    /// Removes the card from the slot. It no longer drives MISO.
+    pub fn remove(&mut self) {
+        self.present = false;
+        self.removed = true;
+    }
+
+    /// Inserts the card (again) into the slot. It is powered up in the idle
+    /// state, with its content preserved.
+    pub fn insert(&mut self) {
+        self.power_up();
+    }
+
+    /// Resets the card to the idle state, keeping its content.
+    fn power_up(&mut self) {
+        let storage = core::mem::take(&mut self.storage);
+        let removed = self.removed;
+        *self = Self::new(0, self.high_capacity);
+        self.storage = storage;
+        self.removed = removed;
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.storage.len() / BLOCK_SIZE) as u64
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        let mut csd: u128 = 0x32 << 96; // TRAN_SPEED: 25 MHz
+        csd |= 0x5b5 << 84; // CCC: classes 0, 2, 4, 5, 7, 8 and 10
+        csd |= 9 << 80; // READ_BL_LEN: 512
+        csd |= 9 << 22; // WRITE_BL_LEN: 512
+        if self.high_capacity {
+            csd |= 1 << 126;
+            csd |= ((self.num_blocks() / 1024 - 1) as u128) << 48;
+            csd |= 1 << 46; // ERASE_BLK_EN
+            csd |= 0x7f << 39; // SECTOR_SIZE: 64 KiB
+        } else {
+            csd |= ((self.num_blocks() / 512 - 1) as u128) << 62;
+            csd |= 7 << 47; // C_SIZE_MULT: 512
+            csd |= 7 << 39; // SECTOR_SIZE: 4 KiB
+        }
+        let mut bytes = csd.to_be_bytes();
+        bytes[15] = (crc7(&bytes[..15]) << 1) | 1;
+        bytes
+    }
+
+    fn cid(&self) -> [u8; 16] {
+        let mut cid: u128 = 0x02 << 120; // MID
+        cid |= (u16::from_be_bytes(*b"MK") as u128) << 104;
+        for (i, &c) in b"MOCK0".iter().enumerate() {
+            cid |= (c as u128) << (96 - 8 * i);
+        }
+        cid |= 0x10 << 56; // PRV: 1.0
+        cid |= 0x1234_5678 << 24; // PSN
+        cid |= ((24 << 4) | 1) << 8; // MDT: 2024-01
+        let mut bytes = cid.to_be_bytes();
+        bytes[15] = (crc7(&bytes[..15]) << 1) | 1;
+        bytes
+    }
+
+    fn scr(&self) -> [u8; 8] {
+        // SD 3.0x, 1-bit and 4-bit bus, CPRM of SDSC or SDHC.
+        let security: u64 = if self.high_capacity { 3 } else { 2 };
+        let scr = (2 << 56) | (security << 52) | (0b0101 << 48) | (1 << 47);
+        u64::to_be_bytes(scr)
+    }
+
+    fn r1(&self) -> u8 {
+        if self.idle {
+            R1_IDLE
+        } else {
+            0
+        }
+    }
+
+    fn respond(&mut self, resp: &[u8]) {
+        self.out.clear();
+        self.out.push_back(0xff);
+        self.out.extend(resp);
+    }
+
+    fn queue_data(&mut self, data: &[u8]) {
+        self.out.push_back(0xff);
+        self.out.push_back(TOKEN_START_BLOCK);
+        self.out.extend(data);
+        self.out.extend(crc16(data).to_be_bytes());
+    }
+
+    fn queue_block(&mut self, block: u64) {
+        let start = block as usize * BLOCK_SIZE;
+        let data = self.storage[start..start + BLOCK_SIZE].to_vec();
+        self.queue_data(&data);
+    }
+
+    /// Converts a command argument to a block number.
+    fn to_block(&self, arg: u32) -> Option<u64> {
+        let block = if self.high_capacity {
+            arg as u64
+        } else if arg as usize % BLOCK_SIZE == 0 {
+            (arg as usize / BLOCK_SIZE) as u64
+        } else {
+            return None;
+        };
+        (block < self.num_blocks()).then_some(block)
+    }
+
+    fn handle_command(&mut self) {
+        let index = self.cmd[0] & 0x3f;
+        let arg = u32::from_be_bytes(self.cmd[1..5].try_into().unwrap());
+        let crc_ok = (crc7(&self.cmd[..5]) << 1) | 1 == self.cmd[5];
+        if !crc_ok && (self.crc_enabled || index == cmd::GO_IDLE_STATE) {
+            self.respond(&[self.r1() | R1_CRC_ERROR]);
+            return;
+        }
+        let app_cmd = core::mem::take(&mut self.app_cmd);
+        if index == cmd::STOP_TRANSMISSION {
+            self.read_next = None;
+            // `respond` queues the stuff byte before R1.
+            self.respond(&[self.r1()]);
+            return;
+        }
+        let r1 = self.r1();
+        match index {
+            cmd::GO_IDLE_STATE => {
+                self.power_up();
+                self.selected = true;
+                self.respond(&[R1_IDLE]);
+            }
+            cmd::SEND_IF_COND => {
+                let echo = arg & 0xfff;
+                let mut resp = [r1, 0, 0, 0, 0];
+                resp[1..].copy_from_slice(&echo.to_be_bytes());
+                self.respond(&resp);
+            }
+            cmd::APP_CMD => {
+                self.app_cmd = true;
+                self.respond(&[r1]);
+            }
+            cmd::APP_SD_SEND_OP_COND if app_cmd => {
+                // Stay busy for one poll, as real cards do.
+                self.op_cond_polls += 1;
+                if self.op_cond_polls > 1 {
+                    self.idle = false;
+                }
+                self.respond(&[self.r1()]);
+            }
+            READ_OCR => {
+                let mut ocr = 0x00ff_8000;
+                if !self.idle {
+                    ocr |= 1 << 31;
+                    if self.high_capacity {
+                        ocr |= OCR_CCS;
+                    }
+                }
+                let mut resp = [r1, 0, 0, 0, 0];
+                resp[1..].copy_from_slice(&u32::to_be_bytes(ocr));
+                self.respond(&resp);
+            }
+            CRC_ON_OFF => {
+                self.crc_enabled = arg & 1 != 0;
+                self.respond(&[r1]);
+            }
+            cmd::SET_BLOCKLEN if arg as usize == BLOCK_SIZE => self.respond(&[r1]),
+            cmd::SEND_CSD => {
+                self.respond(&[r1]);
+                let csd = self.csd();
+                self.queue_data(&csd);
+            }
+            SEND_CID => {
+                self.respond(&[r1]);
+                let cid = self.cid();
+                self.queue_data(&cid);
+            }
+            cmd::APP_SEND_SCR if app_cmd && !self.idle => {
+                self.respond(&[r1]);
+                let scr = self.scr();
+                self.queue_data(&scr);
+            }
+            cmd::SEND_STATUS => self.respond(&[r1, 0]),
+            cmd::READ_SINGLE_BLOCK | cmd::READ_MULTIPLE_BLOCK if !self.idle => {
+                match self.to_block(arg) {
+                    Some(block) => {
+                        self.respond(&[r1]);
+                        self.queue_block(block);
+                        if index == cmd::READ_MULTIPLE_BLOCK {
+                            self.read_next = Some(block + 1);
+                        }
+                    }
+                    None => self.respond(&[r1 | R1_PARAM_ERROR]),
+                }
+            }
+            cmd::WRITE_BLOCK | cmd::WRITE_MULTIPLE_BLOCK if !self.idle => {
+                match self.to_block(arg) {
+                    Some(block) => {
+                        self.respond(&[r1]);
+                        self.write_next = Some((block, index == cmd::WRITE_MULTIPLE_BLOCK));
+                    }
+                    None => self.respond(&[r1 | R1_PARAM_ERROR]),
+                }
+            }
+            cmd::ERASE_WR_BLK_START | cmd::ERASE_WR_BLK_END if !self.idle => {
+                match self.to_block(arg) {
+                    Some(block) => {
+                        if index == cmd::ERASE_WR_BLK_START {
+                            self.erase_start = Some(block);
+                        } else {
+                            self.erase_end = Some(block);
+                        }
+                        self.respond(&[r1]);
+                    }
+                    None => self.respond(&[r1 | R1_PARAM_ERROR]),
+                }
+            }
+            cmd::ERASE if !self.idle => match (self.erase_start.take(), self.erase_end.take()) {
+                (Some(start), Some(end)) if start <= end => {
+                    let range = start as usize * BLOCK_SIZE..(end as usize + 1) * BLOCK_SIZE;
+                    self.storage[range].fill(0);
+                    self.respond(&[r1]);
+                    self.out.extend([0; BUSY_BYTES]);
+                }
+                _ => self.respond(&[r1 | R1_ERASE_SEQ_ERROR]),
+            },
+            cmd::SET_BLOCKLEN => self.respond(&[r1 | R1_PARAM_ERROR]),
+            _ => self.respond(&[r1 | R1_ILLEGAL_COMMAND]),
+        }
+    }
+
+    fn finish_write_block(&mut self, data: Vec<u8>) {
+        let Some((block, multi)) = self.write_next else {
+            return;
+        };
+        let (block_data, crc) = data.split_at(BLOCK_SIZE);
+        let resp = if crc16(block_data) != u16::from_be_bytes([crc[0], crc[1]]) {
+            self.write_next = None;
+            DATA_RESP_CRC_ERROR
+        } else if block >= self.num_blocks() {
+            self.write_next = None;
+            DATA_RESP_WRITE_ERROR
+        } else {
+            let start = block as usize * BLOCK_SIZE;
+            self.storage[start..start + BLOCK_SIZE].copy_from_slice(block_data);
+            self.write_next = multi.then_some((block + 1, true));
+            DATA_RESP_ACCEPTED
+        };
+        self.out.clear();
+        self.out.push_back(resp | 0xe0);
+        self.out.extend([0; BUSY_BYTES]);
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if let Some(rx) = &mut self.rx {
+            rx.push(byte);
+            if rx.len() == BLOCK_SIZE + 2 {
+                let data = self.rx.take().unwrap();
+                self.finish_write_block(data);
+            }
+            return;
+        }
+        if let Some((_, multi)) = self.write_next {
+            match byte {
+                TOKEN_START_BLOCK if !multi => self.rx = Some(Vec::new()),
+                TOKEN_START_MULTI_WRITE if multi => self.rx = Some(Vec::new()),
+                TOKEN_STOP_TRAN if multi => {
+                    self.write_next = None;
+                    // One byte before busy starts (N_BR).
+                    self.out.clear();
+                    self.out.push_back(0xff);
+                    self.out.extend([0; BUSY_BYTES]);
+                }
+                _ => {}
+            }
+            return;
+        }
+        if self.cmd_len == 0 && byte & 0xc0 != 0x40 {
+            return;
+        }
+        self.cmd[self.cmd_len] = byte;
+        self.cmd_len += 1;
+        if self.cmd_len == self.cmd.len() {
+            self.cmd_len = 0;
+            self.handle_command();
+        }
+    }
+}
+
+impl SpiBus for MockSdCard {
+    fn set_clock(&mut self, _hz: u32) {}
+
+    fn select(&mut self) {
+        self.selected = true;
+    }
+
+    fn deselect(&mut self) {
+        self.selected = false;
+        self.cmd_len = 0;
+    }
+
+    fn transfer(&mut self, byte: u8) -> u8 {
+        if !self.selected || !self.present {
+            return 0xff;
+        }
+        if self.out.is_empty() {
+            if let Some(block) = self.read_next {
+                if block < self.num_blocks() {
+                    self.queue_block(block);
+                    self.read_next = Some(block + 1);
+                } else {
+                    // Data error token: out of range.
+                    self.out.push_back(0x08);
+                    self.read_next = None;
+                }
+            }
+        }
+        let out = self.out.pop_front().unwrap_or(0xff);
+        self.receive(byte);
+        out
+    }
+
+    fn card_present(&mut self) -> bool {
+        !core::mem::take(&mut self.removed) && self.present
+    }
+}
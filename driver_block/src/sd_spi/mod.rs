@@ -0,0 +1,571 @@
+//! SD card driver over an SPI bus (SD Physical Layer Specification, chapter 7).
+//!
+//! The platform provides the SPI controller through [`SpiBus`]. Command and
+//! data CRCs are always generated and checked. [`MockSdCard`] emulates a card
+//! on the bus side, so the driver can be exercised without hardware.
+
+mod mock;
+
+pub use self::mock::MockSdCard;
+
+use crate::mmc::{cmd, CardKind, Cid, Csd, Scr, BLOCK_SIZE};
+use crate::{BlockCapabilities, BlockDriverOps, DiscardLimits};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// SPI mode only commands.
+const READ_OCR: u8 = 58;
+const CRC_ON_OFF: u8 = 59;
+/// Native mode gets the CID from ALL_SEND_CID instead.
+const SEND_CID: u8 = 10;
+
+const R1_IDLE: u8 = 1 << 0;
+const R1_ILLEGAL_COMMAND: u8 = 1 << 2;
+const R1_CRC_ERROR: u8 = 1 << 3;
+
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const TOKEN_START_MULTI_WRITE: u8 = 0xfc;
+const TOKEN_STOP_TRAN: u8 = 0xfd;
+const DATA_RESP_MASK: u8 = 0x1f;
+const DATA_RESP_ACCEPTED: u8 = 0x05;
+
+const OCR_CCS: u32 = 1 << 30;
+const IF_COND_ARG: u32 = 0x1aa;
+/// The erase command class, which all SD cards support.
+const CCC_ERASE: u16 = 1 << 5;
+/// The maximum number of blocks erased by one command, to bound the time the
+/// card stays busy.
+const ERASE_MAX_BLOCKS: u64 = 0x2000;
+
+/// The maximum number of bytes before a command response (N_CR).
+const NCR_LIMIT: usize = 8;
+/// The maximum number of bytes to poll for a data token or the end of busy.
+const POLL_LIMIT: usize = 1_000_000;
+/// The maximum number of ACMD41 attempts.
+const INIT_RETRIES: usize = 10_000;
+
+const IDENT_CLOCK: u32 = 400_000;
+const DEFAULT_CLOCK: u32 = 25_000_000;
+
+/// An SPI controller with the card on one of its chip selects.
+pub trait SpiBus: Send + Sync {
+    /// Sets the SPI clock frequency, in Hz. The bus may choose a lower one.
+    fn set_clock(&mut self, hz: u32);
+
+    /// Asserts (drives low) the chip select of the card.
+    fn select(&mut self);
+
+    /// Deasserts the chip select of the card.
+    fn deselect(&mut self);
+
+    /// Sends one byte and returns the byte received at the same time.
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Sends `buf`, discarding the received bytes.
+    fn write(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.transfer(byte);
+        }
+    }
+
+    /// Receives into `buf`, sending all ones.
+    fn read(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.transfer(0xff);
+        }
+    }
+
+    /// Whether a card is in the slot, according to its card detect switch.
+    /// After a removal, it must return `false` at least once even if a card
+    /// was inserted again since. Slots without a switch always report one.
+    fn card_present(&mut self) -> bool {
+        true
+    }
+}
+
+/// Computes the CRC7 of commands and the CID/CSD registers.
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            let top = (crc >> 6) & 1;
+            crc = (crc << 1) & 0x7f;
+            if bit ^ top != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// Computes the CRC16 (CCITT) of data blocks.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// An SD card on an SPI bus, as a block device.
+pub struct SdSpiCard<B: SpiBus> {
+    bus: B,
+    high_capacity: bool,
+    cid: u128,
+    csd: u128,
+    scr: u64,
+    num_blocks: u64,
+    /// Incremented on each removal and insertion of a card.
+    generation: u64,
+    removed: bool,
+}
+
+impl<B: SpiBus> SdSpiCard<B> {
+    /// Initializes the card on `bus`, returns `Ok` if successful.
+    pub fn try_new(bus: B) -> DevResult<Self> {
+        let mut card = Self {
+            bus,
+            high_capacity: false,
+            cid: 0,
+            csd: 0,
+            scr: 0,
+            num_blocks: 0,
+            generation: 0,
+            removed: false,
+        };
+        card.identify()?;
+        Ok(card)
+    }
+
+    fn identify(&mut self) -> DevResult {
+        match self.init() {
+            Ok(()) => {
+                log::info!(
+                    "sd-spi: card {}, {} blocks, high capacity: {}",
+                    self.cid(),
+                    self.num_blocks,
+                    self.high_capacity
+                );
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("sd-spi: init failed: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Polls the card detect switch of the bus. A removed card is forgotten,
+    /// a newly inserted one is initialized. Returns whether a card is ready.
+    ///
+    /// This is also done before each transfer, so that a replaced card is
+    /// never accessed as the previous one.
+    pub fn poll_media(&mut self) -> bool {
+        if !self.bus.card_present() {
+            if !self.removed {
+                log::info!("sd-spi: card removed");
+                self.removed = true;
+                self.num_blocks = 0;
+                self.generation += 1;
+            }
+            return false;
+        }
+        if self.removed {
+            if self.identify().is_err() {
+                return false;
+            }
+            self.removed = false;
+            self.generation += 1;
+        }
+        true
+    }
+
+    /// Whether the card is block addressed (SDHC/SDXC), rather than byte
+    /// addressed (SDSC).
+    pub const fn high_capacity(&self) -> bool {
+        self.high_capacity
+    }
+
+    /// The raw card identification register.
+    pub const fn raw_cid(&self) -> u128 {
+        self.cid
+    }
+
+    /// The raw card specific data register.
+    pub const fn raw_csd(&self) -> u128 {
+        self.csd
+    }
+
+    /// The card identification register: manufacturer, product name, serial
+    /// number and manufacturing date.
+    pub const fn cid(&self) -> Cid {
+        Cid::from_raw(self.cid, CardKind::Sd)
+    }
+
+    /// The card specific data register: capacity, speed and write protection.
+    pub const fn csd(&self) -> Csd {
+        Csd::from_raw(self.csd, CardKind::Sd)
+    }
+
+    /// The SD configuration register: specification version and bus widths.
+    pub const fn scr(&self) -> Scr {
+        Scr::from_raw(self.scr)
+    }
+
+    /// Returns a reference to the SPI bus.
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    /// Returns a mutable reference to the SPI bus.
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    fn init(&mut self) -> DevResult {
+        self.high_capacity = false;
+        self.num_blocks = 0;
+        // At least 74 clocks with CS high to enter native mode, then CMD0
+        // with CS low switches the card to SPI mode.
+        self.bus.deselect();
+        self.bus.set_clock(IDENT_CLOCK);
+        self.bus.write(&[0xff; 10]);
+
+        let r1 = self.transaction(|card| card.command(cmd::GO_IDLE_STATE, 0))?;
+        if r1 != R1_IDLE {
+            log::warn!("sd-spi: no card, CMD0 returned {:#x}", r1);
+            return Err(DevError::Io);
+        }
+
+        let sd_v2 = self.transaction(|card| {
+            let r1 = card.command(cmd::SEND_IF_COND, IF_COND_ARG)?;
+            if r1 & R1_ILLEGAL_COMMAND != 0 {
+                return Ok(false);
+            }
+            let mut r7 = [0u8; 4];
+            card.bus.read(&mut r7);
+            if u32::from_be_bytes(r7) & 0xfff != IF_COND_ARG {
+                log::warn!("sd-spi: unsupported voltage range");
+                return Err(DevError::Unsupported);
+            }
+            Ok(true)
+        })?;
+
+        self.simple_command(CRC_ON_OFF, 1)?;
+
+        let hcs = if sd_v2 { OCR_CCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_RETRIES {
+            self.simple_command(cmd::APP_CMD, 0)?;
+            if self.simple_command(cmd::APP_SD_SEND_OP_COND, hcs)? & R1_IDLE == 0 {
+                ready = true;
+                break;
+            }
+        }
+        if !ready {
+            log::warn!("sd-spi: card stays idle");
+            return Err(DevError::Io);
+        }
+
+        if sd_v2 {
+            let ocr = self.transaction(|card| {
+                Self::check_r1(card.command(READ_OCR, 0)?)?;
+                let mut ocr = [0u8; 4];
+                card.bus.read(&mut ocr);
+                Ok(u32::from_be_bytes(ocr))
+            })?;
+            self.high_capacity = ocr & OCR_CCS != 0;
+        }
+        if !self.high_capacity {
+            self.simple_command(cmd::SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+        }
+
+        let mut csd = [0u8; 16];
+        self.read_register(cmd::SEND_CSD, &mut csd)?;
+        self.csd = u128::from_be_bytes(csd);
+        self.num_blocks = self.csd().num_blocks;
+
+        let mut cid = [0u8; 16];
+        self.read_register(SEND_CID, &mut cid)?;
+        self.cid = u128::from_be_bytes(cid);
+
+        let mut scr = [0u8; 8];
+        self.simple_command(cmd::APP_CMD, 0)?;
+        self.read_register(cmd::APP_SEND_SCR, &mut scr)?;
+        self.scr = u64::from_be_bytes(scr);
+
+        self.bus.set_clock(DEFAULT_CLOCK);
+        Ok(())
+    }
+
+    /// Reads a register sent as a data block, such as the CSD.
+    fn read_register(&mut self, index: u8, buf: &mut [u8]) -> DevResult {
+        self.transaction(|card| {
+            Self::check_r1(card.command(index, 0)?)?;
+            card.read_data(buf)
+        })
+    }
+
+    /// Runs `f` with the card selected.
+    fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> DevResult<T>) -> DevResult<T> {
+        self.bus.select();
+        let result = f(self);
+        self.bus.deselect();
+        // One more byte so that the card releases MISO.
+        self.bus.transfer(0xff);
+        result
+    }
+
+    /// Sends a command and returns the R1 response. The card must be selected.
+    fn command(&mut self, index: u8, arg: u32) -> DevResult<u8> {
+        if index != cmd::GO_IDLE_STATE && index != cmd::STOP_TRANSMISSION {
+            self.wait_ready()?;
+        }
+        let mut frame = [0x40 | index, 0, 0, 0, 0, 0];
+        frame[1..5].copy_from_slice(&arg.to_be_bytes());
+        frame[5] = (crc7(&frame[..5]) << 1) | 1;
+        self.bus.write(&frame);
+        if index == cmd::STOP_TRANSMISSION {
+            // Skip the stuff byte.
+            self.bus.transfer(0xff);
+        }
+        for _ in 0..NCR_LIMIT {
+            let r1 = self.bus.transfer(0xff);
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        log::warn!("sd-spi: no response to CMD{}", index);
+        Err(DevError::Io)
+    }
+
+    /// Sends a command without data in its own transaction, returns the R1
+    /// response if it reports no error.
+    fn simple_command(&mut self, index: u8, arg: u32) -> DevResult<u8> {
+        self.transaction(|card| card.command(index, arg).and_then(Self::check_r1))
+    }
+
+    fn check_r1(r1: u8) -> DevResult<u8> {
+        match r1 & !R1_IDLE {
+            0 => Ok(r1),
+            R1_ILLEGAL_COMMAND => Err(DevError::Unsupported),
+            _ => {
+                if r1 & R1_CRC_ERROR != 0 {
+                    log::warn!("sd-spi: command CRC error");
+                }
+                Err(DevError::Io)
+            }
+        }
+    }
+
+    /// Waits until the card releases the busy signal (MISO held low).
+    fn wait_ready(&mut self) -> DevResult {
+        for _ in 0..POLL_LIMIT {
+            if self.bus.transfer(0xff) == 0xff {
+                return Ok(());
+            }
+        }
+        log::warn!("sd-spi: card stays busy");
+        Err(DevError::Io)
+    }
+
+    /// Receives a data block and checks its CRC.
+    fn read_data(&mut self, buf: &mut [u8]) -> DevResult {
+        let mut token = 0xff;
+        for _ in 0..POLL_LIMIT {
+            token = self.bus.transfer(0xff);
+            if token != 0xff {
+                break;
+            }
+        }
+        if token != TOKEN_START_BLOCK {
+            log::warn!("sd-spi: read failed, token {:#x}", token);
+            return Err(DevError::Io);
+        }
+        self.bus.read(buf);
+        let mut crc = [0u8; 2];
+        self.bus.read(&mut crc);
+        if u16::from_be_bytes(crc) != crc16(buf) {
+            log::warn!("sd-spi: data CRC error");
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    /// Sends a data block and waits for the card to program it.
+    fn write_data(&mut self, token: u8, buf: &[u8]) -> DevResult {
+        self.bus.transfer(0xff);
+        self.bus.transfer(token);
+        self.bus.write(buf);
+        self.bus.write(&crc16(buf).to_be_bytes());
+        let resp = self.bus.transfer(0xff) & DATA_RESP_MASK;
+        if resp != DATA_RESP_ACCEPTED {
+            log::warn!("sd-spi: write rejected, response {:#x}", resp);
+            return Err(DevError::Io);
+        }
+        self.wait_ready()
+    }
+
+    /// Converts a block number to the command argument: the block number
+    /// itself on high capacity cards, its byte address otherwise. Fails if it
+    /// does not fit in 32 bits, rather than wrapping to another block.
+    fn block_arg(&self, block_id: u64) -> DevResult<u32> {
+        let addr = if self.high_capacity {
+            Some(block_id)
+        } else {
+            block_id.checked_mul(BLOCK_SIZE as u64)
+        };
+        addr.and_then(|addr| u32::try_from(addr).ok())
+            .ok_or(DevError::Io)
+    }
+
+    /// Checks that `len` bytes starting at `block_id` are whole blocks inside
+    /// the card, all of which are addressable.
+    fn check_range(&mut self, block_id: u64, len: usize) -> DevResult {
+        if len % BLOCK_SIZE != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        self.check_blocks(block_id, (len / BLOCK_SIZE) as u64)
+    }
+
+    /// Checks that a card is present and that `count` blocks starting at
+    /// `block_id` are inside it, all of which are addressable.
+    fn check_blocks(&mut self, block_id: u64, count: u64) -> DevResult {
+        if count == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        if !self.poll_media() {
+            return Err(DevError::BadState);
+        }
+        match block_id.checked_add(count) {
+            Some(end) if end <= self.num_blocks => self.block_arg(end - 1).map(|_| ()),
+            _ => Err(DevError::Io),
+        }
+    }
+}
+
+impl<B: SpiBus> BaseDriverOps for SdSpiCard<B> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        "sd-spi"
+    }
+}
+
+impl<B: SpiBus> BlockDriverOps for SdSpiCard<B> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.check_range(block_id, buf.len())?;
+        let arg = self.block_arg(block_id)?;
+        if buf.len() == BLOCK_SIZE {
+            return self.transaction(|card| {
+                Self::check_r1(card.command(cmd::READ_SINGLE_BLOCK, arg)?)?;
+                card.read_data(buf)
+            });
+        }
+        self.transaction(|card| {
+            Self::check_r1(card.command(cmd::READ_MULTIPLE_BLOCK, arg)?)?;
+            let result = buf
+                .chunks_exact_mut(BLOCK_SIZE)
+                .try_for_each(|block| card.read_data(block));
+            let stop = card.command(cmd::STOP_TRANSMISSION, 0);
+            result?;
+            Self::check_r1(stop?)?;
+            card.wait_ready()
+        })
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.check_range(block_id, buf.len())?;
+        let arg = self.block_arg(block_id)?;
+        if buf.len() == BLOCK_SIZE {
+            return self.transaction(|card| {
+                Self::check_r1(card.command(cmd::WRITE_BLOCK, arg)?)?;
+                card.write_data(TOKEN_START_BLOCK, buf)
+            });
+        }
+        self.transaction(|card| {
+            Self::check_r1(card.command(cmd::WRITE_MULTIPLE_BLOCK, arg)?)?;
+            let result = buf
+                .chunks_exact(BLOCK_SIZE)
+                .try_for_each(|block| card.write_data(TOKEN_START_MULTI_WRITE, block));
+            card.bus.transfer(TOKEN_STOP_TRAN);
+            card.bus.transfer(0xff);
+            card.wait_ready()?;
+            result
+        })
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+
+    /// Discarded blocks are erased, they read back as zeros or ones
+    /// depending on the card.
+    fn discard(&mut self, start: u64, count: u64) -> DevResult {
+        if self.discard_limits().is_none() {
+            return Err(DevError::Unsupported);
+        }
+        self.check_blocks(start, count)?;
+        // Only the whole erase units of the range are erased, erasing the
+        // others would erase the blocks around the range too.
+        let unit = self.csd().erase_blocks as u64;
+        let end = (start + count) / unit * unit;
+        let mut block = start.div_ceil(unit) * unit;
+        while block < end {
+            let blocks = (end - block).min(ERASE_MAX_BLOCKS / unit * unit);
+            let first = self.block_arg(block)?;
+            let last = self.block_arg(block + blocks - 1)?;
+            self.simple_command(cmd::ERASE_WR_BLK_START, first)?;
+            self.simple_command(cmd::ERASE_WR_BLK_END, last)?;
+            // The card holds MISO low until the blocks are erased.
+            self.transaction(|card| {
+                Self::check_r1(card.command(cmd::ERASE, 0)?)?;
+                card.wait_ready()
+            })?;
+            block += blocks;
+        }
+        Ok(())
+    }
+
+    fn discard_limits(&self) -> Option<DiscardLimits> {
+        (self.csd().command_classes & CCC_ERASE != 0).then_some(DiscardLimits {
+            granularity: self.csd().erase_blocks as u64,
+            max_blocks: self.num_blocks,
+        })
+    }
+
+    /// SD cards are removable, and do not cache writes.
+    fn capabilities(&self) -> BlockCapabilities {
+        let csd = self.csd();
+        BlockCapabilities {
+            removable: true,
+            read_only: csd.perm_write_protect || csd.tmp_write_protect,
+            write_cache: false,
+            discard: self.discard_limits(),
+            ..BlockCapabilities::new(self.num_blocks, BLOCK_SIZE)
+        }
+    }
+
+    fn media_generation(&self) -> u64 {
+        self.generation
+    }
+}
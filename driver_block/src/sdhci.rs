@@ -0,0 +1,960 @@
+//! Generic SD Host Controller (SDHCI) driver.
+//!
+//! Works with any controller following the SD Host Controller Simplified
+//! Specification (versions 1.0 to 3.0), such as the ones on PCI, the Arasan
+//! controller on BCM2835/BCM2711 and most SoC SD/eMMC hosts. Deviations of a
+//! particular controller are described by [`SdhciQuirks`].
+//!
+//! Registers are only accessed 32 bits at a time, as some controllers (notably
+//! BCM2835) do not support narrower accesses. Data is moved by ADMA2 when the
+//! controller supports it, through an aligned bounce buffer if the buffer is
+//! not word aligned or out of reach, and by PIO through the buffer data port
+//! otherwise.
+//!
+//! Completion is polled until [`SdhciHost::enable_irq`] switches to
+//! interrupts, e.g. once the kernel is past early boot.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+use crate::async_ops::IrqEvent;
+use crate::dma::{DmaBuffer, DmaHal, PAGE_SIZE};
+use crate::mmc::{
+    BusTiming, BusWidth, DataBuf, MmcCard, MmcCommand, MmcData, MmcHost, Response, ResponseType,
+    BLOCK_SIZE,
+};
+use driver_common::{DevError, DevResult};
+
+/// The maximum number of polls before a controller operation times out.
+const SPIN_LIMIT: usize = 10_000_000;
+/// The longest wait for an interrupt in interrupt-driven mode, longer than
+/// the largest transfer takes at the slowest data clock.
+const IRQ_TIMEOUT_NS: u64 = 10_000_000_000;
+
+const SDHCI_BLOCK: usize = 0x04;
+const SDHCI_ARGUMENT: usize = 0x08;
+const SDHCI_COMMAND: usize = 0x0c;
+const SDHCI_RESPONSE: usize = 0x10;
+const SDHCI_BUFFER: usize = 0x20;
+const SDHCI_PRESENT_STATE: usize = 0x24;
+const SDHCI_HOST_CONTROL: usize = 0x28;
+const SDHCI_CLOCK_CONTROL: usize = 0x2c;
+const SDHCI_INT_STATUS: usize = 0x30;
+const SDHCI_INT_ENABLE: usize = 0x34;
+const SDHCI_SIGNAL_ENABLE: usize = 0x38;
+/// Host control 2 is the upper half of the auto CMD error status register.
+const SDHCI_HOST_CONTROL2: usize = 0x3c;
+const SDHCI_CAPABILITIES: usize = 0x40;
+const SDHCI_CAPABILITIES_1: usize = 0x44;
+const SDHCI_ADMA_ERROR: usize = 0x54;
+const SDHCI_ADMA_ADDRESS: usize = 0x58;
+const SDHCI_HOST_VERSION: usize = 0xfc;
+
+const TRANSFER_DMA: u32 = 1 << 0;
+const TRANSFER_BLK_CNT_EN: u32 = 1 << 1;
+const TRANSFER_AUTO_CMD12: u32 = 1 << 2;
+const TRANSFER_READ: u32 = 1 << 4;
+const TRANSFER_MULTI: u32 = 1 << 5;
+
+const CMD_RESP_LONG: u32 = 1 << 16;
+const CMD_RESP_SHORT: u32 = 2 << 16;
+const CMD_RESP_SHORT_BUSY: u32 = 3 << 16;
+const CMD_CRC: u32 = 1 << 19;
+const CMD_INDEX: u32 = 1 << 20;
+const CMD_DATA: u32 = 1 << 21;
+
+const PRESENT_CMD_INHIBIT: u32 = 1 << 0;
+const PRESENT_DATA_INHIBIT: u32 = 1 << 1;
+const PRESENT_CARD_INSERTED: u32 = 1 << 16;
+const PRESENT_DAT_LEVEL: u32 = 0xf << 20;
+
+const CTRL_4BITBUS: u32 = 1 << 1;
+const CTRL_HISPD: u32 = 1 << 2;
+const CTRL_8BITBUS: u32 = 1 << 5;
+const CTRL_DMA_MASK: u32 = 3 << 3;
+const CTRL_ADMA32: u32 = 2 << 3;
+const POWER_ON: u32 = 1 << 8;
+const POWER_180: u32 = 5 << 9;
+const POWER_300: u32 = 6 << 9;
+const POWER_330: u32 = 7 << 9;
+const POWER_MASK: u32 = 0xf << 8;
+
+const CLOCK_INT_EN: u32 = 1 << 0;
+const CLOCK_INT_STABLE: u32 = 1 << 1;
+const CLOCK_CARD_EN: u32 = 1 << 2;
+const CLOCK_MASK: u32 = 0xffff;
+const TIMEOUT_MAX: u32 = 0xe << 16;
+const TIMEOUT_MASK: u32 = 0xf << 16;
+const RESET_ALL: u32 = 1 << 24;
+const RESET_CMD: u32 = 1 << 25;
+const RESET_DATA: u32 = 1 << 26;
+
+const CTRL2_UHS_MASK: u32 = 7 << 16;
+const CTRL2_UHS_SDR25: u32 = 1 << 16;
+const CTRL2_UHS_SDR50: u32 = 2 << 16;
+const CTRL2_UHS_SDR104: u32 = 3 << 16;
+const CTRL2_VDD_180: u32 = 1 << 19;
+const CTRL2_EXEC_TUNING: u32 = 1 << 22;
+const CTRL2_TUNED_CLK: u32 = 1 << 23;
+
+const INT_CMD_COMPLETE: u32 = 1 << 0;
+const INT_XFER_COMPLETE: u32 = 1 << 1;
+const INT_SPACE_AVAIL: u32 = 1 << 4;
+const INT_DATA_AVAIL: u32 = 1 << 5;
+const INT_CARD_INSERT: u32 = 1 << 6;
+const INT_CARD_REMOVE: u32 = 1 << 7;
+const INT_ERROR: u32 = 1 << 15;
+const INT_ADMA_ERROR: u32 = 1 << 25;
+const INT_ERROR_MASK: u32 = 0xffff_0000;
+const INT_ALL: u32 = 0xffff_ffff;
+/// Every status but card detect, which is kept latched for `card_present`.
+const INT_TRANSFER: u32 = INT_ALL & !(INT_CARD_INSERT | INT_CARD_REMOVE);
+/// The statuses signaled on the interrupt line in interrupt-driven mode.
+const INT_SIGNALED: u32 =
+    INT_CMD_COMPLETE | INT_XFER_COMPLETE | INT_SPACE_AVAIL | INT_DATA_AVAIL | INT_ERROR_MASK;
+
+const CAN_DO_8BIT: u32 = 1 << 18;
+const CAN_DO_ADMA2: u32 = 1 << 19;
+const CAN_DO_HISPD: u32 = 1 << 21;
+const CAN_VDD_330: u32 = 1 << 24;
+const CAN_VDD_300: u32 = 1 << 25;
+const CAN_VDD_180: u32 = 1 << 26;
+const CAN_DO_SDR50: u32 = 1 << 0;
+const CAN_DO_SDR104: u32 = 1 << 1;
+const TUNING_SDR50: u32 = 1 << 13;
+
+const SPEC_300: u32 = 2;
+
+const IDENT_CLOCK: u32 = 400_000;
+
+/// The time the regulator takes to settle at 1.8V during a voltage switch.
+const VOLTAGE_SETTLE_NS: u64 = 5_000_000;
+/// The time the card takes to release DAT[3:0] after a voltage switch, once
+/// the clock runs again, and the interval at which they are polled.
+const DAT_RELEASE_NS: u64 = 1_000_000;
+const DAT_POLL_NS: u64 = 10_000;
+
+/// The maximum number of tuning commands (CMD19) sent by a tuning procedure.
+const TUNING_LOOPS: usize = 40;
+/// The size of the SD tuning block.
+const TUNING_BLOCK_SIZE: u32 = 64;
+
+const ADMA_VALID: u16 = 1 << 0;
+const ADMA_END: u16 = 1 << 1;
+const ADMA_ACT_TRAN: u16 = 2 << 4;
+/// The size of a 32-bit ADMA2 descriptor.
+const ADMA_DESC_SIZE: usize = 8;
+const ADMA_DESC_COUNT: usize = PAGE_SIZE / ADMA_DESC_SIZE;
+/// The largest segment of one descriptor (a length of 0 means 64 KiB).
+const ADMA_MAX_SEGMENT: usize = 0x1_0000;
+/// The largest transfer whose descriptors always fit in the table, even if
+/// no physical pages are contiguous.
+const ADMA_MAX_TRANSFER: usize = ADMA_DESC_COUNT * PAGE_SIZE;
+
+/// Deviations of a controller from the SDHCI specification.
+#[derive(Clone, Copy, Debug)]
+pub struct SdhciQuirks {
+    /// The base clock in Hz, for controllers that do not report it in the
+    /// capabilities register.
+    pub base_clock: Option<u32>,
+    /// The card detect signal is not wired, assume a card is always present.
+    pub broken_card_detect: bool,
+    /// Never power the card or signal at 1.8V even if the capabilities claim
+    /// so, which also rules out the UHS-I modes.
+    pub no_1_8v: bool,
+    /// SD clock cycles to wait after each register write. The BCM2835
+    /// controller drops writes issued within two SD clock cycles of each
+    /// other.
+    pub write_delay_cycles: u32,
+    /// ADMA2 does not work even if the capabilities claim so.
+    pub broken_adma: bool,
+    /// The controller has no high speed enable bit, high speed timing only
+    /// takes the faster card clock.
+    pub no_hispd_bit: bool,
+}
+
+impl SdhciQuirks {
+    /// A controller that follows the specification.
+    pub const GENERIC: Self = Self {
+        base_clock: None,
+        broken_card_detect: false,
+        no_1_8v: false,
+        write_delay_cycles: 0,
+        broken_adma: false,
+        no_hispd_bit: false,
+    };
+
+    /// The Arasan controller on BCM2835 (Raspberry Pi). The base clock is
+    /// board specific and should be filled in from the device tree. ADMA2
+    /// is used if the capabilities claim it, PIO takes over if it fails.
+    pub const BCM2835: Self = Self {
+        base_clock: None,
+        broken_card_detect: true,
+        no_1_8v: true,
+        write_delay_cycles: 2,
+        broken_adma: false,
+        no_hispd_bit: true,
+    };
+
+    /// The EMMC2 controller on BCM2711 (Raspberry Pi 4). Unlike the older
+    /// Arasan controller it has a card detect line and reports its base
+    /// clock.
+    /// 1.8V signaling needs a board regulator not driven by this driver, so
+    /// cards run at high speed at most.
+    pub const BCM2711: Self = Self {
+        base_clock: None,
+        broken_card_detect: false,
+        no_1_8v: true,
+        write_delay_cycles: 0,
+        broken_adma: false,
+        no_hispd_bit: false,
+    };
+}
+
+/// A generic SDHCI host controller.
+pub struct SdhciHost<H: DmaHal> {
+    name: String,
+    base: NonNull<u8>,
+    quirks: SdhciQuirks,
+    version: u32,
+    caps: u32,
+    caps1: u32,
+    timing: BusTiming,
+    /// The card clock in Hz, 0 while it is not set.
+    clock: u32,
+    /// Nanoseconds to wait after each register write at the current card
+    /// clock, shared with the interrupt handler.
+    write_delay: Arc<AtomicU32>,
+    /// The ADMA2 descriptor table, if the controller supports ADMA2.
+    adma: Option<DmaBuffer<H>>,
+    use_dma: bool,
+    /// The buffer through which data that ADMA2 cannot reach in place is
+    /// copied, allocated on first use and grown to the largest transfer.
+    bounce: Option<DmaBuffer<H>>,
+    /// The statuses acknowledged by the interrupt handler and not consumed
+    /// yet, looked at along with the status register.
+    pending: Arc<AtomicU32>,
+    /// Used while waiting for an interrupt, `None` when polling.
+    irq_wait: Option<IrqWaiter>,
+}
+
+/// The event signaled by the interrupt handler, and the function called with
+/// it to wait for an interrupt.
+type IrqWaiter = (&'static IrqEvent, fn(&IrqEvent, u64) -> bool);
+
+/// The interrupt handler of an SDHCI controller in interrupt-driven mode,
+/// returned by [`SdhciHost::enable_irq`].
+///
+/// It only touches the interrupt status register, so it can run while a
+/// request is in flight on another CPU.
+pub struct SdhciIrq {
+    base: NonNull<u8>,
+    pending: Arc<AtomicU32>,
+    write_delay: Arc<AtomicU32>,
+    delay_ns: fn(u64),
+    event: &'static IrqEvent,
+}
+
+unsafe impl Send for SdhciIrq {}
+unsafe impl Sync for SdhciIrq {}
+
+impl SdhciIrq {
+    /// Handles an interrupt of the controller: acknowledges it, records its
+    /// status for the waiting request and signals the event. Returns whether
+    /// the controller raised it, for shared interrupt lines.
+    pub fn handle_irq(&self) -> bool {
+        let reg = unsafe { self.base.as_ptr().add(SDHCI_INT_STATUS) as *mut u32 };
+        let status = unsafe { reg.read_volatile() };
+        if status == 0 {
+            return false;
+        }
+        self.pending.fetch_or(status, Ordering::AcqRel);
+        unsafe { reg.write_volatile(status) };
+        let delay = self.write_delay.load(Ordering::Relaxed);
+        if delay != 0 {
+            (self.delay_ns)(delay as u64);
+        }
+        self.event.signal();
+        true
+    }
+}
+
+/// A card behind a generic SDHCI host controller, as a block device.
+pub type SdhciDriver<H> = MmcCard<SdhciHost<H>>;
+
+unsafe impl<H: DmaHal> Send for SdhciHost<H> {}
+unsafe impl<H: DmaHal> Sync for SdhciHost<H> {}
+
+impl<H: DmaHal> SdhciHost<H> {
+    /// Creates a host for the controller whose registers are mapped at
+    /// `base`. The controller is not touched until the card is initialized.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to the mapped SDHCI registers, which must remain
+    /// valid for the lifetime of the host.
+    pub unsafe fn try_new(base: NonNull<u8>, name: &str, quirks: SdhciQuirks) -> DevResult<Self> {
+        let mut host = Self {
+            name: String::from(name),
+            base,
+            quirks,
+            version: 0,
+            caps: 0,
+            caps1: 0,
+            timing: BusTiming::Default,
+            clock: 0,
+            write_delay: Arc::new(AtomicU32::new(0)),
+            adma: None,
+            use_dma: false,
+            bounce: None,
+            pending: Arc::new(AtomicU32::new(0)),
+            irq_wait: None,
+        };
+        host.set_write_delay(0);
+        host.version = (host.read(SDHCI_HOST_VERSION) >> 16) & 0xff;
+        host.caps = host.read(SDHCI_CAPABILITIES);
+        if host.version >= SPEC_300 {
+            host.caps1 = host.read(SDHCI_CAPABILITIES_1);
+        }
+        if host.caps & CAN_DO_ADMA2 != 0 && !quirks.broken_adma {
+            // Platforms without DMA memory still get PIO.
+            match DmaBuffer::new(PAGE_SIZE) {
+                Ok(table) => {
+                    host.adma = Some(table);
+                    host.use_dma = true;
+                }
+                Err(_) => log::warn!("{}: no memory for ADMA2, using PIO", host.name),
+            }
+        }
+        Ok(host)
+    }
+
+    /// Enables or disables ADMA2 transfers. DMA cannot be enabled if the
+    /// controller has no working ADMA2.
+    pub fn set_dma_enabled(&mut self, enabled: bool) {
+        self.use_dma = enabled && self.adma.is_some();
+    }
+
+    /// Switches to interrupt-driven completion. The controller then signals
+    /// command and transfer completion, errors and card changes on its
+    /// interrupt line, and the kernel must call [`SdhciIrq::handle_irq`] on
+    /// the returned handler when it fires.
+    ///
+    /// The handler signals `event` each time it records a status. While a
+    /// request waits for the controller, `wait` is called with `event` and
+    /// a timeout in nanoseconds until the handler recorded what it waits
+    /// for. It should block the task until the event is signaled or the
+    /// timeout expires, e.g. by running [`IrqEvent::wait`] on the executor
+    /// of the kernel along with a timer, which lets other tasks run
+    /// meanwhile. It returns whether the event was signaled.
+    ///
+    /// If the timeout expires, the status is looked at once more in case
+    /// the interrupt was lost, then the request fails and the command and
+    /// data lines are reset.
+    pub fn enable_irq(
+        &mut self,
+        event: &'static IrqEvent,
+        wait: fn(&IrqEvent, u64) -> bool,
+    ) -> SdhciIrq {
+        event.clear();
+        self.irq_wait = Some((event, wait));
+        self.write(SDHCI_SIGNAL_ENABLE, self.signaled_ints());
+        SdhciIrq {
+            base: self.base,
+            pending: self.pending.clone(),
+            write_delay: self.write_delay.clone(),
+            delay_ns: H::delay_ns,
+            event,
+        }
+    }
+
+    /// Switches back to polling completion, e.g. before the kernel shuts
+    /// interrupts off. The handler may still be called, as long as it is
+    /// not freed.
+    pub fn disable_irq(&mut self) {
+        self.irq_wait = None;
+        self.write(SDHCI_SIGNAL_ENABLE, 0);
+    }
+
+    /// Whether completion is interrupt-driven.
+    pub const fn irq_enabled(&self) -> bool {
+        self.irq_wait.is_some()
+    }
+
+    /// The specification version implemented by the controller, as encoded in
+    /// the host controller version register (0 for 1.0, 2 for 3.0).
+    pub const fn spec_version(&self) -> u32 {
+        self.version
+    }
+
+    fn card_inserted(&self) -> bool {
+        self.quirks.broken_card_detect
+            || self.read(SDHCI_PRESENT_STATE) & PRESENT_CARD_INSERTED != 0
+    }
+
+    /// The statuses signaled on the interrupt line in interrupt-driven mode.
+    fn signaled_ints(&self) -> u32 {
+        if self.quirks.broken_card_detect {
+            INT_SIGNALED
+        } else {
+            INT_SIGNALED | INT_CARD_INSERT | INT_CARD_REMOVE
+        }
+    }
+
+    /// The interrupt status, including the statuses already acknowledged by
+    /// the interrupt handler.
+    fn int_status(&self) -> u32 {
+        self.read(SDHCI_INT_STATUS) | self.pending.load(Ordering::Acquire)
+    }
+
+    /// Clears statuses, whether the interrupt handler acknowledged them
+    /// already or not, along with the signal of the event reporting them.
+    fn ack_int(&mut self, mask: u32) {
+        if let Some((event, _)) = self.irq_wait {
+            event.clear();
+        }
+        self.pending.fetch_and(!mask, Ordering::AcqRel);
+        self.write(SDHCI_INT_STATUS, mask);
+    }
+
+    /// Waits a little before looking at the interrupt status again. Returns
+    /// `false` if no interrupt came in time in interrupt-driven mode.
+    fn idle(&self) -> bool {
+        match self.irq_wait {
+            Some((event, wait)) => wait(event, IRQ_TIMEOUT_NS),
+            None => {
+                core::hint::spin_loop();
+                true
+            }
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { (self.base.as_ptr().add(offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&mut self, offset: usize, val: u32) {
+        unsafe { (self.base.as_ptr().add(offset) as *mut u32).write_volatile(val) };
+        let delay = self.write_delay.load(Ordering::Relaxed);
+        if delay != 0 {
+            H::delay_ns(delay as u64);
+        }
+    }
+
+    /// Spaces register writes for a card clock of `clock` Hz, which is at
+    /// least the identification clock, as when the clock is not set.
+    fn set_write_delay(&self, clock: u32) {
+        let clock = clock.max(IDENT_CLOCK) as u64;
+        let ns = (self.quirks.write_delay_cycles as u64 * 1_000_000_000).div_ceil(clock);
+        self.write_delay.store(ns as u32, Ordering::Relaxed);
+    }
+
+    fn modify(&mut self, offset: usize, clear: u32, set: u32) {
+        let val = self.read(offset);
+        self.write(offset, (val & !clear) | set);
+    }
+
+    fn wait(&self, offset: usize, mask: u32, set: bool) -> DevResult {
+        for _ in 0..SPIN_LIMIT {
+            if (self.read(offset) & mask != 0) == set {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        log::warn!("{}: timeout on register {:#x}", self.name, offset);
+        Err(DevError::Io)
+    }
+
+    fn reset(&mut self, mask: u32) -> DevResult {
+        self.modify(SDHCI_CLOCK_CONTROL, 0, mask);
+        self.wait(SDHCI_CLOCK_CONTROL, mask, false)
+    }
+
+    fn base_clock(&self) -> u32 {
+        if let Some(clock) = self.quirks.base_clock {
+            return clock;
+        }
+        let mask = if self.version >= SPEC_300 { 0xff } else { 0x3f };
+        ((self.caps >> 8) & mask) * 1_000_000
+    }
+
+    /// Waits for `mask` in the interrupt status, returns an error and resets
+    /// the command and data lines if an error interrupt is raised instead.
+    /// In interrupt-driven mode, the wait function is called in between, and
+    /// the status is looked at a last time once it times out.
+    ///
+    /// An ADMA error turns DMA off, so that the retry of the transfer and
+    /// the following ones go through PIO.
+    fn wait_int(&mut self, mask: u32) -> DevResult {
+        let mut timed_out = false;
+        for _ in 0..SPIN_LIMIT {
+            let status = self.int_status();
+            if status & INT_ERROR != 0 {
+                self.ack_int(status & INT_TRANSFER);
+                log::warn!("{}: error interrupt {:#x}", self.name, status >> 16);
+                if status & INT_ADMA_ERROR != 0 {
+                    let adma_error = self.read(SDHCI_ADMA_ERROR);
+                    log::warn!("{}: ADMA error status {:#x}", self.name, adma_error);
+                    if self.use_dma {
+                        log::warn!("{}: falling back to PIO", self.name);
+                        self.use_dma = false;
+                    }
+                }
+                self.reset(RESET_CMD)?;
+                self.reset(RESET_DATA)?;
+                return Err(DevError::Io);
+            }
+            if status & mask != 0 {
+                self.ack_int(status & mask);
+                return Ok(());
+            }
+            if timed_out {
+                break;
+            }
+            timed_out = !self.idle();
+        }
+        log::warn!("{}: timeout waiting for interrupt {:#x}", self.name, mask);
+        self.reset(RESET_CMD)?;
+        self.reset(RESET_DATA)?;
+        Err(DevError::Io)
+    }
+
+    fn read_response(&self, resp: ResponseType) -> Response {
+        match resp {
+            ResponseType::None => Response::None,
+            ResponseType::R2 => {
+                // The controller strips the CRC byte.
+                let mut val = 0u128;
+                for i in 0..4 {
+                    val |= (self.read(SDHCI_RESPONSE + i * 4) as u128) << (i * 32);
+                }
+                Response::Long(val << 8)
+            }
+            _ => Response::Short(self.read(SDHCI_RESPONSE)),
+        }
+    }
+
+    /// Fills the ADMA2 descriptor table for the buffer at `vaddr`. Returns
+    /// `false` if the buffer is not suitable for 32-bit ADMA2.
+    ///
+    /// Lengths are in bytes, only the address of each segment must be word
+    /// aligned.
+    fn setup_adma(&mut self, vaddr: usize, len: usize) -> bool {
+        let Some(table) = &self.adma else {
+            return false;
+        };
+        if vaddr % 4 != 0 || table.paddr() > u32::MAX as usize {
+            return false;
+        }
+        let desc = table.as_ptr() as *mut u64;
+        let mut count = 0;
+        let mut seg_start = H::virt_to_phys(vaddr);
+        let mut seg_len = 0;
+        let mut offset = 0;
+        while offset < len {
+            let chunk = (PAGE_SIZE - (vaddr + offset) % PAGE_SIZE).min(len - offset);
+            let paddr = H::virt_to_phys(vaddr + offset);
+            if paddr + chunk - 1 > u32::MAX as usize {
+                return false;
+            }
+            if paddr != seg_start + seg_len || seg_len + chunk > ADMA_MAX_SEGMENT {
+                if count == ADMA_DESC_COUNT {
+                    return false;
+                }
+                unsafe {
+                    desc.add(count)
+                        .write_volatile(adma_desc(seg_start, seg_len, false))
+                };
+                count += 1;
+                seg_start = paddr;
+                seg_len = 0;
+            }
+            seg_len += chunk;
+            offset += chunk;
+        }
+        if count == ADMA_DESC_COUNT {
+            return false;
+        }
+        unsafe {
+            desc.add(count)
+                .write_volatile(adma_desc(seg_start, seg_len, true))
+        };
+        fence(Ordering::SeqCst);
+        true
+    }
+
+    /// Enables or disables the card clock, which must be stopped while the
+    /// timing or the signaling voltage changes.
+    fn set_card_clock(&mut self, enabled: bool) {
+        if enabled {
+            self.modify(SDHCI_CLOCK_CONTROL, 0, CLOCK_CARD_EN);
+        } else {
+            self.modify(SDHCI_CLOCK_CONTROL, CLOCK_CARD_EN, 0);
+        }
+    }
+
+    /// Returns the bounce buffer, grown to `len` bytes if needed, `None` if
+    /// there is not enough DMA memory.
+    fn bounce_buffer(&mut self, len: usize) -> Option<&mut DmaBuffer<H>> {
+        if self.bounce.as_ref().map_or(true, |buf| buf.len() < len) {
+            // Drop the old buffer first, the new one is larger.
+            self.bounce = None;
+            match DmaBuffer::new(len) {
+                Ok(buf) => self.bounce = Some(buf),
+                Err(_) => {
+                    log::warn!("{}: no memory for a bounce buffer, using PIO", self.name);
+                    return None;
+                }
+            }
+        }
+        self.bounce.as_mut()
+    }
+
+    /// Sets up ADMA2 for `len` bytes of `buf`, in place or through the
+    /// bounce buffer, into which data to write is copied. Returns whether
+    /// ADMA2 is used, and whether the bounce buffer is.
+    fn setup_dma(&mut self, buf: &DataBuf, len: usize) -> (bool, bool) {
+        if !self.use_dma {
+            return (false, false);
+        }
+        let vaddr = match buf {
+            DataBuf::Read(buf) => buf.as_ptr() as usize,
+            DataBuf::Write(buf) => buf.as_ptr() as usize,
+        };
+        if self.setup_adma(vaddr, len) {
+            return (true, false);
+        }
+        let Some(bounce) = self.bounce_buffer(len) else {
+            return (false, false);
+        };
+        if let DataBuf::Write(buf) = buf {
+            bounce.as_mut_slice()[..len].copy_from_slice(&buf[..len]);
+        }
+        let vaddr = bounce.as_ptr() as usize;
+        let dma = self.setup_adma(vaddr, len);
+        (dma, dma)
+    }
+
+    fn transfer_pio(&mut self, data: MmcData) -> DevResult {
+        let block_size = data.block_size;
+        match data.buf {
+            DataBuf::Read(buf) => {
+                for block in buf.chunks_exact_mut(block_size).take(data.blocks) {
+                    self.wait_int(INT_DATA_AVAIL)?;
+                    for word in block.chunks_mut(4) {
+                        let val = self.read(SDHCI_BUFFER).to_le_bytes();
+                        word.copy_from_slice(&val[..word.len()]);
+                    }
+                }
+            }
+            DataBuf::Write(buf) => {
+                for block in buf.chunks_exact(block_size).take(data.blocks) {
+                    self.wait_int(INT_SPACE_AVAIL)?;
+                    for word in block.chunks(4) {
+                        let mut val = [0u8; 4];
+                        val[..word.len()].copy_from_slice(word);
+                        self.write(SDHCI_BUFFER, u32::from_le_bytes(val));
+                    }
+                }
+            }
+        }
+        self.wait_int(INT_XFER_COMPLETE)
+    }
+}
+
+/// Encodes a 32-bit ADMA2 transfer descriptor.
+fn adma_desc(paddr: usize, len: usize, end: bool) -> u64 {
+    let mut attr = ADMA_VALID | ADMA_ACT_TRAN;
+    if end {
+        attr |= ADMA_END;
+    }
+    // A length of 0 stands for 64 KiB.
+    let len = (len % ADMA_MAX_SEGMENT) as u64;
+    attr as u64 | len << 16 | (paddr as u64) << 32
+}
+
+impl<H: DmaHal> MmcHost for SdhciHost<H> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn init(&mut self) -> DevResult {
+        self.reset(RESET_ALL)?;
+        self.clock = 0;
+        self.set_write_delay(0);
+        if !self.card_inserted() {
+            log::warn!("{}: no card inserted", self.name);
+            return Err(DevError::Io);
+        }
+
+        // Completion is polled unless interrupts were enabled.
+        self.write(SDHCI_INT_ENABLE, INT_ALL);
+        let signals = if self.irq_enabled() {
+            self.signaled_ints()
+        } else {
+            0
+        };
+        self.write(SDHCI_SIGNAL_ENABLE, signals);
+
+        let power = if self.caps & CAN_VDD_330 != 0 {
+            POWER_330
+        } else if self.caps & CAN_VDD_300 != 0 {
+            POWER_300
+        } else if self.caps & CAN_VDD_180 != 0 && !self.quirks.no_1_8v {
+            POWER_180
+        } else {
+            log::warn!("{}: no supported voltage", self.name);
+            return Err(DevError::Unsupported);
+        };
+        self.modify(SDHCI_HOST_CONTROL, POWER_MASK, power);
+        self.modify(SDHCI_HOST_CONTROL, 0, POWER_ON);
+        self.modify(SDHCI_CLOCK_CONTROL, TIMEOUT_MASK, TIMEOUT_MAX);
+
+        // The reset also returned the timing and the signaling to default.
+        self.timing = BusTiming::Default;
+        self.set_clock(IDENT_CLOCK)?;
+        self.set_bus_width(BusWidth::Width1)
+    }
+
+    fn card_present(&mut self) -> bool {
+        if self.quirks.broken_card_detect {
+            return true;
+        }
+        // A removal is latched even if a card was inserted again since.
+        if self.int_status() & INT_CARD_REMOVE != 0 {
+            self.ack_int(INT_CARD_INSERT | INT_CARD_REMOVE);
+            return false;
+        }
+        self.card_inserted()
+    }
+
+    fn set_clock(&mut self, hz: u32) -> DevResult {
+        let base = self.base_clock();
+        if base == 0 || hz == 0 {
+            log::warn!("{}: unknown base clock", self.name);
+            return Err(DevError::Unsupported);
+        }
+        // The card clock is base / (2 * div), or base when div is 0. Before
+        // version 3.0, div is an 8-bit power of two.
+        let div = if base <= hz {
+            0
+        } else if self.version >= SPEC_300 {
+            base.div_ceil(2 * hz).min(0x3ff)
+        } else {
+            base.div_ceil(2 * hz).next_power_of_two().min(0x80)
+        };
+        let div_bits = ((div & 0xff) << 8) | ((div >> 8) & 0x3) << 6;
+        let clock = if div == 0 { base } else { base / (2 * div) };
+
+        // Writes are spaced for the slower clock until the new one runs.
+        self.set_write_delay(self.clock.min(clock));
+        self.modify(SDHCI_CLOCK_CONTROL, CLOCK_MASK, 0);
+        self.modify(SDHCI_CLOCK_CONTROL, 0, div_bits | CLOCK_INT_EN);
+        self.wait(SDHCI_CLOCK_CONTROL, CLOCK_INT_STABLE, true)?;
+        self.modify(SDHCI_CLOCK_CONTROL, 0, CLOCK_CARD_EN);
+        self.clock = clock;
+        self.set_write_delay(clock);
+        Ok(())
+    }
+
+    fn reset_lines(&mut self) -> DevResult {
+        self.reset(RESET_CMD)?;
+        self.reset(RESET_DATA)
+    }
+
+    fn max_blocks(&self) -> usize {
+        if self.use_dma {
+            ADMA_MAX_TRANSFER / BLOCK_SIZE
+        } else {
+            u16::MAX as usize
+        }
+    }
+
+    fn supports_bus_width(&self, width: BusWidth) -> bool {
+        match width {
+            BusWidth::Width1 | BusWidth::Width4 => true,
+            BusWidth::Width8 => self.caps & CAN_DO_8BIT != 0,
+        }
+    }
+
+    fn set_bus_width(&mut self, width: BusWidth) -> DevResult {
+        let bits = match width {
+            BusWidth::Width1 => 0,
+            BusWidth::Width4 => CTRL_4BITBUS,
+            BusWidth::Width8 => CTRL_8BITBUS,
+        };
+        self.modify(SDHCI_HOST_CONTROL, CTRL_4BITBUS | CTRL_8BITBUS, bits);
+        Ok(())
+    }
+
+    fn supports_timing(&self, timing: BusTiming) -> bool {
+        let uhs = self.version >= SPEC_300 && !self.quirks.no_1_8v;
+        match timing {
+            BusTiming::Default => true,
+            BusTiming::HighSpeed => self.caps & CAN_DO_HISPD != 0,
+            BusTiming::Sdr50 => uhs && self.caps1 & (CAN_DO_SDR50 | CAN_DO_SDR104) != 0,
+            BusTiming::Sdr104 => uhs && self.caps1 & CAN_DO_SDR104 != 0,
+        }
+    }
+
+    fn set_timing(&mut self, timing: BusTiming) -> DevResult {
+        if !self.supports_timing(timing) {
+            return Err(DevError::Unsupported);
+        }
+        let signal_1v8 = self.read(SDHCI_HOST_CONTROL2) & CTRL2_VDD_180 != 0;
+        let uhs_mode = match timing {
+            BusTiming::Default => 0,
+            BusTiming::HighSpeed if signal_1v8 => CTRL2_UHS_SDR25,
+            BusTiming::HighSpeed => 0,
+            BusTiming::Sdr50 => CTRL2_UHS_SDR50,
+            BusTiming::Sdr104 => CTRL2_UHS_SDR104,
+        };
+        self.set_card_clock(false);
+        if !self.quirks.no_hispd_bit {
+            let (clear, set) = if timing == BusTiming::Default {
+                (CTRL_HISPD, 0)
+            } else {
+                (0, CTRL_HISPD)
+            };
+            self.modify(SDHCI_HOST_CONTROL, clear, set);
+        }
+        if self.version >= SPEC_300 {
+            self.modify(SDHCI_HOST_CONTROL2, CTRL2_UHS_MASK, uhs_mode);
+        }
+        self.set_card_clock(true);
+        self.timing = timing;
+        Ok(())
+    }
+
+    fn switch_signal_voltage(&mut self) -> DevResult {
+        if !self.supports_timing(BusTiming::Sdr50) {
+            return Err(DevError::Unsupported);
+        }
+        // The card drives DAT[3:0] low until the clock is stopped.
+        self.set_card_clock(false);
+        if self.read(SDHCI_PRESENT_STATE) & PRESENT_DAT_LEVEL != 0 {
+            log::warn!("{}: card did not start the voltage switch", self.name);
+            return Err(DevError::Io);
+        }
+        self.modify(SDHCI_HOST_CONTROL2, 0, CTRL2_VDD_180);
+        // The host clears the bit if the regulator fails.
+        H::delay_ns(VOLTAGE_SETTLE_NS);
+        if self.read(SDHCI_HOST_CONTROL2) & CTRL2_VDD_180 == 0 {
+            log::warn!("{}: 1.8V signaling not stable", self.name);
+            return Err(DevError::Io);
+        }
+        self.set_card_clock(true);
+        for _ in 0..=DAT_RELEASE_NS / DAT_POLL_NS {
+            if self.read(SDHCI_PRESENT_STATE) & PRESENT_DAT_LEVEL == PRESENT_DAT_LEVEL {
+                return Ok(());
+            }
+            H::delay_ns(DAT_POLL_NS);
+        }
+        log::warn!("{}: voltage switch failed", self.name);
+        Err(DevError::Io)
+    }
+
+    fn execute_tuning(&mut self, index: u8) -> DevResult {
+        if self.timing == BusTiming::Sdr50 && self.caps1 & TUNING_SDR50 == 0 {
+            return Ok(());
+        }
+        self.modify(SDHCI_HOST_CONTROL2, CTRL2_TUNED_CLK, CTRL2_EXEC_TUNING);
+        // The controller checks the tuning block itself and signals each one
+        // as ready in the buffer, without it being read out.
+        let command =
+            (index as u32) << 24 | CMD_RESP_SHORT | CMD_CRC | CMD_INDEX | CMD_DATA | TRANSFER_READ;
+        for _ in 0..TUNING_LOOPS {
+            self.wait(SDHCI_PRESENT_STATE, PRESENT_CMD_INHIBIT, false)?;
+            self.ack_int(INT_TRANSFER);
+            self.write(SDHCI_BLOCK, TUNING_BLOCK_SIZE | 1 << 16);
+            self.write(SDHCI_ARGUMENT, 0);
+            self.write(SDHCI_COMMAND, command);
+            self.wait_int(INT_DATA_AVAIL)?;
+            if self.read(SDHCI_HOST_CONTROL2) & CTRL2_EXEC_TUNING == 0 {
+                break;
+            }
+        }
+        let ctrl2 = self.read(SDHCI_HOST_CONTROL2);
+        if ctrl2 & (CTRL2_EXEC_TUNING | CTRL2_TUNED_CLK) != CTRL2_TUNED_CLK {
+            self.modify(SDHCI_HOST_CONTROL2, CTRL2_EXEC_TUNING | CTRL2_TUNED_CLK, 0);
+            log::warn!("{}: tuning failed", self.name);
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    fn send_command(&mut self, cmd: &MmcCommand, data: Option<MmcData>) -> DevResult<Response> {
+        let mut inhibit = PRESENT_CMD_INHIBIT;
+        if data.is_some() || cmd.resp == ResponseType::R1b {
+            inhibit |= PRESENT_DATA_INHIBIT;
+        }
+        self.wait(SDHCI_PRESENT_STATE, inhibit, false)?;
+        self.ack_int(INT_TRANSFER);
+
+        let (mut dma, mut bounced) = (false, false);
+        let mut command = (cmd.index as u32) << 24;
+        command |= match cmd.resp {
+            ResponseType::None => 0,
+            ResponseType::R1 => CMD_RESP_SHORT | CMD_CRC | CMD_INDEX,
+            ResponseType::R1b => CMD_RESP_SHORT_BUSY | CMD_CRC | CMD_INDEX,
+            ResponseType::R2 => CMD_RESP_LONG | CMD_CRC,
+            ResponseType::R3 => CMD_RESP_SHORT,
+        };
+        if let Some(data) = &data {
+            let len = match &data.buf {
+                DataBuf::Read(buf) => buf.len(),
+                DataBuf::Write(buf) => buf.len(),
+            };
+            if data.block_size > 2048 || len < data.block_size * data.blocks {
+                return Err(DevError::InvalidParam);
+            }
+            command |= CMD_DATA | TRANSFER_BLK_CNT_EN;
+            if matches!(data.buf, DataBuf::Read(_)) {
+                command |= TRANSFER_READ;
+            }
+            if data.blocks > 1 {
+                command |= TRANSFER_MULTI | TRANSFER_AUTO_CMD12;
+            }
+            (dma, bounced) = self.setup_dma(&data.buf, data.block_size * data.blocks);
+            if dma {
+                let table = self.adma.as_ref().unwrap().paddr();
+                self.write(SDHCI_ADMA_ADDRESS, table as u32);
+                self.modify(SDHCI_HOST_CONTROL, CTRL_DMA_MASK, CTRL_ADMA32);
+                command |= TRANSFER_DMA;
+            }
+            self.write(
+                SDHCI_BLOCK,
+                data.block_size as u32 | (data.blocks as u32) << 16,
+            );
+        }
+        self.write(SDHCI_ARGUMENT, cmd.arg);
+        // The transfer mode is in the low half of the command register, so
+        // both are written at once.
+        self.write(SDHCI_COMMAND, command);
+
+        self.wait_int(INT_CMD_COMPLETE)?;
+        let resp = self.read_response(cmd.resp);
+        match data {
+            Some(data) if dma => {
+                self.wait_int(INT_XFER_COMPLETE)?;
+                if let (true, DataBuf::Read(buf)) = (bounced, data.buf) {
+                    let len = data.block_size * data.blocks;
+                    let bounce = self.bounce.as_ref().unwrap();
+                    fence(Ordering::SeqCst);
+                    buf[..len].copy_from_slice(&bounce.as_slice()[..len]);
+                }
+            }
+            Some(data) => self.transfer_pio(data)?,
+            None if cmd.resp == ResponseType::R1b => self.wait_int(INT_XFER_COMPLETE)?,
+            None => {}
+        }
+        if self.int_status() & INT_ERROR_MASK != 0 {
+            return Err(DevError::Io);
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,176 @@
+//! VirtIO over MMIO transport (VirtIO 1.1, section 4.2).
+//!
+//! Both the legacy (version 1) and the modern (version 2) register layouts
+//! are supported, since QEMU still defaults to the legacy one.
+
+use core::ptr::NonNull;
+
+use super::Transport;
+use crate::dma::{PhysAddr, PAGE_SIZE};
+use driver_common::{DevError, DevResult};
+
+const MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_LEGACY_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_LEGACY_QUEUE_ALIGN: usize = 0x03c;
+const REG_LEGACY_QUEUE_PFN: usize = 0x040;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_CONFIG: usize = 0x100;
+
+/// VirtIO MMIO transport.
+pub struct MmioTransport {
+    base: NonNull<u8>,
+    version: u32,
+}
+
+unsafe impl Send for MmioTransport {}
+unsafe impl Sync for MmioTransport {}
+
+impl MmioTransport {
+    /// Probes the VirtIO MMIO register block at `base`.
+    ///
+    /// Returns `Err(DevError::Unsupported)` if `base` does not look like a
+    /// VirtIO MMIO device, and `Err(DevError::BadState)` if the slot is valid
+    /// but has no device behind it (device ID 0), as QEMU reports for unused
+    /// `virtio-mmio` slots.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a mapped VirtIO MMIO register block of at least
+    /// 0x200 bytes.
+    pub unsafe fn new(base: NonNull<u8>) -> DevResult<Self> {
+        let transport = Self { base, version: 0 };
+        if transport.read(REG_MAGIC) != MAGIC_VALUE {
+            return Err(DevError::Unsupported);
+        }
+        let version = transport.read(REG_VERSION);
+        if version != 1 && version != 2 {
+            return Err(DevError::Unsupported);
+        }
+        if transport.read(REG_DEVICE_ID) == 0 {
+            return Err(DevError::BadState);
+        }
+        Ok(Self { base, version })
+    }
+
+    /// The version of the MMIO interface (1 for legacy, 2 for modern).
+    pub const fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { (self.base.as_ptr().add(offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&mut self, offset: usize, val: u32) {
+        unsafe { (self.base.as_ptr().add(offset) as *mut u32).write_volatile(val) }
+    }
+}
+
+impl Transport for MmioTransport {
+    fn device_id(&self) -> u32 {
+        self.read(REG_DEVICE_ID)
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.write(REG_DEVICE_FEATURES_SEL, 0);
+        let low = self.read(REG_DEVICE_FEATURES) as u64;
+        self.write(REG_DEVICE_FEATURES_SEL, 1);
+        let high = self.read(REG_DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    fn write_driver_features(&mut self, features: u64) {
+        self.write(REG_DRIVER_FEATURES_SEL, 0);
+        self.write(REG_DRIVER_FEATURES, features as u32);
+        self.write(REG_DRIVER_FEATURES_SEL, 1);
+        self.write(REG_DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.read(REG_STATUS) as u8
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.write(REG_STATUS, status as u32);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.write(REG_QUEUE_SEL, queue as u32);
+        self.read(REG_QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> DevResult {
+        self.write(REG_QUEUE_SEL, queue as u32);
+        self.write(REG_QUEUE_NUM, size as u32);
+        if self.is_legacy() {
+            if desc % PAGE_SIZE != 0 {
+                return Err(DevError::InvalidParam);
+            }
+            self.write(REG_LEGACY_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            self.write(REG_LEGACY_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(REG_LEGACY_QUEUE_PFN, (desc / PAGE_SIZE) as u32);
+        } else {
+            let (desc, avail, used) = (desc as u64, avail as u64, used as u64);
+            self.write(REG_QUEUE_DESC_LOW, desc as u32);
+            self.write(REG_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(REG_QUEUE_DRIVER_LOW, avail as u32);
+            self.write(REG_QUEUE_DRIVER_HIGH, (avail >> 32) as u32);
+            self.write(REG_QUEUE_DEVICE_LOW, used as u32);
+            self.write(REG_QUEUE_DEVICE_HIGH, (used >> 32) as u32);
+            self.write(REG_QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.write(REG_QUEUE_NOTIFY, queue as u32);
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(REG_INTERRUPT_ACK, status);
+        }
+        status != 0
+    }
+
+    fn read_config_u8(&self, offset: usize) -> u8 {
+        unsafe { self.base.as_ptr().add(REG_CONFIG + offset).read_volatile() }
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        self.read(REG_CONFIG + offset)
+    }
+}
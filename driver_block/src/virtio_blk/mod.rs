@@ -0,0 +1,571 @@
+//! VirtIO block device driver (VirtIO 1.1, section 5.2).
+
+mod mmio;
+#[cfg(feature = "pci")]
+mod pci;
+mod queue;
+
+pub use self::mmio::MmioTransport;
+#[cfg(feature = "pci")]
+pub use self::pci::{PciTransport, NO_VECTOR};
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+use self::queue::{QueueBuffer, VirtQueue};
+use crate::dma::{DmaBuffer, DmaHal, PhysAddr, PAGE_SIZE};
+use crate::{BlockCapabilities, BlockDriverOps};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// The sector size of VirtIO block devices, all offsets are in this unit.
+const SECTOR_SIZE: usize = 512;
+/// The maximum number of descriptors we use for the request queue.
+const QUEUE_SIZE: u16 = 16;
+/// The maximum number of data buffers in one request, besides its header and
+/// status descriptors.
+const MAX_SEGMENTS: usize = QUEUE_SIZE as usize - 2;
+/// The index of the only request queue.
+const REQUEST_QUEUE: u16 = 0;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+const F_SIZE_MAX: u64 = 1 << 1;
+const F_SEG_MAX: u64 = 1 << 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+const F_TOPOLOGY: u64 = 1 << 10;
+const F_WRITE_ZEROES: u64 = 1 << 14;
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Features understood by this driver.
+const SUPPORTED_FEATURES: u64 =
+    F_SIZE_MAX | F_SEG_MAX | F_RO | F_FLUSH | F_TOPOLOGY | F_WRITE_ZEROES;
+
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SIZE_MAX: usize = 8;
+const CONFIG_SEG_MAX: usize = 12;
+/// The physical block exponent, the alignment offset and the minimum I/O
+/// size, then the optimal I/O size in the next word.
+const CONFIG_TOPOLOGY: usize = 24;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+const CONFIG_WRITE_ZEROES_MAY_UNMAP: usize = 56;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_WRITE_ZEROES: u32 = 13;
+
+/// Unmap the sectors of a write zeroes segment.
+const WRITE_ZEROES_UNMAP: u32 = 1 << 0;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// The header of every block request.
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// The data of a write zeroes request.
+#[repr(C)]
+struct BlkWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+/// The offset of the status byte in the request DMA buffer.
+const REQ_STATUS_OFFSET: usize = size_of::<BlkReqHeader>();
+/// The offset of the write zeroes segment in the request DMA buffer.
+const REQ_SEGMENT_OFFSET: usize = REQ_STATUS_OFFSET + 8;
+
+/// The VirtIO transport a device is attached to.
+///
+/// It hides the register layout differences between MMIO and PCI.
+pub trait Transport {
+    /// The VirtIO device ID.
+    fn device_id(&self) -> u32;
+    /// Whether the device only speaks the legacy (pre-1.0) interface.
+    fn is_legacy(&self) -> bool;
+    /// Reads the 64-bit feature bits offered by the device.
+    fn read_device_features(&mut self) -> u64;
+    /// Writes the feature bits accepted by the driver.
+    fn write_driver_features(&mut self, features: u64);
+    /// Reads the device status.
+    fn status(&self) -> u8;
+    /// Writes the device status, writing 0 resets the device.
+    fn set_status(&mut self, status: u8);
+    /// The maximum size of the given queue, 0 if it is not available.
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+    /// Configures the given queue with the physical addresses of its rings.
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> DevResult;
+    /// Notifies the device that the given queue has new buffers.
+    fn notify(&mut self, queue: u16);
+    /// Acknowledges a pending interrupt, returns `false` if there is none.
+    fn ack_interrupt(&mut self) -> bool;
+    /// Reads a byte from the device-specific configuration space.
+    fn read_config_u8(&self, offset: usize) -> u8;
+    /// Reads a 32-bit word from the device-specific configuration space.
+    fn read_config_u32(&self, offset: usize) -> u32;
+}
+
+/// VirtIO block device driver.
+pub struct VirtIoBlkDev<H: DmaHal, T: Transport> {
+    name: String,
+    transport: T,
+    queue: VirtQueue<H>,
+    req: DmaBuffer<H>,
+    features: u64,
+    capacity: u64,
+    max_transfer: usize,
+    /// The maximum number of data buffers in one request.
+    max_segments: usize,
+    /// The maximum number of sectors of a write zeroes request, 0 if the
+    /// device has no such request.
+    max_write_zeroes: u64,
+    write_zeroes_may_unmap: bool,
+    caps: BlockCapabilities,
+}
+
+unsafe impl<H: DmaHal, T: Transport> Send for VirtIoBlkDev<H, T> {}
+unsafe impl<H: DmaHal, T: Transport> Sync for VirtIoBlkDev<H, T> {}
+
+impl<H: DmaHal> VirtIoBlkDev<H, MmioTransport> {
+    /// Scans `count` VirtIO MMIO slots starting at `base`, each `stride` bytes
+    /// apart, and initializes the first block device found.
+    ///
+    /// On `riscv64-qemu-virt` there are 8 slots of 0x1000 bytes starting at
+    /// physical address `0x1000_1000`.
+    ///
+    /// # Safety
+    ///
+    /// All slots must be mapped at `base` with device memory attributes.
+    pub unsafe fn probe_mmio(base: NonNull<u8>, stride: usize, count: usize) -> DevResult<Self> {
+        for i in 0..count {
+            let slot = NonNull::new_unchecked(base.as_ptr().add(i * stride));
+            match MmioTransport::new(slot) {
+                Ok(transport) if transport.device_id() == VIRTIO_ID_BLOCK => {
+                    return Self::try_new(transport);
+                }
+                _ => continue,
+            }
+        }
+        Err(DevError::Unsupported)
+    }
+}
+
+#[cfg(feature = "pci")]
+impl<H: DmaHal, C: crate::pci::PciConfigSpace> VirtIoBlkDev<H, PciTransport<H, C>> {
+    /// Initializes the VirtIO block device behind a PCI transport.
+    ///
+    /// Unlike [`VirtIoBlkDev::try_new`], the device is named after its PCI
+    /// address, e.g. `virtio-blk-0000:00:04.0`, to tell multiple disks apart.
+    pub fn try_new_pci(transport: PciTransport<H, C>) -> DevResult<Self> {
+        let name = alloc::format!("virtio-blk-{}", transport.bdf());
+        let mut dev = Self::try_new(transport)?;
+        dev.name = name;
+        Ok(dev)
+    }
+}
+
+impl<H: DmaHal, T: Transport> VirtIoBlkDev<H, T> {
+    /// Initializes the block device behind `transport`, returns `Ok` if
+    /// successful.
+    pub fn try_new(mut transport: T) -> DevResult<Self> {
+        if transport.device_id() != VIRTIO_ID_BLOCK {
+            return Err(DevError::Unsupported);
+        }
+        match Self::init(&mut transport) {
+            Ok((queue, req, features)) => {
+                let capacity = transport.read_config_u32(CONFIG_CAPACITY) as u64
+                    | (transport.read_config_u32(CONFIG_CAPACITY + 4) as u64) << 32;
+                let max_transfer = if features & F_SIZE_MAX != 0 {
+                    let size_max = transport.read_config_u32(CONFIG_SIZE_MAX) as usize;
+                    (size_max / SECTOR_SIZE * SECTOR_SIZE).max(SECTOR_SIZE)
+                } else {
+                    u32::MAX as usize / SECTOR_SIZE * SECTOR_SIZE
+                };
+                let seg_max = if features & F_SEG_MAX != 0 {
+                    transport.read_config_u32(CONFIG_SEG_MAX) as usize
+                } else {
+                    MAX_SEGMENTS
+                };
+                let max_segments = seg_max
+                    .min(queue.size().saturating_sub(2) as usize)
+                    .clamp(1, MAX_SEGMENTS);
+                let (max_write_zeroes, write_zeroes_may_unmap) = if features & F_WRITE_ZEROES != 0 {
+                    (
+                        transport.read_config_u32(CONFIG_MAX_WRITE_ZEROES_SECTORS) as u64,
+                        transport.read_config_u8(CONFIG_WRITE_ZEROES_MAY_UNMAP) != 0,
+                    )
+                } else {
+                    (0, false)
+                };
+                let mut caps = BlockCapabilities {
+                    max_transfer,
+                    max_segments,
+                    read_only: features & F_RO != 0,
+                    write_cache: features & F_FLUSH != 0,
+                    // A header, data and status descriptor per request.
+                    queue_depth: (queue.size() as usize / 3).max(1),
+                    ..BlockCapabilities::new(capacity, SECTOR_SIZE)
+                };
+                if features & F_TOPOLOGY != 0 {
+                    let topology = transport.read_config_u32(CONFIG_TOPOLOGY);
+                    caps.physical_block_size = SECTOR_SIZE << (topology & 0xf);
+                    caps.alignment_offset = ((topology >> 8) & 0xff) as usize * SECTOR_SIZE;
+                    caps.min_io_size = ((topology >> 16) as usize).max(1) * SECTOR_SIZE;
+                    caps.optimal_io_size =
+                        transport.read_config_u32(CONFIG_TOPOLOGY + 4) as usize * SECTOR_SIZE;
+                }
+                log::info!(
+                    "virtio-blk: {} sectors, features {:#x}, legacy={}",
+                    capacity,
+                    features,
+                    transport.is_legacy()
+                );
+                Ok(Self {
+                    name: String::from("virtio-blk"),
+                    transport,
+                    queue,
+                    req,
+                    features,
+                    capacity,
+                    max_transfer,
+                    max_segments,
+                    max_write_zeroes,
+                    write_zeroes_may_unmap,
+                    caps,
+                })
+            }
+            Err(e) => {
+                log::warn!("virtio-blk: init failed: {:?}", e);
+                transport.set_status(STATUS_FAILED);
+                Err(e)
+            }
+        }
+    }
+
+    fn init(transport: &mut T) -> DevResult<(VirtQueue<H>, DmaBuffer<H>, u64)> {
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut supported = SUPPORTED_FEATURES;
+        if !transport.is_legacy() {
+            supported |= F_VERSION_1;
+        }
+        let features = transport.read_device_features() & supported;
+        if !transport.is_legacy() && features & F_VERSION_1 == 0 {
+            return Err(DevError::Unsupported);
+        }
+        transport.write_driver_features(features);
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        if !transport.is_legacy() {
+            status |= STATUS_FEATURES_OK;
+            transport.set_status(status);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                return Err(DevError::Unsupported);
+            }
+        }
+
+        let max_size = transport.max_queue_size(REQUEST_QUEUE);
+        if max_size == 0 {
+            return Err(DevError::BadState);
+        }
+        // Legacy devices may offer any size, but the queue needs a power of 2.
+        let size = QUEUE_SIZE.min(1 << max_size.ilog2());
+        let queue = VirtQueue::new(size)?;
+        transport.setup_queue(
+            REQUEST_QUEUE,
+            queue.size(),
+            queue.desc_paddr(),
+            queue.avail_paddr(),
+            queue.used_paddr(),
+        )?;
+        let req = DmaBuffer::new(REQ_SEGMENT_OFFSET + size_of::<BlkWriteZeroes>())?;
+
+        transport.set_status(status | STATUS_DRIVER_OK);
+        Ok((queue, req, features))
+    }
+
+    /// Whether the device is read-only.
+    pub const fn readonly(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    /// Acknowledges the interrupt of the device, returns `false` if there is
+    /// no pending interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    fn check_range(&self, block_id: u64, len: usize) -> DevResult {
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        match block_id.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(DevError::Io),
+        }
+    }
+
+    /// Zeroes sectors with write zeroes requests, whose only data is the
+    /// range in the request buffer.
+    fn write_zeroes_native(&mut self, start: u64, count: u64, unmap: bool) -> DevResult {
+        if count == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let end = match start.checked_add(count) {
+            Some(end) if end <= self.capacity => end,
+            _ => return Err(DevError::Io),
+        };
+        let flags = if unmap { WRITE_ZEROES_UNMAP } else { 0 };
+        let mut sector = start;
+        while sector < end {
+            let num_sectors = (end - sector).min(self.max_write_zeroes);
+            let segment = BlkWriteZeroes {
+                sector,
+                num_sectors: num_sectors as u32,
+                flags,
+            };
+            unsafe {
+                (self.req.as_ptr().add(REQ_SEGMENT_OFFSET) as *mut BlkWriteZeroes)
+                    .write_volatile(segment);
+            }
+            let data = QueueBuffer {
+                paddr: self.req.paddr() + REQ_SEGMENT_OFFSET,
+                len: size_of::<BlkWriteZeroes>() as u32,
+                device_writable: false,
+            };
+            self.request(T_WRITE_ZEROES, 0, &[data])?;
+            sector += num_sectors;
+        }
+        Ok(())
+    }
+
+    /// Transfers the buffers of a vectored request, given as `(vaddr, len)`,
+    /// chaining as many of them as the device accepts into each request.
+    ///
+    /// Buffers are only virtually contiguous, so each one is split into one
+    /// descriptor per page it spans.
+    fn transfer_vectored(
+        &mut self,
+        req_type: u32,
+        block_id: u64,
+        bufs: impl Iterator<Item = (usize, usize)>,
+    ) -> DevResult {
+        let device_writable = req_type == T_IN;
+        let mut chain = [QueueBuffer {
+            paddr: 0,
+            len: 0,
+            device_writable,
+        }; MAX_SEGMENTS];
+        let mut count = 0;
+        let mut bytes = 0;
+        let mut sector = block_id;
+        for (vaddr, len) in bufs {
+            let mut offset = 0;
+            while offset < len {
+                let addr = vaddr + offset;
+                let chunk = (len - offset)
+                    .min(PAGE_SIZE - addr % PAGE_SIZE)
+                    .min(self.max_transfer - bytes);
+                chain[count].paddr = H::virt_to_phys(addr);
+                chain[count].len = chunk as u32;
+                count += 1;
+                bytes += chunk;
+                offset += chunk;
+                if count == self.max_segments || bytes == self.max_transfer {
+                    let done = bytes / SECTOR_SIZE * SECTOR_SIZE;
+                    count = self.request_prefix(req_type, sector, &mut chain[..count], done)?;
+                    sector += (done / SECTOR_SIZE) as u64;
+                    bytes -= done;
+                }
+            }
+        }
+        if count > 0 {
+            self.request(req_type, sector, &chain[..count])?;
+        }
+        Ok(())
+    }
+
+    /// Submits the first `bytes` bytes of the `chain` buffers, a multiple of
+    /// the sector size, then moves the remaining bytes to the front of
+    /// `chain` and returns the number of buffers they span.
+    fn request_prefix(
+        &mut self,
+        req_type: u32,
+        sector: u64,
+        chain: &mut [QueueBuffer],
+        bytes: usize,
+    ) -> DevResult<usize> {
+        if bytes == 0 {
+            // Too many tiny buffers to fill a single sector.
+            return Err(DevError::InvalidParam);
+        }
+        // Find the buffer where the prefix ends.
+        let mut end = 0;
+        let mut split = chain.len();
+        for (i, buf) in chain.iter().enumerate() {
+            if end + buf.len as usize > bytes {
+                split = i;
+                break;
+            }
+            end += buf.len as usize;
+        }
+        if split == chain.len() {
+            self.request(req_type, sector, chain)?;
+            return Ok(0);
+        }
+        let head = bytes - end;
+        let mut tail = chain[split];
+        tail.paddr += head;
+        tail.len -= head as u32;
+        chain[split].len = head as u32;
+        let sent = if head > 0 { split + 1 } else { split };
+        self.request(req_type, sector, &chain[..sent])?;
+        chain[split] = tail;
+        chain.copy_within(split.., 0);
+        Ok(chain.len() - split)
+    }
+
+    /// Submits one request with the `data` buffers, at most
+    /// `MAX_SEGMENTS`, and busy-waits for its completion.
+    fn request(&mut self, req_type: u32, sector: u64, data: &[QueueBuffer]) -> DevResult {
+        let header = BlkReqHeader {
+            req_type,
+            reserved: 0,
+            sector,
+        };
+        let req = self.req.as_ptr();
+        unsafe {
+            (req as *mut BlkReqHeader).write_volatile(header);
+            req.add(REQ_STATUS_OFFSET).write_volatile(0xff);
+        }
+        let header_buf = QueueBuffer {
+            paddr: self.req.paddr(),
+            len: size_of::<BlkReqHeader>() as u32,
+            device_writable: false,
+        };
+        let status_buf = QueueBuffer {
+            paddr: self.req.paddr() + REQ_STATUS_OFFSET,
+            len: 1,
+            device_writable: true,
+        };
+        let mut chain = [header_buf; MAX_SEGMENTS + 2];
+        chain[1..=data.len()].copy_from_slice(data);
+        chain[data.len() + 1] = status_buf;
+        self.queue.add(&chain[..data.len() + 2])?;
+        self.transport.notify(REQUEST_QUEUE);
+        while !self.queue.can_pop() {
+            core::hint::spin_loop();
+        }
+        self.queue.pop_used();
+        self.transport.ack_interrupt();
+
+        match unsafe { req.add(REQ_STATUS_OFFSET).read_volatile() } {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(DevError::Unsupported),
+            S_IOERR => Err(DevError::Io),
+            _ => Err(DevError::BadState),
+        }
+    }
+}
+
+impl<H: DmaHal, T: Transport> BaseDriverOps for VirtIoBlkDev<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<H: DmaHal, T: Transport> BlockDriverOps for VirtIoBlkDev<H, T> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.check_range(block_id, buf.len())?;
+        let buf = (buf.as_mut_ptr() as usize, buf.len());
+        self.transfer_vectored(T_IN, block_id, core::iter::once(buf))
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        if self.readonly() {
+            return Err(DevError::Unsupported);
+        }
+        self.check_range(block_id, buf.len())?;
+        let buf = (buf.as_ptr() as usize, buf.len());
+        self.transfer_vectored(T_OUT, block_id, core::iter::once(buf))
+    }
+
+    fn flush(&mut self) -> DevResult {
+        if self.features & F_FLUSH == 0 {
+            // Without VIRTIO_BLK_F_FLUSH the device is write-through.
+            return Ok(());
+        }
+        self.request(T_FLUSH, 0, &[])
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        crate::vectored_blocks(self, block_id, bufs.iter().map(|buf| buf.len()))?;
+        let bufs = bufs
+            .iter_mut()
+            .map(|buf| (buf.as_mut_ptr() as usize, buf.len()));
+        self.transfer_vectored(T_IN, block_id, bufs)
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        if self.readonly() {
+            return Err(DevError::Unsupported);
+        }
+        crate::vectored_blocks(self, block_id, bufs.iter().map(|buf| buf.len()))?;
+        let bufs = bufs.iter().map(|buf| (buf.as_ptr() as usize, buf.len()));
+        self.transfer_vectored(T_OUT, block_id, bufs)
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.caps
+    }
+
+    fn write_zeroes(&mut self, start: u64, count: u64, may_unmap: bool) -> DevResult {
+        if self.readonly() {
+            return Err(DevError::Unsupported);
+        }
+        if self.max_write_zeroes == 0 {
+            return crate::write_zeroes_fallback(self, start, count);
+        }
+        let unmap = may_unmap && self.write_zeroes_may_unmap;
+        self.write_zeroes_native(start, count, unmap)
+    }
+}
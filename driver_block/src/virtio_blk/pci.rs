@@ -0,0 +1,305 @@
+//! VirtIO over PCI transport (VirtIO 1.1, section 4.1).
+//!
+//! Only the modern interface is supported, which transitional devices (e.g.
+//! QEMU `virtio-blk-pci`) expose as well.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use super::Transport;
+use crate::dma::{DmaHal, PhysAddr};
+use crate::pci::{PciBdf, PciConfigSpace, PciDevice};
+use driver_common::{DevError, DevResult};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+const TRANSITIONAL_DEVICE_ID_BASE: u16 = 0x1000;
+const REG_SUBSYSTEM_ID: u16 = 0x2e;
+
+/// The vendor-specific capability ID used by VirtIO structures.
+const CAP_ID_VENDOR: u8 = 0x09;
+/// MSI-X capability ID.
+const CAP_ID_MSIX: u8 = 0x11;
+
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_OFF_MULTIPLIER: u16 = 16;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// The MSI-X vector value that disables MSI-X for an event source.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// A register block located in one of the BARs.
+#[derive(Clone, Copy)]
+struct Region {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Region {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        debug_assert!(offset + core::mem::size_of::<T>() <= self.len);
+        unsafe { (self.ptr.as_ptr().add(offset) as *const T).read_volatile() }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, val: T) {
+        debug_assert!(offset + core::mem::size_of::<T>() <= self.len);
+        unsafe { (self.ptr.as_ptr().add(offset) as *mut T).write_volatile(val) }
+    }
+}
+
+/// VirtIO PCI transport.
+///
+/// MSI-X is optional: by default no vectors are assigned and the device
+/// raises INTx interrupts, which are acknowledged through the ISR status.
+pub struct PciTransport<H: DmaHal, C: PciConfigSpace> {
+    pci: PciDevice<C>,
+    device_id: u32,
+    common: Region,
+    notify: Region,
+    notify_off_multiplier: u32,
+    isr: Region,
+    device: Region,
+    msix: bool,
+    config_vector: u16,
+    queue_vector: u16,
+    _hal: PhantomData<H>,
+}
+
+unsafe impl<H: DmaHal, C: PciConfigSpace> Send for PciTransport<H, C> {}
+unsafe impl<H: DmaHal, C: PciConfigSpace> Sync for PciTransport<H, C> {}
+
+impl<H: DmaHal, C: PciConfigSpace> PciTransport<H, C> {
+    /// Locates the VirtIO structures of `pci` and enables the function.
+    ///
+    /// The BARs must have been assigned by the kernel or firmware.
+    pub fn new(mut pci: PciDevice<C>) -> DevResult<Self> {
+        if pci.vendor_id() != VIRTIO_VENDOR_ID {
+            return Err(DevError::Unsupported);
+        }
+        let device_id = match pci.device_id() {
+            id if (MODERN_DEVICE_ID_BASE..MODERN_DEVICE_ID_BASE + 0x40).contains(&id) => {
+                (id - MODERN_DEVICE_ID_BASE) as u32
+            }
+            // Transitional devices report the device type as subsystem ID.
+            id if (TRANSITIONAL_DEVICE_ID_BASE..TRANSITIONAL_DEVICE_ID_BASE + 0x40)
+                .contains(&id) =>
+            {
+                pci.read_u16(REG_SUBSYSTEM_ID) as u32
+            }
+            _ => return Err(DevError::Unsupported),
+        };
+
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        let mut notify_off_multiplier = 0;
+        let mut msix = false;
+
+        let caps: Vec<(u16, u8)> = pci.capabilities().collect();
+        for (offset, id) in caps {
+            if id == CAP_ID_MSIX {
+                msix = pci.read_u16(offset + 2) & (1 << 15) != 0;
+                continue;
+            }
+            if id != CAP_ID_VENDOR {
+                continue;
+            }
+            let cfg_type = pci.read_u8(offset + CAP_CFG_TYPE);
+            let bar = pci.read_u8(offset + CAP_BAR);
+            let cap_offset = pci.read_u32(offset + CAP_OFFSET) as usize;
+            let cap_len = pci.read_u32(offset + CAP_LENGTH) as usize;
+            let slot = match cfg_type {
+                CFG_TYPE_COMMON => &mut common,
+                CFG_TYPE_NOTIFY => {
+                    notify_off_multiplier = pci.read_u32(offset + CAP_NOTIFY_OFF_MULTIPLIER);
+                    &mut notify
+                }
+                CFG_TYPE_ISR => &mut isr,
+                CFG_TYPE_DEVICE => &mut device,
+                _ => continue,
+            };
+            // Use the first structure of each type, as the spec recommends.
+            if slot.is_none() {
+                let (paddr, size) = pci.memory_bar(bar)?;
+                if cap_offset + cap_len > size {
+                    return Err(DevError::BadState);
+                }
+                *slot = Some(Self::map(paddr + cap_offset, cap_len));
+            }
+        }
+
+        let (Some(common), Some(notify), Some(isr)) = (common, notify, isr) else {
+            log::warn!("virtio-pci {}: missing modern capabilities", pci.bdf());
+            return Err(DevError::Unsupported);
+        };
+        let device = device.unwrap_or(Region {
+            ptr: NonNull::dangling(),
+            len: 0,
+        });
+        pci.enable();
+
+        Ok(Self {
+            pci,
+            device_id,
+            common,
+            notify,
+            notify_off_multiplier,
+            isr,
+            device,
+            msix,
+            config_vector: NO_VECTOR,
+            queue_vector: NO_VECTOR,
+            _hal: PhantomData,
+        })
+    }
+
+    fn map(paddr: PhysAddr, len: usize) -> Region {
+        Region {
+            ptr: H::mmio_phys_to_virt(paddr, len),
+            len,
+        }
+    }
+
+    /// The address of the PCI function.
+    pub const fn bdf(&self) -> PciBdf {
+        self.pci.bdf()
+    }
+
+    /// Whether MSI-X has been enabled on the function by the kernel.
+    pub const fn msix_enabled(&self) -> bool {
+        self.msix
+    }
+
+    /// Assigns the MSI-X vectors used for configuration changes and for the
+    /// request queue.
+    ///
+    /// It must be called before the device is initialized, and only takes
+    /// effect when MSI-X is enabled. Use [`NO_VECTOR`] to leave an event
+    /// without a vector.
+    pub fn set_msix_vectors(&mut self, config_vector: u16, queue_vector: u16) {
+        self.config_vector = config_vector;
+        self.queue_vector = queue_vector;
+    }
+}
+
+impl<H: DmaHal, C: PciConfigSpace> Transport for PciTransport<H, C> {
+    fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn is_legacy(&self) -> bool {
+        false
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+        (high << 32) | low
+    }
+
+    fn write_driver_features(&mut self, features: u64) {
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.common.write(COMMON_DRIVER_FEATURE, features as u32);
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.common
+            .write(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.common.read(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.common.write(COMMON_DEVICE_STATUS, status);
+        if status == 0 {
+            // The reset is complete once the status reads back as 0.
+            while self.common.read::<u8>(COMMON_DEVICE_STATUS) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.common.write(COMMON_QUEUE_SELECT, queue);
+        self.common.read(COMMON_QUEUE_SIZE)
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> DevResult {
+        self.common.write(COMMON_QUEUE_SELECT, queue);
+        self.common.write(COMMON_QUEUE_SIZE, size);
+        self.common.write(COMMON_QUEUE_DESC, desc as u64);
+        self.common.write(COMMON_QUEUE_DRIVER, avail as u64);
+        self.common.write(COMMON_QUEUE_DEVICE, used as u64);
+        if self.msix {
+            self.common.write(COMMON_MSIX_CONFIG, self.config_vector);
+            self.common
+                .write(COMMON_QUEUE_MSIX_VECTOR, self.queue_vector);
+            if self.common.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) != self.queue_vector {
+                return Err(DevError::NoMemory);
+            }
+        }
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.common.write(COMMON_QUEUE_SELECT, queue);
+        let offset = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize
+            * self.notify_off_multiplier as usize;
+        self.notify.write(offset, queue);
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        // Reading the ISR status acknowledges the interrupt.
+        self.isr.read::<u8>(0) & 0x3 != 0
+    }
+
+    fn read_config_u8(&self, offset: usize) -> u8 {
+        if offset >= self.device.len {
+            return 0;
+        }
+        self.device.read(offset)
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        if offset + 4 > self.device.len {
+            return 0;
+        }
+        self.device.read(offset)
+    }
+}
@@ -0,0 +1,194 @@
+//! Split virtqueue (VirtIO 1.1, section 2.6).
+
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::dma::{DmaBuffer, DmaHal, PhysAddr, PAGE_SIZE};
+use driver_common::{DevError, DevResult};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer to be chained into a virtqueue request.
+#[derive(Clone, Copy)]
+pub(super) struct QueueBuffer {
+    pub paddr: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer (device-writable).
+    pub device_writable: bool,
+}
+
+/// A split virtqueue whose rings live in one DMA region, laid out as the
+/// legacy interface requires so that it can be used by both legacy and
+/// modern transports.
+pub(super) struct VirtQueue<H: DmaHal> {
+    dma: DmaBuffer<H>,
+    size: u16,
+    avail_offset: usize,
+    used_offset: usize,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+const fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+impl<H: DmaHal> VirtQueue<H> {
+    /// Allocates a queue with `size` descriptors, `size` must be a power of 2.
+    pub fn new(size: u16) -> DevResult<Self> {
+        if !size.is_power_of_two() {
+            return Err(DevError::InvalidParam);
+        }
+        let n = size as usize;
+        let avail_offset = size_of::<Descriptor>() * n;
+        let used_offset = align_up(avail_offset + 2 * (3 + n), PAGE_SIZE);
+        let total = used_offset + align_up(2 * 3 + size_of::<UsedElem>() * n, PAGE_SIZE);
+        let dma = DmaBuffer::new(total)?;
+        let queue = Self {
+            dma,
+            size,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size - 1 {
+            unsafe { (*queue.desc(i)).next = i + 1 };
+        }
+        Ok(queue)
+    }
+
+    pub const fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_paddr(&self) -> PhysAddr {
+        self.dma.paddr()
+    }
+
+    pub fn avail_paddr(&self) -> PhysAddr {
+        self.dma.paddr() + self.avail_offset
+    }
+
+    pub fn used_paddr(&self) -> PhysAddr {
+        self.dma.paddr() + self.used_offset
+    }
+
+    fn desc(&self, idx: u16) -> *mut Descriptor {
+        unsafe { (self.dma.as_ptr() as *mut Descriptor).add(idx as usize) }
+    }
+
+    fn avail_ring(&self) -> *mut u16 {
+        unsafe { self.dma.as_ptr().add(self.avail_offset) as *mut u16 }
+    }
+
+    fn used_ring(&self) -> *mut u16 {
+        unsafe { self.dma.as_ptr().add(self.used_offset) as *mut u16 }
+    }
+
+    /// Chains `bufs` into a descriptor list and makes it available to the
+    /// device. Returns the head descriptor index as the request token.
+    pub fn add(&mut self, bufs: &[QueueBuffer]) -> DevResult<u16> {
+        if bufs.is_empty() {
+            return Err(DevError::InvalidParam);
+        }
+        if bufs.len() > self.num_free as usize {
+            return Err(DevError::ResourceBusy);
+        }
+        let head = self.free_head;
+        for (i, buf) in bufs.iter().enumerate() {
+            let idx = self.free_head;
+            let desc = self.desc(idx);
+            unsafe {
+                self.free_head = (*desc).next;
+                (*desc).addr = buf.paddr as u64;
+                (*desc).len = buf.len;
+                (*desc).flags = if buf.device_writable { DESC_F_WRITE } else { 0 };
+                if i + 1 < bufs.len() {
+                    (*desc).flags |= DESC_F_NEXT;
+                }
+            }
+        }
+        self.num_free -= bufs.len() as u16;
+
+        let slot = (self.avail_idx & (self.size - 1)) as usize;
+        let avail = self.avail_ring();
+        unsafe {
+            avail.add(2 + slot).write_volatile(head);
+            // The ring entry must be visible before the index update.
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            avail.add(1).write_volatile(self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Whether the device has returned a used buffer that is not popped yet.
+    pub fn can_pop(&self) -> bool {
+        fence(Ordering::SeqCst);
+        let used_idx = unsafe { self.used_ring().add(1).read_volatile() };
+        used_idx != self.last_used_idx
+    }
+
+    /// Pops a used descriptor chain and recycles its descriptors.
+    ///
+    /// Returns the head index (token) and the length written by the device.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.can_pop() {
+            return None;
+        }
+        let slot = (self.last_used_idx & (self.size - 1)) as usize;
+        let elem = unsafe {
+            let ring = self.used_ring().add(2) as *const UsedElem;
+            let elem = ring.add(slot);
+            (
+                addr_of!((*elem).id).read_volatile(),
+                addr_of!((*elem).len).read_volatile(),
+            )
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        let head = elem.0 as u16;
+        self.recycle(head);
+        Some((head, elem.1))
+    }
+
+    fn recycle(&mut self, head: u16) {
+        let mut idx = head;
+        loop {
+            let desc = self.desc(idx);
+            self.num_free += 1;
+            unsafe {
+                let flags = (*desc).flags;
+                (*desc).addr = 0;
+                (*desc).len = 0;
+                if flags & DESC_F_NEXT == 0 {
+                    (*desc).next = self.free_head;
+                    break;
+                }
+                idx = (*desc).next;
+            }
+        }
+        self.free_head = head;
+    }
+}
@@ -4,11 +4,26 @@
 #[macro_use]
 extern crate axlog2;
 extern crate alloc;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
 use alloc::vec;
 
+use core::future::Future;
 use core::panic::PanicInfo;
+use core::pin::pin;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{
+    AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use driver_common::{BaseDriverOps, DeviceType};
-use driver_block::{ramdisk, BlockDriverOps};
+use driver_block::async_ops::{AsyncAdapter, AsyncBlockOps, IrqEvent};
+use driver_block::dma::{DmaHal, PhysAddr, PAGE_SIZE};
+use driver_block::ide::{IdeChannel, IdeDisk, PortIo};
+use driver_block::mmc::rpmb::{HmacSha256, Rpmb, RpmbEmulator, Sha256};
+use driver_block::mmc::MmcCard;
+use driver_block::request_queue::{BlockOp, BlockQueueOps, BlockRequest, SyncQueue};
+use driver_block::sdhci::{SdhciDriver, SdhciHost, SdhciIrq, SdhciQuirks};
+use driver_block::{ramdisk, sd_spi, BlockDriverOps};
 
 const DISK_SIZE: usize = 0x1000;    // 4K
 const BLOCK_SIZE: usize = 0x200;    // 512
@@ -44,11 +59,925 @@ pub extern "Rust" fn runtime_main(_cpu_id: usize, _dtb_pa: usize) {
     assert!(disk.read_block(block_id, &mut buf).is_ok());
     assert!(buf[0..4] == *b"0123");
 
+    assert!(disk.discard(block_id, 1).is_ok());
+    assert!(disk.read_block(block_id, &mut buf).is_ok());
+    assert!(buf.iter().all(|&b| b == 0));
+    assert!(disk.discard(disk.num_blocks(), 1).is_err());
+
+    assert!(disk.write_block(block_id, &[0xa5; BLOCK_SIZE]).is_ok());
+    assert!(disk.write_zeroes(block_id, 1, false).is_ok());
+    assert!(disk.read_block(block_id, &mut buf).is_ok());
+    assert!(buf.iter().all(|&b| b == 0));
+    assert!(disk.write_zeroes(disk.num_blocks() - 1, 2, true).is_err());
+
+    let (a, b) = (vec![0x11u8; BLOCK_SIZE], vec![0x22u8; BLOCK_SIZE * 2]);
+    assert!(disk.write_blocks_vectored(2, &[&a, &b]).is_ok());
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 3];
+    assert!(disk.read_block(2, &mut rbuf).is_ok());
+    assert!(rbuf[..BLOCK_SIZE] == a[..] && rbuf[BLOCK_SIZE..] == b[..]);
+    let (mut ra, mut rb) = (vec![0u8; BLOCK_SIZE * 2], vec![0u8; BLOCK_SIZE]);
+    assert!(disk.read_blocks_vectored(2, &mut [&mut ra, &mut rb]).is_ok());
+    assert!(ra[..] == rbuf[..BLOCK_SIZE * 2] && rb[..] == b[BLOCK_SIZE..]);
+    assert!(disk.write_blocks_vectored(2, &[&a, &a[..1]]).is_err());
+    assert!(disk.write_blocks_vectored(disk.num_blocks() - 1, &[&a, &a]).is_err());
+    assert!(disk.read_blocks_vectored(0, &mut []).is_err());
+
+    let caps = disk.capabilities();
+    assert_eq!(caps.num_blocks, disk.num_blocks());
+    assert_eq!(caps.block_size, BLOCK_SIZE);
+    assert_eq!(caps.physical_block_size, BLOCK_SIZE);
+    assert!(!caps.write_cache && !caps.removable && !caps.read_only);
+    assert_eq!(caps.discard, disk.discard_limits());
+
     info!("[rt_ramdisk]: ok!");
+
+    test_sd_spi(true);
+    test_sd_spi(false);
+    test_sd_spi_range(true);
+    test_sd_spi_range(false);
+    test_sd_spi_hotplug(true);
+    test_sd_spi_hotplug(false);
+    test_sd_spi_discard(true);
+    test_sd_spi_discard(false);
+    test_sd_spi_write_zeroes();
+    info!("[rt_sd_spi]: ok!");
+    test_rpmb();
+    info!("[rt_rpmb]: ok!");
+    test_ide(true);
+    test_ide(false);
+    info!("[rt_ide]: ok!");
+    test_sync_queue();
+    info!("[rt_request_queue]: ok!");
+    test_async();
+    info!("[rt_async]: ok!");
+    test_sdhci();
+    test_sdhci_irq();
+    info!("[rt_sdhci]: ok!");
     info!("[rt_driver_block]: ok!");
     axhal::misc::terminate();
 }
 
+fn test_sd_spi(high_capacity: bool) {
+    let card = sd_spi::MockSdCard::new(1024, high_capacity);
+    let mut disk = sd_spi::SdSpiCard::try_new(card).unwrap();
+    assert_eq!(disk.device_type(), DeviceType::Block);
+    assert_eq!(disk.high_capacity(), high_capacity);
+    assert_eq!(disk.block_size(), BLOCK_SIZE);
+    assert_eq!(disk.num_blocks(), 1024);
+
+    // The card identity of the emulated card.
+    let cid = disk.cid();
+    assert_eq!(cid.name(), "MOCK0");
+    assert_eq!(cid.date, (2024, 1));
+    assert_eq!(disk.csd().num_blocks, 1024);
+    assert!(!disk.csd().write_protected());
+    assert_eq!(disk.scr().spec_version, (3, 0));
+    assert!(disk.scr().bus_width_4);
+    info!("sd-spi card: {}", cid);
+
+    // Single and multiple block transfers.
+    let mut buf = vec![0u8; BLOCK_SIZE * 3];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert!(disk.write_block(5, &buf).is_ok());
+    assert!(disk.write_block(1023, &buf[..BLOCK_SIZE]).is_ok());
+    assert!(disk.bus().storage()[BLOCK_SIZE * 5..BLOCK_SIZE * 8] == buf[..]);
+
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 3];
+    assert!(disk.read_block(5, &mut rbuf).is_ok());
+    assert!(rbuf == buf);
+    assert!(disk.read_block(1023, &mut rbuf[..BLOCK_SIZE]).is_ok());
+    assert!(rbuf[..BLOCK_SIZE] == buf[..BLOCK_SIZE]);
+
+    assert!(disk.read_block(1022, &mut rbuf).is_err());
+    assert!(disk.write_block(0, &buf[..100]).is_err());
+}
+
+/// Requests outside the card must fail without touching any block, even if
+/// their address would wrap around to a valid one.
+fn test_sd_spi_range(high_capacity: bool) {
+    let card = sd_spi::MockSdCard::new(1024, high_capacity);
+    let mut disk = sd_spi::SdSpiCard::try_new(card).unwrap();
+    let buf = vec![0xa5u8; BLOCK_SIZE * 2];
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 2];
+
+    let bad_ranges = [
+        (1024, 1),
+        (1023, 2),
+        (u64::MAX, 1),
+        (u64::MAX - 1, 2),
+        // Wraps to block 5 as a 32-bit block address.
+        ((1 << 32) + 5, 1),
+        // Wraps to block 5 as a 32-bit byte address.
+        ((1 << 23) + 5, 1),
+    ];
+    for (block_id, count) in bad_ranges {
+        let len = BLOCK_SIZE * count;
+        assert!(disk.write_block(block_id, &buf[..len]).is_err());
+        assert!(disk.read_block(block_id, &mut rbuf[..len]).is_err());
+    }
+    assert!(disk.bus().storage().iter().all(|&b| b == 0));
+
+    // Partial blocks are rejected too.
+    assert!(disk.write_block(5, &buf[..BLOCK_SIZE + 1]).is_err());
+    assert!(disk.write_block(5, &[]).is_err());
+    assert!(disk.bus().storage().iter().all(|&b| b == 0));
+
+    // The last block is still reachable.
+    assert!(disk.write_block(1023, &buf[..BLOCK_SIZE]).is_ok());
+    assert!(disk.read_block(1023, &mut rbuf[..BLOCK_SIZE]).is_ok());
+    assert!(rbuf[..BLOCK_SIZE] == buf[..BLOCK_SIZE]);
+}
+
+/// A removed card fails I/O and changes the media generation, the card
+/// inserted afterwards is initialized again on the next request.
+fn test_sd_spi_hotplug(high_capacity: bool) {
+    let card = sd_spi::MockSdCard::new(1024, high_capacity);
+    let mut disk = sd_spi::SdSpiCard::try_new(card).unwrap();
+    let buf = vec![0x5au8; BLOCK_SIZE];
+    let mut rbuf = vec![0u8; BLOCK_SIZE];
+    assert!(disk.write_block(7, &buf).is_ok());
+    let generation = disk.media_generation();
+
+    disk.bus_mut().remove();
+    assert!(disk.read_block(7, &mut rbuf).is_err());
+    assert!(!disk.poll_media());
+    assert_eq!(disk.num_blocks(), 0);
+    assert!(disk.media_generation() != generation);
+
+    let generation = disk.media_generation();
+    disk.bus_mut().insert();
+    assert!(disk.read_block(7, &mut rbuf).is_ok());
+    assert!(rbuf == buf);
+    assert_eq!(disk.num_blocks(), 1024);
+    assert!(disk.media_generation() != generation);
+
+    // A card swapped between two requests is noticed as well.
+    let generation = disk.media_generation();
+    disk.bus_mut().remove();
+    disk.bus_mut().insert();
+    assert!(disk.read_block(7, &mut rbuf).is_err());
+    assert!(disk.read_block(7, &mut rbuf).is_ok());
+    assert!(disk.media_generation() != generation);
+}
+
+/// Discarded blocks are erased, the blocks around them are left alone. The
+/// standard capacity card erases 8-block sectors, only the whole sectors of
+/// a range are erased.
+fn test_sd_spi_discard(high_capacity: bool) {
+    let card = sd_spi::MockSdCard::new(1024, high_capacity);
+    let mut disk = sd_spi::SdSpiCard::try_new(card).unwrap();
+    let limits = disk.discard_limits().unwrap();
+    let unit = if high_capacity { 1 } else { 8 };
+    assert_eq!(limits.granularity, unit);
+    assert_eq!(limits.max_blocks, 1024);
+
+    let buf = vec![0x5au8; BLOCK_SIZE * 4];
+    assert!(disk.write_block(10, &buf).is_ok());
+    assert!(disk.discard(11, 2).is_ok());
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 4];
+    assert!(disk.read_block(10, &mut rbuf).is_ok());
+    assert!(rbuf[..BLOCK_SIZE] == buf[..BLOCK_SIZE]);
+    if high_capacity {
+        assert!(rbuf[BLOCK_SIZE..BLOCK_SIZE * 3].iter().all(|&b| b == 0));
+    } else {
+        assert!(rbuf[BLOCK_SIZE..BLOCK_SIZE * 3] == buf[BLOCK_SIZE..BLOCK_SIZE * 3]);
+    }
+    assert!(rbuf[BLOCK_SIZE * 3..] == buf[BLOCK_SIZE * 3..]);
+
+    // Blocks 16 to 23 are a whole sector of the standard capacity card.
+    let buf = vec![0x5au8; BLOCK_SIZE * 16];
+    assert!(disk.write_block(8, &buf).is_ok());
+    assert!(disk.discard(9, 15).is_ok());
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 16];
+    assert!(disk.read_block(8, &mut rbuf).is_ok());
+    let erased = if high_capacity { 1 } else { 8 };
+    assert!(rbuf[..BLOCK_SIZE * erased] == buf[..BLOCK_SIZE * erased]);
+    assert!(rbuf[BLOCK_SIZE * erased..].iter().all(|&b| b == 0));
+
+    assert!(disk.discard(1020, 5).is_err());
+    assert!(disk.discard(0, 0).is_err());
+
+    let caps = disk.capabilities();
+    assert_eq!(caps.num_blocks, 1024);
+    assert!(caps.removable && !caps.read_only && !caps.write_cache);
+    assert_eq!(caps.discard, Some(limits));
+}
+
+/// Without a native command, blocks are zeroed by writing them, in several
+/// chunks for a long range.
+fn test_sd_spi_write_zeroes() {
+    let card = sd_spi::MockSdCard::new(1024, true);
+    let mut disk = sd_spi::SdSpiCard::try_new(card).unwrap();
+    let buf = vec![0x5au8; BLOCK_SIZE * 400];
+    assert!(disk.write_block(0, &buf).is_ok());
+    assert!(disk.write_zeroes(10, 300, false).is_ok());
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 400];
+    assert!(disk.read_block(0, &mut rbuf).is_ok());
+    assert!(rbuf[..BLOCK_SIZE * 10] == buf[..BLOCK_SIZE * 10]);
+    assert!(rbuf[BLOCK_SIZE * 10..BLOCK_SIZE * 310].iter().all(|&b| b == 0));
+    assert!(rbuf[BLOCK_SIZE * 310..] == buf[BLOCK_SIZE * 310..]);
+
+    assert!(disk.write_zeroes(1000, 25, false).is_err());
+    assert!(disk.write_zeroes(0, 0, false).is_err());
+}
+
+/// Authenticated writes and reads on an emulated RPMB partition, which only
+/// succeed with the programmed key.
+/// Decodes a SHA-256 digest written in hexadecimal.
+fn digest(hex: &str) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).unwrap();
+        *byte = u8::from_str_radix(pair, 16).unwrap();
+    }
+    digest
+}
+
+fn test_rpmb() {
+    // Known answers of FIPS 180-2 and RFC 4231 (test cases 1 and 6).
+    let mut sha = Sha256::new();
+    sha.update(b"a");
+    sha.update(b"bc");
+    assert_eq!(
+        sha.finish(),
+        digest("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+    let mut hmac = HmacSha256::new(&[0x0b; 20]);
+    hmac.update(b"Hi There");
+    assert_eq!(
+        hmac.finish(),
+        digest("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")
+    );
+    let mut hmac = HmacSha256::new(&[0xaa; 131]);
+    hmac.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+    assert_eq!(
+        hmac.finish(),
+        digest("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+    );
+
+    let key = [0x42u8; 32];
+    let mut rpmb = Rpmb::new(RpmbEmulator::new(64));
+    assert!(rpmb.read_counter(&key, &[1; 16]).is_err());
+    assert!(rpmb.program_key(&key).is_ok());
+    assert!(rpmb.program_key(&key).is_err());
+    assert_eq!(rpmb.read_counter(&key, &[1; 16]).unwrap(), 0);
+
+    let data = [0x5au8; 256];
+    assert_eq!(rpmb.write(&key, 3, &data).unwrap(), 1);
+    let mut rbuf = [0u8; 256];
+    assert!(rpmb.read(&key, 3, &[2; 16], &mut rbuf).is_ok());
+    assert!(rbuf == data);
+    assert!(rpmb.write(&key, 64, &data).is_err());
+
+    // Neither writes nor responses pass with another key.
+    let bad_key = [0x24u8; 32];
+    assert!(rpmb.write(&bad_key, 3, &[0; 256]).is_err());
+    assert!(rpmb.read(&bad_key, 3, &[3; 16], &mut rbuf).is_err());
+    assert_eq!(rpmb.transport().counter(), 1);
+    assert!(rpmb.transport().data()[3] == data);
+}
+
+/// The number of sectors of the emulated ATA disk.
+static ATA_SECTORS: AtomicU64 = AtomicU64::new(0);
+/// Whether the emulated ATA disk supports LBA48.
+static ATA_LBA48: AtomicBool = AtomicBool::new(false);
+/// The task file registers of the primary channel, and their previous values
+/// (the high order bytes of LBA48 commands).
+#[allow(clippy::declare_interior_mutable_const)]
+const ATA_REG: AtomicU8 = AtomicU8::new(0);
+static ATA_REGS: [AtomicU8; 8] = [ATA_REG; 8];
+static ATA_HOB: [AtomicU8; 8] = [ATA_REG; 8];
+/// The last command issued, with its LBA and sector count.
+static ATA_COMMAND: AtomicU8 = AtomicU8::new(0);
+static ATA_LBA: AtomicU64 = AtomicU64::new(0);
+static ATA_COUNT: AtomicU64 = AtomicU64::new(0);
+/// The index of the next IDENTIFY DEVICE word to read.
+static ATA_WORD: AtomicUsize = AtomicUsize::new(0);
+
+/// An ATA disk emulated on the primary channel, which records the task
+/// file of each command, reads zeroes and drops the data written.
+struct MockAta;
+
+impl MockAta {
+    fn identify_word(index: usize) -> u16 {
+        let sectors = ATA_SECTORS.load(Ordering::SeqCst);
+        let lba28 = sectors.min((1 << 28) - 1);
+        match index {
+            49 => 1 << 9,
+            60 => lba28 as u16,
+            61 => (lba28 >> 16) as u16,
+            83 if ATA_LBA48.load(Ordering::SeqCst) => 1 << 10,
+            100..=103 => (sectors >> (16 * (index - 100))) as u16,
+            _ => 0,
+        }
+    }
+
+    fn command(cmd: u8) {
+        let reg = |i: usize| ATA_REGS[i].load(Ordering::SeqCst) as u64;
+        let hob = |i: usize| ATA_HOB[i].load(Ordering::SeqCst) as u64;
+        let (lba, count) = match cmd {
+            0x24 | 0x34 => (
+                reg(3) | reg(4) << 8 | reg(5) << 16 | hob(3) << 24 | hob(4) << 32 | hob(5) << 40,
+                match hob(2) << 8 | reg(2) {
+                    0 => 65536,
+                    count => count,
+                },
+            ),
+            _ => (
+                reg(3) | reg(4) << 8 | reg(5) << 16 | (reg(6) & 0xf) << 24,
+                match reg(2) {
+                    0 => 256,
+                    count => count,
+                },
+            ),
+        };
+        ATA_COMMAND.store(cmd, Ordering::SeqCst);
+        ATA_LBA.store(lba, Ordering::SeqCst);
+        ATA_COUNT.store(count, Ordering::SeqCst);
+        ATA_WORD.store(0, Ordering::SeqCst);
+    }
+
+    /// Emulates a disk of `sectors` sectors, and returns the driver of it.
+    fn attach(sectors: u64, lba48: bool) -> IdeDisk<MockAta> {
+        ATA_SECTORS.store(sectors, Ordering::SeqCst);
+        ATA_LBA48.store(lba48, Ordering::SeqCst);
+        IdeDisk::try_new(IdeChannel::Primary, false).unwrap()
+    }
+
+    fn last_command() -> (u8, u64, u64) {
+        (
+            ATA_COMMAND.load(Ordering::SeqCst),
+            ATA_LBA.load(Ordering::SeqCst),
+            ATA_COUNT.load(Ordering::SeqCst),
+        )
+    }
+}
+
+impl PortIo for MockAta {
+    fn inb(port: u16) -> u8 {
+        match port {
+            // Ready, with data to transfer.
+            0x1f7 | 0x3f6 => 0x58,
+            0x1f1..=0x1f6 => ATA_REGS[(port - 0x1f0) as usize].load(Ordering::SeqCst),
+            // Nothing on the secondary channel.
+            _ => 0xff,
+        }
+    }
+
+    fn outb(port: u16, val: u8) {
+        if !(0x1f1..=0x1f7).contains(&port) {
+            return;
+        }
+        let reg = (port - 0x1f0) as usize;
+        if reg == 7 {
+            Self::command(val);
+        } else {
+            let old = ATA_REGS[reg].swap(val, Ordering::SeqCst);
+            ATA_HOB[reg].store(old, Ordering::SeqCst);
+        }
+    }
+
+    fn inw(port: u16) -> u16 {
+        if port != 0x1f0 || ATA_COMMAND.load(Ordering::SeqCst) != 0xec {
+            return 0;
+        }
+        Self::identify_word(ATA_WORD.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn outw(_port: u16, _val: u16) {}
+}
+
+/// Requests below the 28-bit limit use LBA28 commands, the others LBA48
+/// ones if the disk has them. Requests outside the disk issue no command.
+fn test_ide(lba48: bool) {
+    const LBA28_LIMIT: u64 = 1 << 28;
+    let sectors = if lba48 { LBA28_LIMIT + 1024 } else { LBA28_LIMIT - 1 };
+    let mut disk = MockAta::attach(sectors, lba48);
+    assert_eq!(disk.device_name(), "ide-hda");
+    assert_eq!(disk.num_blocks(), sectors);
+    assert!(IdeDisk::<MockAta>::try_new(IdeChannel::Secondary, false).is_err());
+
+    let mut buf = vec![0u8; BLOCK_SIZE * 300];
+    assert!(disk.read_block(LBA28_LIMIT - 2, &mut buf[..BLOCK_SIZE]).is_ok());
+    assert_eq!(MockAta::last_command(), (0x20, LBA28_LIMIT - 2, 1));
+    // 256 sectors is the most of an LBA28 command, encoded as 0.
+    assert!(disk.write_block(0x12_3456, &buf[..BLOCK_SIZE * 256]).is_ok());
+    assert_eq!(MockAta::last_command(), (0x30, 0x12_3456, 256));
+
+    if lba48 {
+        // Ending past the 28-bit limit, or starting at it.
+        assert!(disk.read_block(LBA28_LIMIT - 1, &mut buf[..BLOCK_SIZE * 2]).is_ok());
+        assert_eq!(MockAta::last_command(), (0x24, LBA28_LIMIT - 1, 2));
+        assert!(disk.write_block(LBA28_LIMIT, &buf[..BLOCK_SIZE]).is_ok());
+        assert_eq!(MockAta::last_command(), (0x34, LBA28_LIMIT, 1));
+        // More than 256 sectors needs LBA48 even at a low LBA.
+        assert!(disk.read_block(5, &mut buf[..BLOCK_SIZE * 257]).is_ok());
+        assert_eq!(MockAta::last_command(), (0x24, 5, 257));
+        assert!(disk.read_block(sectors - 1, &mut buf[..BLOCK_SIZE]).is_ok());
+        assert_eq!(MockAta::last_command(), (0x24, sectors - 1, 1));
+        assert!(disk.flush().is_ok());
+        assert_eq!(MockAta::last_command().0, 0xea);
+    } else {
+        // Split into commands of at most 256 sectors.
+        assert!(disk.read_block(1000, &mut buf).is_ok());
+        assert_eq!(MockAta::last_command(), (0x20, 1256, 44));
+        assert!(disk.read_block(sectors - 1, &mut buf[..BLOCK_SIZE]).is_ok());
+        assert_eq!(MockAta::last_command(), (0x20, sectors - 1, 1));
+        assert!(disk.flush().is_ok());
+        assert_eq!(MockAta::last_command().0, 0xe7);
+    }
+
+    let last = MockAta::last_command();
+    let bad_ranges = [
+        (sectors, 1),
+        (sectors - 1, 2),
+        (u64::MAX, 1),
+        (u64::MAX - 1, 2),
+    ];
+    for (block_id, count) in bad_ranges {
+        let len = BLOCK_SIZE * count;
+        assert!(disk.read_block(block_id, &mut buf[..len]).is_err());
+        assert!(disk.write_block(block_id, &buf[..len]).is_err());
+    }
+    assert!(disk.write_block(0, &buf[..BLOCK_SIZE + 1]).is_err());
+    assert!(disk.read_block(0, &mut []).is_err());
+    assert_eq!(MockAta::last_command(), last);
+}
+
+/// Requests on a synchronous driver complete in order, and no more than the
+/// queue depth are accepted before their completions are polled.
+fn test_sync_queue() {
+    // A RAM disk takes one request at a time.
+    let queue = SyncQueue::new(ramdisk::RamDisk::new(DISK_SIZE));
+    assert_eq!(queue.inner().capabilities().queue_depth, 1);
+    assert_eq!(queue.queue_depth(), 1);
+
+    let mut queue = SyncQueue::with_depth(ramdisk::RamDisk::new(DISK_SIZE), 2);
+    assert_eq!(queue.queue_depth(), 2);
+    let write = BlockRequest {
+        tag: 1,
+        op: BlockOp::Write {
+            block_id: 3,
+            buf: vec![0x5a; BLOCK_SIZE],
+        },
+    };
+    let read = BlockRequest {
+        tag: 2,
+        op: BlockOp::Read {
+            block_id: 3,
+            buf: vec![0; BLOCK_SIZE],
+        },
+    };
+    assert!(queue.submit(write).is_ok());
+    assert!(queue.submit(read).is_ok());
+    let flush = BlockRequest {
+        tag: 3,
+        op: BlockOp::Flush,
+    };
+    let flush = queue.submit(flush).unwrap_err();
+    assert_eq!(queue.in_flight(), 2);
+
+    let done = queue.poll_completion().unwrap();
+    assert!(done.tag == 1 && done.result.is_ok());
+    let done = queue.poll_completion().unwrap();
+    assert!(done.tag == 2 && done.result.is_ok());
+    match done.op {
+        BlockOp::Read { buf, .. } => assert!(buf.iter().all(|&b| b == 0x5a)),
+        _ => panic!("unexpected operation"),
+    }
+    assert!(queue.poll_completion().is_none());
+
+    assert!(queue.submit(flush).is_ok());
+    let out_of_range = BlockRequest {
+        tag: 4,
+        op: BlockOp::Discard {
+            start: queue.num_blocks(),
+            count: 1,
+        },
+    };
+    assert!(queue.submit(out_of_range).is_ok());
+    assert!(queue.poll_completion().unwrap().result.is_ok());
+    assert!(queue.poll_completion().unwrap().result.is_err());
+}
+
+static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+/// A waker that counts its wakes.
+fn counting_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WAKES.fetch_add(1, Ordering::SeqCst);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+    unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+}
+
+/// A trivial executor, which polls the future until it completes.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = counting_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Block I/O through the async adapter, and an interrupt event that wakes
+/// its waiting task once signaled.
+fn test_async() {
+    let mut disk = AsyncAdapter::new(ramdisk::RamDisk::new(DISK_SIZE));
+    assert_eq!(disk.block_size(), BLOCK_SIZE);
+    let buf = vec![0x3cu8; BLOCK_SIZE * 2];
+    assert!(block_on(disk.write(4, &buf)).is_ok());
+    assert!(block_on(disk.flush()).is_ok());
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 2];
+    assert!(block_on(disk.read(4, &mut rbuf)).is_ok());
+    assert!(rbuf == buf);
+    assert!(block_on(disk.read(disk.num_blocks(), &mut rbuf)).is_err());
+
+    let event = IrqEvent::new();
+    event.signal();
+    block_on(event.wait());
+    assert!(!event.is_signaled());
+
+    let waker = counting_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut wait = pin!(event.wait());
+    let wakes = WAKES.load(Ordering::SeqCst);
+    assert!(wait.as_mut().poll(&mut cx).is_pending());
+    // As an interrupt handler would.
+    event.signal();
+    assert_eq!(WAKES.load(Ordering::SeqCst), wakes + 1);
+    assert!(wait.as_mut().poll(&mut cx).is_ready());
+}
+
+/// The number of blocks of the SD card behind the emulated SDHCI controller.
+const SD_BLOCKS: usize = 16;
+
+/// The registers of the emulated SDHCI controller, and their values when the
+/// emulator last looked at them.
+#[allow(clippy::declare_interior_mutable_const)]
+const SDHCI_REG: AtomicU32 = AtomicU32::new(0);
+static SDHCI_REGS: [AtomicU32; 64] = [SDHCI_REG; 64];
+static SDHCI_SHADOW: [AtomicU32; 64] = [SDHCI_REG; 64];
+/// The content of the emulated SD card.
+#[allow(clippy::declare_interior_mutable_const)]
+const SD_BYTE: AtomicU8 = AtomicU8::new(0);
+static SD_DATA: [AtomicU8; SD_BLOCKS * BLOCK_SIZE] = [SD_BYTE; SD_BLOCKS * BLOCK_SIZE];
+/// Whether the next command is an application command.
+static SD_APP: AtomicBool = AtomicBool::new(false);
+/// The number of data commands transferred by ADMA2, and the physical
+/// address of the first segment of the last one.
+static SDHCI_DMA: AtomicUsize = AtomicUsize::new(0);
+static SDHCI_DMA_ADDR: AtomicUsize = AtomicUsize::new(0);
+/// In interrupt-driven mode, the statuses of the commands that complete when
+/// the task waits, whether their interrupt is lost, and the handler.
+static SDHCI_DEFERRED: AtomicU32 = AtomicU32::new(0);
+static SDHCI_IRQ_LOST: AtomicBool = AtomicBool::new(false);
+static SDHCI_IRQ: AtomicPtr<SdhciIrq> = AtomicPtr::new(null_mut());
+static SDHCI_EVENT: IrqEvent = IrqEvent::new();
+/// The number of waits for an interrupt.
+static SDHCI_WAITS: AtomicUsize = AtomicUsize::new(0);
+/// Whether commands never complete.
+static SDHCI_HANG: AtomicBool = AtomicBool::new(false);
+/// The 1 GiB windows of virtual memory numbered as physical memory, so that
+/// DMA buffers are below 4 GiB whatever their virtual address.
+#[allow(clippy::declare_interior_mutable_const)]
+const DMA_WINDOW: AtomicUsize = AtomicUsize::new(0);
+static DMA_WINDOWS: [AtomicUsize; 4] = [DMA_WINDOW; 4];
+
+const SDHCI_ARGUMENT: usize = 0x08;
+const SDHCI_COMMAND: usize = 0x0c;
+const SDHCI_RESPONSE: usize = 0x10;
+const SDHCI_PRESENT_STATE: usize = 0x24;
+const SDHCI_HOST_CONTROL: usize = 0x28;
+const SDHCI_CLOCK_CONTROL: usize = 0x2c;
+const SDHCI_INT_STATUS: usize = 0x30;
+const SDHCI_SIGNAL_ENABLE: usize = 0x38;
+const SDHCI_CAPABILITIES: usize = 0x40;
+const SDHCI_ADMA_ADDRESS: usize = 0x58;
+const SDHCI_HOST_VERSION: usize = 0xfc;
+/// The value of the command register while no command is pending.
+const SDHCI_NO_COMMAND: u32 = u32::MAX;
+
+/// An SDHCI controller emulated in memory with an SDSC card in its slot.
+///
+/// The controller runs each time the driver writes a register, as it waits
+/// after every write. It has ADMA2 and no PIO: data commands without DMA
+/// fail with a data timeout. Once interrupts are signaled, commands only
+/// complete when the driver waits for them.
+struct MockSdhci;
+
+impl MockSdhci {
+    fn reg(offset: usize) -> &'static AtomicU32 {
+        &SDHCI_REGS[offset / 4]
+    }
+
+    fn phys_to_virt(paddr: usize) -> usize {
+        let window = DMA_WINDOWS[paddr >> 30].load(Ordering::SeqCst) - 1;
+        window << 30 | paddr & ((1 << 30) - 1)
+    }
+
+    /// Runs the controller after a register write.
+    fn tick() {
+        let changed = SDHCI_REGS
+            .iter()
+            .zip(&SDHCI_SHADOW)
+            .any(|(reg, old)| reg.load(Ordering::SeqCst) != old.load(Ordering::SeqCst));
+        // Interrupt statuses are cleared by writing 1s. Writing back the
+        // exact status is the only write that changes no register.
+        let old = SDHCI_SHADOW[SDHCI_INT_STATUS / 4].load(Ordering::SeqCst);
+        let status = Self::reg(SDHCI_INT_STATUS).load(Ordering::SeqCst);
+        if status != old || !changed {
+            Self::reg(SDHCI_INT_STATUS).store(old & !status, Ordering::SeqCst);
+        }
+
+        let mut clock = Self::reg(SDHCI_CLOCK_CONTROL).load(Ordering::SeqCst);
+        if clock & 1 << 24 != 0 {
+            for offset in [SDHCI_HOST_CONTROL, SDHCI_INT_STATUS, SDHCI_ADMA_ADDRESS] {
+                Self::reg(offset).store(0, Ordering::SeqCst);
+            }
+            clock = 0;
+        }
+        // Resets complete at once, and the internal clock is stable as soon
+        // as it is enabled.
+        clock &= !(7 << 24 | 1 << 1);
+        if clock & 1 != 0 {
+            clock |= 1 << 1;
+        }
+        Self::reg(SDHCI_CLOCK_CONTROL).store(clock, Ordering::SeqCst);
+
+        let command = Self::reg(SDHCI_COMMAND).swap(SDHCI_NO_COMMAND, Ordering::SeqCst);
+        if command != SDHCI_NO_COMMAND {
+            Self::command(command);
+        }
+        for (reg, old) in SDHCI_REGS.iter().zip(&SDHCI_SHADOW) {
+            old.store(reg.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+    }
+
+    fn command(command: u32) {
+        if SDHCI_HANG.load(Ordering::SeqCst) {
+            return;
+        }
+        let index = command >> 24;
+        let arg = Self::reg(SDHCI_ARGUMENT).load(Ordering::SeqCst);
+        let app = SD_APP.swap(false, Ordering::SeqCst);
+        let resp: u128 = match (index, app) {
+            (8, false) => arg as u128,
+            (55, _) => {
+                SD_APP.store(true, Ordering::SeqCst);
+                0x120
+            }
+            // Powered up, standard capacity.
+            (41, true) => 0x80ff_8000,
+            (2, false) => 0x1234 << 8,
+            (3, false) => 0xaaaa_0000,
+            // CSD version 1.0, 512-byte blocks, C_SIZE 3 and C_SIZE_MULT 0.
+            (9, false) => 9 << 80 | 3 << 62,
+            _ => 0x900,
+        };
+        if command & 3 << 16 == 1 << 16 {
+            // The controller strips the CRC byte of long responses.
+            for i in 0..4 {
+                let word = (resp >> 8 >> (i * 32)) as u32;
+                Self::reg(SDHCI_RESPONSE + i * 4).store(word, Ordering::SeqCst);
+            }
+        } else {
+            Self::reg(SDHCI_RESPONSE).store(resp as u32, Ordering::SeqCst);
+        }
+
+        let mut status = 1 << 0;
+        if command & 1 << 21 != 0 {
+            status |= match command & 1 << 0 {
+                0 => 1 << 15 | 1 << 20,
+                _ => Self::dma(index, app, arg as usize, command & 1 << 4 != 0),
+            };
+        } else if command & 3 << 16 == 3 << 16 {
+            status |= 1 << 1;
+        }
+        if Self::reg(SDHCI_SIGNAL_ENABLE).load(Ordering::SeqCst) != 0 {
+            SDHCI_DEFERRED.fetch_or(status, Ordering::SeqCst);
+        } else {
+            Self::reg(SDHCI_INT_STATUS).fetch_or(status, Ordering::SeqCst);
+        }
+    }
+
+    /// Waits for an interrupt as the kernel would: completes the deferred
+    /// commands and raises the interrupt, unless it is lost, then returns
+    /// whether the handler signaled `event`.
+    fn wait(event: &IrqEvent, timeout_ns: u64) -> bool {
+        assert!(timeout_ns > 0);
+        SDHCI_WAITS.fetch_add(1, Ordering::SeqCst);
+        let status = SDHCI_DEFERRED.swap(0, Ordering::SeqCst);
+        if status != 0 {
+            SDHCI_SHADOW[SDHCI_INT_STATUS / 4].fetch_or(status, Ordering::SeqCst);
+            Self::reg(SDHCI_INT_STATUS).fetch_or(status, Ordering::SeqCst);
+            let irq = SDHCI_IRQ.load(Ordering::SeqCst);
+            if !SDHCI_IRQ_LOST.load(Ordering::SeqCst) && !irq.is_null() {
+                assert!(unsafe { &*irq }.handle_irq());
+            }
+        }
+        let signaled = event.is_signaled();
+        event.clear();
+        signaled
+    }
+
+    /// Transfers the data of a command through the ADMA2 descriptor table,
+    /// and returns the resulting interrupt status.
+    fn dma(index: u32, app: bool, arg: usize, read: bool) -> u32 {
+        const SCR: [u8; 8] = 0x0235_8000_0000_0000u64.to_be_bytes();
+        let block = Self::reg(0x04).load(Ordering::SeqCst) as usize;
+        let len = (block & 0xfff) * (block >> 16);
+        // SEND_SCR reads the SCR, the other commands the card.
+        let scr = index == 51 && app;
+        let mut desc = Self::reg(SDHCI_ADMA_ADDRESS).load(Ordering::SeqCst) as usize;
+        let mut offset = 0;
+        loop {
+            let attr = unsafe { (Self::phys_to_virt(desc) as *const u64).read_volatile() };
+            let seg_len = match (attr >> 16) & 0xffff {
+                0 => 0x1_0000,
+                seg_len => seg_len as usize,
+            };
+            let paddr = (attr >> 32) as usize;
+            if attr & 1 == 0 || offset + seg_len > len {
+                return 1 << 15 | 1 << 25;
+            }
+            if offset == 0 {
+                SDHCI_DMA_ADDR.store(paddr, Ordering::SeqCst);
+            }
+            let mem = Self::phys_to_virt(paddr) as *mut u8;
+            for i in 0..seg_len {
+                let mem = unsafe { mem.add(i) };
+                let pos = offset + i;
+                if scr {
+                    unsafe { mem.write_volatile(SCR[pos % SCR.len()]) };
+                    continue;
+                }
+                let Some(data) = SD_DATA.get(arg + pos) else {
+                    return 1 << 15 | 1 << 25;
+                };
+                if read {
+                    unsafe { mem.write_volatile(data.load(Ordering::SeqCst)) };
+                } else {
+                    data.store(unsafe { mem.read_volatile() }, Ordering::SeqCst);
+                }
+            }
+            offset += seg_len;
+            if attr & 1 << 1 != 0 {
+                break;
+            }
+            desc += 8;
+        }
+        if offset != len {
+            return 1 << 15 | 1 << 25;
+        }
+        SDHCI_DMA.fetch_add(1, Ordering::SeqCst);
+        1 << 1
+    }
+
+    /// Powers the controller up with the card inserted, and returns the
+    /// driver of the card.
+    fn attach() -> SdhciDriver<MockSdhci> {
+        for reg in &SDHCI_REGS {
+            reg.store(0, Ordering::SeqCst);
+        }
+        // A card inserted, with DAT[3:0] high.
+        Self::reg(SDHCI_PRESENT_STATE).store(1 << 16 | 0xf << 20, Ordering::SeqCst);
+        // A 50 MHz base clock, ADMA2 and 3.3V.
+        Self::reg(SDHCI_CAPABILITIES).store(50 << 8 | 1 << 19 | 1 << 24, Ordering::SeqCst);
+        // Specification version 2.0.
+        Self::reg(SDHCI_HOST_VERSION).store(1 << 16, Ordering::SeqCst);
+        Self::reg(SDHCI_COMMAND).store(SDHCI_NO_COMMAND, Ordering::SeqCst);
+        for (reg, old) in SDHCI_REGS.iter().zip(&SDHCI_SHADOW) {
+            old.store(reg.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+
+        let base = NonNull::new(SDHCI_REGS.as_ptr() as *mut u8).unwrap();
+        let quirks = SdhciQuirks {
+            write_delay_cycles: 1,
+            ..SdhciQuirks::GENERIC
+        };
+        let host = unsafe { SdhciHost::try_new(base, "sdhci", quirks) }.unwrap();
+        MmcCard::try_new(host).unwrap()
+    }
+}
+
+impl DmaHal for MockSdhci {
+    fn dma_alloc(pages: usize) -> Option<(PhysAddr, NonNull<u8>)> {
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).ok()?;
+        let vaddr = NonNull::new(unsafe { alloc_zeroed(layout) })?;
+        Some((Self::virt_to_phys(vaddr.as_ptr() as usize), vaddr))
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) {
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        dealloc(vaddr.as_ptr(), layout);
+    }
+
+    fn virt_to_phys(vaddr: usize) -> PhysAddr {
+        let window = (vaddr >> 30) + 1;
+        let slot = DMA_WINDOWS
+            .iter()
+            .position(|slot| {
+                slot.compare_exchange(0, window, Ordering::SeqCst, Ordering::SeqCst)
+                    .map_or_else(|used| used == window, |_| true)
+            })
+            .expect("too many DMA windows");
+        slot << 30 | vaddr & ((1 << 30) - 1)
+    }
+
+    fn mmio_phys_to_virt(_paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        NonNull::new(SDHCI_REGS.as_ptr() as *mut u8).unwrap()
+    }
+
+    fn delay_ns(_ns: u64) {
+        Self::tick();
+    }
+}
+
+/// Data moves by ADMA2 whatever the alignment of the buffer: in place if it
+/// is word aligned, through the bounce buffer of the host otherwise.
+fn test_sdhci() {
+    let mut disk = MockSdhci::attach();
+    assert_eq!(disk.num_blocks(), SD_BLOCKS as u64);
+    assert!(disk.scr().is_some_and(|scr| scr.bus_width_4));
+
+    let mut buf = vec![0u8; BLOCK_SIZE * 2 + 1];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (i * 3) as u8;
+    }
+    let (aligned, misaligned) = (&buf[..BLOCK_SIZE * 2], &buf[1..]);
+    let dma = SDHCI_DMA.load(Ordering::SeqCst);
+    assert!(disk.write_block(2, aligned).is_ok());
+    let paddr = MockSdhci::virt_to_phys(aligned.as_ptr() as usize);
+    assert_eq!(SDHCI_DMA_ADDR.load(Ordering::SeqCst), paddr);
+    assert!(disk.write_block(4, misaligned).is_ok());
+    let paddr = MockSdhci::virt_to_phys(misaligned.as_ptr() as usize);
+    assert_ne!(SDHCI_DMA_ADDR.load(Ordering::SeqCst), paddr);
+    let stored = |block: usize, data: &[u8]| {
+        let card = &SD_DATA[block * BLOCK_SIZE..][..data.len()];
+        card.iter()
+            .map(|byte| byte.load(Ordering::SeqCst))
+            .eq(data.iter().copied())
+    };
+    assert!(stored(2, aligned) && stored(4, misaligned));
+
+    let mut rbuf = vec![0u8; BLOCK_SIZE * 2 + 1];
+    assert!(disk.read_block(4, &mut rbuf[1..]).is_ok());
+    assert!(rbuf[1..] == *misaligned);
+    assert!(disk.read_block(2, &mut rbuf[..BLOCK_SIZE * 2]).is_ok());
+    assert!(rbuf[..BLOCK_SIZE * 2] == *aligned);
+    assert_eq!(SDHCI_DMA.load(Ordering::SeqCst), dma + 4);
+}
+
+/// In interrupt-driven mode, requests wait for the handler to signal the
+/// event. A lost interrupt is made up for once the wait times out, and a
+/// command that never completes fails instead of blocking.
+fn test_sdhci_irq() {
+    let mut disk = MockSdhci::attach();
+    let irq = disk.host_mut().enable_irq(&SDHCI_EVENT, MockSdhci::wait);
+    SDHCI_IRQ.store(&irq as *const SdhciIrq as *mut SdhciIrq, Ordering::SeqCst);
+    assert!(disk.host().irq_enabled());
+    assert!(!irq.handle_irq());
+    assert!(!SDHCI_EVENT.is_signaled());
+
+    let buf = vec![0x5au8; BLOCK_SIZE];
+    let mut rbuf = vec![0u8; BLOCK_SIZE];
+    let waits = SDHCI_WAITS.load(Ordering::SeqCst);
+    assert!(disk.write_block(1, &buf).is_ok());
+    assert!(disk.read_block(1, &mut rbuf).is_ok());
+    assert!(rbuf == buf);
+    assert!(SDHCI_WAITS.load(Ordering::SeqCst) > waits);
+
+    SDHCI_IRQ_LOST.store(true, Ordering::SeqCst);
+    rbuf.fill(0);
+    assert!(disk.read_block(1, &mut rbuf).is_ok());
+    assert!(rbuf == buf);
+    SDHCI_IRQ_LOST.store(false, Ordering::SeqCst);
+
+    SDHCI_HANG.store(true, Ordering::SeqCst);
+    assert!(disk.read_block(1, &mut rbuf).is_err());
+    SDHCI_HANG.store(false, Ordering::SeqCst);
+
+    disk.host_mut().disable_irq();
+    SDHCI_IRQ.store(null_mut(), Ordering::SeqCst);
+    assert!(!disk.host().irq_enabled());
+    let waits = SDHCI_WAITS.load(Ordering::SeqCst);
+    rbuf.fill(0);
+    assert!(disk.read_block(1, &mut rbuf).is_ok());
+    assert!(rbuf == buf);
+    assert_eq!(SDHCI_WAITS.load(Ordering::SeqCst), waits);
+}
+
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
     error!("{}", info);